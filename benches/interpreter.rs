@@ -0,0 +1,66 @@
+use chip8_emu::cpu::Cpu;
+use chip8_emu::display::Display;
+use chip8_emu::jit::BlockCache;
+use chip8_emu::keyboard::KeyboardState;
+use chip8_emu::memory::Memory;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+const CYCLES: u64 = 100_000;
+const ROMS: [&str; 3] = ["breakout", "space_invaders", "chip8_emu_logo"];
+
+#[derive(Clone, Copy)]
+enum Backend {
+    Decode,
+    Cached,
+    Jit,
+}
+
+fn run(program: &[u8], backend: Backend) {
+    let mut cpu = Cpu::new();
+    let mut memory = Memory::new();
+    let mut display = Display::new();
+    let mut jit = BlockCache::new();
+    let keyboard_state = KeyboardState::default();
+    memory.set_decode_cache(!matches!(backend, Backend::Decode));
+    memory.write_chunk(0x200, program.to_vec().into_boxed_slice());
+
+    // Timers run at 60Hz against a 500Hz CPU
+    for _ in 0..CYCLES / 8 {
+        match backend {
+            Backend::Jit => {
+                jit.run(&mut cpu, &mut memory, &mut display, &keyboard_state, 8)
+                    .unwrap();
+            }
+            _ => {
+                for _ in 0..8 {
+                    cpu.step(&mut memory, &mut display, &keyboard_state).unwrap();
+                }
+            }
+        }
+        cpu.tick_timers();
+    }
+}
+
+fn interpreter(c: &mut Criterion) {
+    let mut group = c.benchmark_group("interpreter");
+    group.throughput(Throughput::Elements(CYCLES));
+
+    for rom in ROMS.iter() {
+        let program = std::fs::read(format!("roms/{}.ch8", rom)).unwrap();
+        group.bench_with_input(BenchmarkId::new("decode", rom), &program, |b, program| {
+            b.iter(|| run(program, Backend::Decode))
+        });
+        group.bench_with_input(BenchmarkId::new("cached", rom), &program, |b, program| {
+            b.iter(|| run(program, Backend::Cached))
+        });
+        group.bench_with_input(BenchmarkId::new("jit", rom), &program, |b, program| {
+            b.iter(|| run(program, Backend::Jit))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, interpreter);
+criterion_main!(benches);
@@ -0,0 +1,15 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use chip8_emu::opcode::Opcode;
+
+fuzz_target!(|instruction: u16| {
+    let opcode = Opcode::decode(instruction);
+    assert_eq!(Opcode::decode(opcode.encode()), opcode);
+
+    match opcode {
+        // Unknown instructions all decode to NOP, so only the decoded form round-trips
+        Opcode::NOP => {}
+        _ => assert_eq!(opcode.encode(), instruction),
+    }
+});
@@ -0,0 +1,46 @@
+#![no_main]
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+
+use chip8_emu::cpu::Cpu;
+use chip8_emu::display::Display;
+use chip8_emu::keyboard::KeyboardState;
+use chip8_emu::memory::Memory;
+
+const FRAMES: usize = 256;
+const STEPS_PER_FRAME: usize = 8;
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    seed: u64,
+    // One 16-bit key mask per frame, repeated when the input runs out
+    keys: Vec<u16>,
+    rom: Vec<u8>,
+}
+
+// Any ROM and key sequence must either run or stop with a CpuError, never panic
+fuzz_target!(|input: Input| {
+    let mut cpu = Cpu::with_seed(input.seed);
+    let mut memory = Memory::new();
+    let mut display = Display::new();
+
+    let rom_len = input.rom.len().min(memory.size() - 0x200);
+    memory.write_chunk(0x200, input.rom[..rom_len].to_vec().into_boxed_slice());
+
+    for frame in 0..FRAMES {
+        let mut keyboard_state = KeyboardState::default();
+        if !input.keys.is_empty() {
+            let mask = input.keys[frame % input.keys.len()];
+            for key in 0..16 {
+                keyboard_state.set_key(key, (mask >> key) & 1 == 1);
+            }
+        }
+
+        for _ in 0..STEPS_PER_FRAME {
+            if cpu.step(&mut memory, &mut display, &keyboard_state).is_err() {
+                return;
+            }
+        }
+        cpu.tick_timers();
+    }
+});
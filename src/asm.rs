@@ -0,0 +1,332 @@
+use super::opcode::Opcode;
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+// Assembles the mnemonics `Opcode` prints (Cowgod's syntax) into a ROM. Besides
+// instructions it takes `label:` definitions, `db`/`dw` data and `;` comments.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+#[derive(Debug, Default)]
+pub struct Assembly {
+    pub bytes: Vec<u8>,
+    pub labels: BTreeMap<String, u16>,
+    // Source line of each statement, by address
+    pub lines: BTreeMap<u16, usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operand {
+    V(u8),
+    I,
+    IndirectI,
+    DT,
+    ST,
+    K,
+    F,
+    B,
+    Value(u16),
+}
+
+struct Statement<'a> {
+    line: usize,
+    mnemonic: String,
+    operands: Vec<&'a str>,
+}
+
+pub fn assemble(source: &str, origin: u16) -> Result<Assembly, AsmError> {
+    let mut labels = BTreeMap::new();
+    let mut statements = Vec::new();
+    let mut address = origin as usize;
+
+    // First pass: find where every label lands
+    for (i, line) in source.lines().enumerate() {
+        let line_number = i + 1;
+        let error = |message: String| AsmError {
+            line: line_number,
+            message: message,
+        };
+        let mut text = line.split(';').next().unwrap().trim();
+
+        while let Some(colon) = text.find(':') {
+            let label = text[..colon].trim();
+            if !is_label(label) {
+                return Err(error(format!("Invalid label: {}", label)));
+            }
+            if labels.insert(label.to_string(), address as u16).is_some() {
+                return Err(error(format!("Duplicate label: {}", label)));
+            }
+            text = text[colon + 1..].trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+
+        let mut parts = text.splitn(2, char::is_whitespace);
+        let mnemonic = parts.next().unwrap().to_ascii_uppercase();
+        let operands: Vec<&str> = match parts.next() {
+            Some(rest) => rest.split(',').map(str::trim).collect(),
+            None => Vec::new(),
+        };
+        address += match mnemonic.as_str() {
+            "DB" => operands.len(),
+            "DW" => operands.len() * 2,
+            _ => 2,
+        };
+        if address > 0x1000 {
+            return Err(error("Program does not fit in memory".to_string()));
+        }
+
+        statements.push(Statement {
+            line: line_number,
+            mnemonic: mnemonic,
+            operands: operands,
+        });
+    }
+
+    // Second pass: encode with every label known
+    let mut bytes = Vec::new();
+    let mut lines = BTreeMap::new();
+    for statement in statements.iter() {
+        lines.insert(origin + bytes.len() as u16, statement.line);
+        let error = |message: String| AsmError {
+            line: statement.line,
+            message: message,
+        };
+        let operands = statement
+            .operands
+            .iter()
+            .map(|operand| parse_operand(operand, &labels))
+            .collect::<Result<Vec<Operand>, String>>()
+            .map_err(error)?;
+
+        match statement.mnemonic.as_str() {
+            "DB" => {
+                for operand in operands {
+                    bytes.push(byte(operand).map_err(error)?);
+                }
+            }
+            "DW" => {
+                for operand in operands {
+                    match operand {
+                        Operand::Value(value) => bytes.extend_from_slice(&value.to_be_bytes()),
+                        _ => return Err(error("Expected a number".to_string())),
+                    }
+                }
+            }
+            mnemonic => {
+                let opcode = encode(mnemonic, &operands).map_err(error)?;
+                bytes.extend_from_slice(&opcode.encode().to_be_bytes());
+            }
+        }
+    }
+
+    Ok(Assembly {
+        bytes: bytes,
+        labels: labels,
+        lines: lines,
+    })
+}
+
+pub fn is_label(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_operand(text: &str, labels: &BTreeMap<String, u16>) -> Result<Operand, String> {
+    let upper = text.to_ascii_uppercase();
+    let operand = match upper.as_str() {
+        "I" => Operand::I,
+        "[I]" => Operand::IndirectI,
+        "DT" => Operand::DT,
+        "ST" => Operand::ST,
+        "K" => Operand::K,
+        "F" => Operand::F,
+        "B" => Operand::B,
+        _ if upper.len() == 2 && upper.starts_with('V') => {
+            match u8::from_str_radix(&upper[1..], 16) {
+                Ok(x) => Operand::V(x),
+                Err(_) => return Err(format!("Invalid register: {}", text)),
+            }
+        }
+        _ => match parse_number(text) {
+            Some(value) => Operand::Value(value),
+            None => match labels.get(text) {
+                Some(address) => Operand::Value(*address),
+                None => return Err(format!("Unknown label: {}", text)),
+            },
+        },
+    };
+
+    Ok(operand)
+}
+
+pub fn parse_number(text: &str) -> Option<u16> {
+    let text = text.to_ascii_lowercase();
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix('#')) {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = text.strip_prefix("0b") {
+        u16::from_str_radix(binary, 2).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+fn byte(operand: Operand) -> Result<u8, String> {
+    match operand {
+        Operand::Value(value) if value <= 0xFF => Ok(value as u8),
+        Operand::Value(value) => Err(format!("Value out of range for a byte: {:#X}", value)),
+        _ => Err("Expected a number".to_string()),
+    }
+}
+
+fn address(operand: Operand) -> Result<u16, String> {
+    match operand {
+        Operand::Value(value) if value <= 0xFFF => Ok(value),
+        Operand::Value(value) => Err(format!("Address out of range: {:#X}", value)),
+        _ => Err("Expected an address".to_string()),
+    }
+}
+
+fn encode(mnemonic: &str, operands: &[Operand]) -> Result<Opcode, String> {
+    use Operand::*;
+
+    let opcode = match (mnemonic, operands) {
+        ("CLS", []) => Opcode::CLS,
+        ("RET", []) => Opcode::RET,
+        ("SYS", [a]) => Opcode::SYS { addr: address(*a)? },
+        ("JP", [V(0), a]) => Opcode::JP_V0 { addr: address(*a)? },
+        ("JP", [a]) => Opcode::JP { addr: address(*a)? },
+        ("CALL", [a]) => Opcode::CALL { addr: address(*a)? },
+        ("SE", [V(x), V(y)]) => Opcode::SE_R { x: *x, y: *y },
+        ("SE", [V(x), b]) => Opcode::SE {
+            x: *x,
+            byte: byte(*b)?,
+        },
+        ("SNE", [V(x), V(y)]) => Opcode::SNE_R { x: *x, y: *y },
+        ("SNE", [V(x), b]) => Opcode::SNE {
+            x: *x,
+            byte: byte(*b)?,
+        },
+        ("LD", [V(x), V(y)]) => Opcode::LD_R { x: *x, y: *y },
+        ("LD", [V(x), DT]) => Opcode::LD_DT { x: *x },
+        ("LD", [V(x), K]) => Opcode::LD_R_K { x: *x },
+        ("LD", [V(x), IndirectI]) => Opcode::LD_M { x: *x },
+        ("LD", [V(x), b]) => Opcode::LD_IMM {
+            x: *x,
+            byte: byte(*b)?,
+        },
+        ("LD", [I, a]) => Opcode::LDI_IMM { addr: address(*a)? },
+        ("LD", [DT, V(x)]) => Opcode::SET_DT { x: *x },
+        ("LD", [ST, V(x)]) => Opcode::SET_ST { x: *x },
+        ("LD", [F, V(x)]) => Opcode::LD_F { x: *x },
+        ("LD", [B, V(x)]) => Opcode::LD_B { x: *x },
+        ("LD", [IndirectI, V(x)]) => Opcode::ST_M { x: *x },
+        ("ADD", [V(x), V(y)]) => Opcode::ADD_R { x: *x, y: *y },
+        ("ADD", [V(x), b]) => Opcode::ADD_IMM {
+            x: *x,
+            byte: byte(*b)?,
+        },
+        ("ADD", [I, V(x)]) => Opcode::ADDI_R { x: *x },
+        ("OR", [V(x), V(y)]) => Opcode::OR_R { x: *x, y: *y },
+        ("AND", [V(x), V(y)]) => Opcode::AND { x: *x, y: *y },
+        ("XOR", [V(x), V(y)]) => Opcode::XOR_R { x: *x, y: *y },
+        ("SUB", [V(x), V(y)]) => Opcode::SUB_R { x: *x, y: *y },
+        ("SUBN", [V(x), V(y)]) => Opcode::SUBN_R { x: *x, y: *y },
+        // With one operand the shift reads Vx whichever way the shift quirk is set
+        ("SHR", [V(x)]) => Opcode::SHR { x: *x, y: *x },
+        ("SHR", [V(x), V(y)]) => Opcode::SHR { x: *x, y: *y },
+        ("SHL", [V(x)]) => Opcode::SHL { x: *x, y: *x },
+        ("SHL", [V(x), V(y)]) => Opcode::SHL { x: *x, y: *y },
+        ("RND", [V(x), b]) => Opcode::RND {
+            x: *x,
+            byte: byte(*b)?,
+        },
+        ("DRW", [V(x), V(y), Value(n)]) if *n <= 0xF => Opcode::DRW {
+            x: *x,
+            y: *y,
+            size: *n as u8,
+        },
+        ("SKP", [V(x)]) => Opcode::SKP { x: *x },
+        ("SKNP", [V(x)]) => Opcode::SKNP { x: *x },
+        _ => {
+            return Err(format!(
+                "Invalid instruction: {} {}",
+                mnemonic,
+                operands
+                    .iter()
+                    .map(|operand| format!("{:?}", operand))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ))
+        }
+    };
+
+    Ok(opcode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assembles_instructions_labels_and_data() {
+        let source = "
+            start:  LD V0, 0x05    ; counter
+                    CALL sub
+            loop:   JP loop
+            sub:    LD I, sprite
+                    DRW V0, V1, 2
+                    RET
+            sprite: db 0b11110000, 0x90
+                    dw 0x1234
+        ";
+        let assembly = assemble(source, 0x200).unwrap();
+
+        assert_eq!(
+            assembly.bytes,
+            vec![
+                0x60, 0x05, 0x22, 0x06, 0x12, 0x04, 0xA2, 0x0C, 0xD0, 0x12, 0x00, 0xEE, 0xF0, 0x90,
+                0x12, 0x34
+            ]
+        );
+        assert_eq!(assembly.labels["sprite"], 0x20C);
+        assert_eq!(assembly.lines[&0x206], 5);
+        assert_eq!(assembly.lines[&0x20E], 9);
+    }
+
+    #[test]
+    fn round_trips_every_mnemonic() {
+        for instruction in 0..=0xFFFFu16 {
+            let opcode = Opcode::decode(instruction);
+            if opcode == Opcode::NOP {
+                continue;
+            }
+            let assembly = assemble(&opcode.to_string(), 0x200).unwrap();
+            assert_eq!(assembly.bytes, instruction.to_be_bytes(), "{}", opcode);
+        }
+    }
+
+    #[test]
+    fn reports_errors_with_line_numbers() {
+        let error = assemble("CLS\nLD V0, 0x100", 0x200).unwrap_err();
+        assert_eq!(error.line, 2);
+
+        let error = assemble("JP nowhere", 0x200).unwrap_err();
+        assert_eq!(error.message, "Unknown label: nowhere");
+
+        assert!(assemble("a:\na: CLS", 0x200).is_err());
+    }
+}
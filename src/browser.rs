@@ -0,0 +1,296 @@
+use super::database::Database;
+use super::loader::{self, LoadError, Loaded};
+use super::osd;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const EXTENSIONS: [&str; 6] = ["ch8", "sc8", "xo8", "8o", "gif", "zip"];
+
+const BACKGROUND: u32 = 0x000000;
+// Rows kept for the heading and the selected ROM's details
+const HEADER_ROWS: usize = 2;
+const DETAIL_ROWS: usize = 4;
+
+pub fn is_rom(path: &Path) -> bool {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => EXTENSIONS
+            .iter()
+            .any(|known| known.eq_ignore_ascii_case(ext)),
+        None => false,
+    }
+}
+
+pub fn is_octo_source(path: &Path) -> bool {
+    match path.extension() {
+        Some(ext) => ext.eq_ignore_ascii_case("8o"),
+        None => false,
+    }
+}
+
+pub fn is_archive(path: &Path) -> bool {
+    match path.extension() {
+        Some(ext) => ext.eq_ignore_ascii_case("zip"),
+        None => false,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BrowserEntry {
+    pub path: PathBuf,
+    pub title: String,
+    pub details: Vec<String>,
+}
+
+// The ROMs in a directory, with whatever the database knows about them
+#[derive(Debug)]
+pub struct RomBrowser {
+    dir: PathBuf,
+    entries: Vec<BrowserEntry>,
+    selected: usize,
+    // First entry on screen
+    top: usize,
+}
+
+impl RomBrowser {
+    // Recent ROMs that still exist come first, wherever they are
+    pub fn scan(dir: &Path, recent: &[PathBuf], database: &Database) -> io::Result<Self> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| is_rom(path))
+            .collect();
+        paths.sort();
+
+        let mut entries = Vec::new();
+        for path in recent.iter().filter(|path| path.is_file()) {
+            if let Some(mut entry) = RomBrowser::entry(path.clone(), database) {
+                entry.details.insert(1, "played recently".to_string());
+                entries.push(entry);
+            }
+        }
+        for path in paths {
+            let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+            if recent.contains(&canonical) {
+                continue;
+            }
+            entries.extend(RomBrowser::entry(path, database));
+        }
+
+        Ok(RomBrowser {
+            dir: dir.to_path_buf(),
+            entries: entries,
+            selected: 0,
+            top: 0,
+        })
+    }
+
+    // None for files that can't be read
+    fn entry(path: PathBuf, database: &Database) -> Option<BrowserEntry> {
+        let file_name = path.file_name()?.to_string_lossy().into_owned();
+        // Archives holding several ROMs are only listed; the one to run is
+        // asked for once the archive is picked
+        let mut choices = 0;
+        let program = match loader::load(&path, |names| {
+            choices = names.len();
+            None
+        }) {
+            Ok(Loaded::Program(program)) | Ok(Loaded::Cartridge { program, .. }) => program,
+            // One unreadable file shouldn't hide the rest
+            Err(LoadError::Io { .. }) => return None,
+            Err(LoadError::NotChosen) => {
+                return Some(BrowserEntry {
+                    path: path,
+                    title: file_name,
+                    details: vec![format!("archive of {} ROMs", choices)],
+                })
+            }
+            Err(e) => {
+                return Some(BrowserEntry {
+                    path: path,
+                    title: file_name,
+                    details: vec![e.to_string()],
+                })
+            }
+        };
+        let entry = match database.lookup(&program) {
+            Some(entry) => {
+                let mut details = vec![file_name];
+                if !entry.program.description.is_empty() {
+                    details.push(entry.program.description.clone());
+                }
+                if !entry.program.authors.is_empty() {
+                    details.push(format!("by {}", entry.program.authors.join(", ")));
+                }
+                if let Some(platform) = &entry.rom.platform {
+                    details.push(format!("platform {}", platform));
+                }
+                BrowserEntry {
+                    path: path,
+                    title: entry.program.title.clone(),
+                    details: details,
+                }
+            }
+            None => BrowserEntry {
+                path: path,
+                title: file_name,
+                details: vec![
+                    format!("{} bytes", program.len()),
+                    "not in the ROM database".to_string(),
+                ],
+            },
+        };
+        Some(entry)
+    }
+
+    pub fn entries(&self) -> &[BrowserEntry] {
+        &self.entries
+    }
+
+    pub fn selected(&self) -> Option<&BrowserEntry> {
+        self.entries.get(self.selected)
+    }
+
+    // Moves the selection by `offset` entries, stopping at either end
+    pub fn move_selection(&mut self, offset: isize) {
+        if self.entries.is_empty() {
+            return;
+        }
+        let last = self.entries.len() as isize - 1;
+        self.selected = (self.selected as isize + offset).max(0).min(last) as usize;
+    }
+
+    // How many entries fit between the heading and the details
+    pub fn page_size(height: usize, width: usize) -> usize {
+        let line_height = osd::text_size("", osd::text_scale(width)).1;
+        (height / line_height)
+            .saturating_sub(HEADER_ROWS + DETAIL_ROWS)
+            .max(1)
+    }
+
+    pub fn render(&mut self, buffer: &mut [u32], width: usize) {
+        let height = buffer.len() / width;
+        let scale = osd::text_scale(width);
+        let line_height = osd::text_size("", scale).1;
+        let columns = osd::columns(width, scale);
+        let rows = RomBrowser::page_size(height, width);
+
+        // Scroll just enough to keep the selection on screen
+        if self.selected < self.top {
+            self.top = self.selected;
+        } else if self.selected >= self.top + rows {
+            self.top = self.selected + 1 - rows;
+        }
+
+        for pixel in buffer.iter_mut() {
+            *pixel = BACKGROUND;
+        }
+        let mut draw = |row: usize, text: &str| {
+            let text: String = text.chars().take(columns).collect();
+            osd::draw_text(buffer, width, 0, row * line_height, &text, scale);
+        };
+
+        draw(0, &format!("{}", self.dir.display()));
+        draw(1, "Enter loads, Esc goes back");
+        if self.entries.is_empty() {
+            draw(HEADER_ROWS, "No ROMs here");
+            return;
+        }
+        for (row, entry) in self.entries.iter().enumerate().skip(self.top).take(rows) {
+            let marker = if row == self.selected { ">" } else { " " };
+            draw(
+                HEADER_ROWS + row - self.top,
+                &format!("{} {}", marker, entry.title),
+            );
+        }
+        let details_row = HEADER_ROWS + rows;
+        for (i, line) in self.entries[self.selected]
+            .details
+            .iter()
+            .take(DETAIL_ROWS)
+            .enumerate()
+        {
+            draw(details_row + i, line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    // `recent` names files in the fixture directory, as the recent list would
+    fn scan_fixture(name: &str, recent: &[&str]) -> RomBrowser {
+        let dir = std::env::temp_dir().join(format!("chip8-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("b.sc8"), [0x00, 0xE0]).unwrap();
+        fs::write(dir.join("notes.txt"), "not a ROM").unwrap();
+        fs::create_dir_all(dir.join("unreadable.ch8")).unwrap();
+        fs::write(dir.join("c.8o"), ": main\n  v0 := 300\n").unwrap();
+
+        let mut zip = ZipWriter::new(fs::File::create(dir.join("d.zip")).unwrap());
+        for name in ["one.ch8", "two.ch8"].iter() {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(&[0x12, 0x00]).unwrap();
+        }
+        zip.finish().unwrap();
+        fs::copy("roms/breakout.ch8", dir.join("a.ch8")).unwrap();
+
+        let dir = dir.canonicalize().unwrap();
+        let recent: Vec<PathBuf> = recent.iter().map(|name| dir.join(name)).collect();
+        let browser = RomBrowser::scan(&dir, &recent, &Database::bundled()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        browser
+    }
+
+    #[test]
+    fn lists_roms_with_database_titles() {
+        let browser = scan_fixture("titles", &[]);
+        let titles: Vec<&str> = browser
+            .entries()
+            .iter()
+            .map(|entry| entry.title.as_str())
+            .collect();
+
+        assert_eq!(titles, vec!["Breakout", "b.sc8", "c.8o", "d.zip"]);
+        assert_eq!(browser.entries()[0].details[0], "a.ch8");
+        assert_eq!(browser.entries()[1].details[0], "2 bytes");
+        assert!(browser.entries()[2].details[0].starts_with("line 2,"));
+        assert_eq!(browser.entries()[3].details[0], "archive of 2 ROMs");
+    }
+
+    #[test]
+    fn lists_recent_roms_first() {
+        let browser = scan_fixture("recent", &["b.sc8", "gone.ch8"]);
+        let titles: Vec<&str> = browser
+            .entries()
+            .iter()
+            .map(|entry| entry.title.as_str())
+            .collect();
+
+        assert_eq!(titles, vec!["b.sc8", "Breakout", "c.8o", "d.zip"]);
+        assert_eq!(browser.entries()[0].details[1], "played recently");
+    }
+
+    #[test]
+    fn selection_stays_in_range() {
+        let mut browser = scan_fixture("selection", &[]);
+        browser.move_selection(-1);
+        assert_eq!(browser.selected().unwrap().title, "Breakout");
+        browser.move_selection(10);
+        assert_eq!(browser.selected().unwrap().title, "d.zip");
+    }
+
+    #[test]
+    fn renders_into_any_buffer() {
+        let mut browser = scan_fixture("render", &[]);
+        let mut buffer = vec![0x123456; 64 * 32];
+        browser.render(&mut buffer, 64);
+        assert!(buffer.iter().all(|pixel| *pixel != 0x123456));
+        assert!(buffer.iter().any(|pixel| *pixel != BACKGROUND));
+    }
+}
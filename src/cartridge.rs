@@ -0,0 +1,215 @@
+use super::database;
+use super::quirks::Quirks;
+
+use serde::Deserialize;
+use std::fmt;
+
+// Octo saves programs as cartridges: GIFs of a cartridge label whose pixels
+// also carry the source and its options. The low two bits of every palette
+// index are data, four pixels to a byte with the first in the high bits,
+// running through the frames in order. The data is a big endian 32-bit length
+// followed by that many bytes of JSON.
+
+const GIF_MAGIC: &[u8] = b"GIF8";
+
+// Octo's run settings. Only the ones the emulator can honour are kept.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct OctoOptions {
+    // Instructions per 60 Hz frame
+    pub tickrate: Option<u64>,
+    pub fill_color: Option<String>,
+    pub background_color: Option<String>,
+    // Each quirk flag is Octo's name for turning the modern behaviour off
+    pub shift_quirks: Option<bool>,
+    pub load_store_quirks: Option<bool>,
+    pub jump_quirks: Option<bool>,
+    pub clip_quirks: Option<bool>,
+    pub logic_quirks: Option<bool>,
+}
+
+impl OctoOptions {
+    // Octo with every quirk off behaves like the xochip preset
+    pub fn quirks(&self) -> Quirks {
+        let mut quirks = Quirks::preset("xochip").unwrap();
+        if let Some(shift) = self.shift_quirks {
+            quirks.shift_vy = !shift;
+        }
+        if let Some(load_store) = self.load_store_quirks {
+            quirks.memory_increment = !load_store;
+        }
+        if let Some(jump) = self.jump_quirks {
+            quirks.jump_vx = jump;
+        }
+        if let Some(clip) = self.clip_quirks {
+            quirks.clip_sprites = clip;
+        }
+        if let Some(logic) = self.logic_quirks {
+            quirks.vf_reset = logic;
+        }
+        quirks
+    }
+
+    pub fn palette(&self) -> Option<[u32; 2]> {
+        let background = database::parse_color(self.background_color.as_deref()?)?;
+        let fill = database::parse_color(self.fill_color.as_deref()?)?;
+        Some([background, fill])
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Cartridge {
+    // Octo source, not a binary
+    pub program: String,
+    #[serde(default)]
+    pub options: OctoOptions,
+}
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Gif(gif::DecodingError),
+    Truncated { length: usize, available: usize },
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::Gif(e) => write!(f, "not a readable GIF: {}", e),
+            CartridgeError::Truncated { length, available } => write!(
+                f,
+                "cartridge says it holds {} bytes but only has {}",
+                length, available
+            ),
+            CartridgeError::Json(e) => write!(f, "cartridge data is not valid: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+pub fn is_gif(bytes: &[u8]) -> bool {
+    bytes.starts_with(GIF_MAGIC)
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Cartridge, CartridgeError> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(bytes).map_err(CartridgeError::Gif)?;
+
+    let mut data = Vec::new();
+    let (mut byte, mut bits) = (0u8, 0);
+    while let Some(frame) = decoder.read_next_frame().map_err(CartridgeError::Gif)? {
+        for index in frame.buffer.iter() {
+            byte = (byte << 2) | (index & 3);
+            bits += 2;
+            if bits == 8 {
+                data.push(byte);
+                byte = 0;
+                bits = 0;
+            }
+        }
+    }
+
+    let available = data.len().saturating_sub(4);
+    if data.len() < 4 {
+        return Err(CartridgeError::Truncated {
+            length: 4,
+            available: data.len(),
+        });
+    }
+    let length = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    let json = data.get(4..4 + length).ok_or(CartridgeError::Truncated {
+        length: length,
+        available: available,
+    })?;
+    serde_json::from_slice(json).map_err(CartridgeError::Json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: u16 = 32;
+    const HEIGHT: u16 = 16;
+
+    // Spreads `data` over as many frames as it takes, the way Octo does. The
+    // high bits of each index are a made up label.
+    fn encode_data(data: &[u8]) -> Vec<u8> {
+        let mut indices: Vec<u8> = data
+            .iter()
+            .flat_map(|byte| (0..4).rev().map(move |pair| (byte >> (pair * 2)) & 3))
+            .enumerate()
+            .map(|(i, bits)| ((i % 4) as u8) << 2 | bits)
+            .collect();
+        let frame_size = WIDTH as usize * HEIGHT as usize;
+        let frames = indices.len().div_ceil(frame_size);
+        indices.resize(frames * frame_size, 0);
+
+        let palette: Vec<u8> = (0..16).flat_map(|i| vec![i * 16, i * 8, i * 4]).collect();
+        let mut gif = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut gif, WIDTH, HEIGHT, &palette).unwrap();
+            for pixels in indices.chunks(frame_size) {
+                let frame = gif::Frame::from_indexed_pixels(WIDTH, HEIGHT, pixels, None);
+                encoder.write_frame(&frame).unwrap();
+            }
+        }
+        gif
+    }
+
+    fn encode(json: &str) -> Vec<u8> {
+        let mut data = (json.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(json.as_bytes());
+        encode_data(&data)
+    }
+
+    #[test]
+    fn decodes_source_and_options() {
+        let json = r##"{
+            "program": ": main\n  loop again\n",
+            "options": {
+                "tickrate": 20,
+                "fillColor": "#FF6600",
+                "backgroundColor": "#000000",
+                "shiftQuirks": true,
+                "clipQuirks": true,
+                "screenRotation": 0
+            }
+        }"##;
+        let gif = encode(json);
+        assert!(is_gif(&gif));
+
+        let cartridge = decode(&gif).unwrap();
+        assert_eq!(cartridge.program, ": main\n  loop again\n");
+        assert_eq!(cartridge.options.tickrate, Some(20));
+        assert_eq!(cartridge.options.palette(), Some([0x000000, 0xFF6600]));
+        let quirks = cartridge.options.quirks();
+        assert!(!quirks.shift_vy);
+        assert!(quirks.clip_sprites);
+        assert!(quirks.memory_increment);
+    }
+
+    #[test]
+    fn spans_frames() {
+        let program = "0123456789".repeat(40);
+        let gif = encode(&format!(r#"{{"program": "{}"}}"#, program));
+        let cartridge = decode(&gif).unwrap();
+        assert_eq!(cartridge.program, program);
+        assert_eq!(cartridge.options.quirks().name(), "xochip");
+    }
+
+    #[test]
+    fn rejects_bad_data() {
+        let gif = encode(r#"{"program": ""}"#);
+        assert!(matches!(decode(&gif[..20]), Err(CartridgeError::Gif(_))));
+        assert!(matches!(
+            decode(&encode_data(&[0x00, 0x00, 0xFF, 0xFF, b'{'])),
+            Err(CartridgeError::Truncated { length: 0xFFFF, .. })
+        ));
+        assert!(matches!(
+            decode(&encode(r#"{"options": {}}"#)),
+            Err(CartridgeError::Json(_))
+        ));
+    }
+}
@@ -0,0 +1,361 @@
+use super::keyboard::KeyboardState;
+use super::memory::Memory;
+
+// Where the VIP CHIP-8 interpreter keeps its state, for machine code that reads it
+pub const VIP_STACK: u16 = 0xECF;
+pub const VIP_REGISTERS: u16 = 0xEF0;
+pub const VIP_DISPLAY: u16 = 0xF00;
+
+// The interpreter calls 0nnn routines with SEP R3 and they return with SEP R4 (D4)
+const CALL_REGISTER: u8 = 3;
+const RETURN_REGISTER: u8 = 4;
+
+#[derive(Debug, Default, Clone)]
+pub struct Cdp1802 {
+    pub r: [u16; 16],
+    pub d: u8,
+    pub df: bool,
+    pub p: u8,
+    pub x: u8,
+    pub t: u8,
+    pub ie: bool,
+    pub q: bool,
+    // Keypad latch written by OUT 2 and read back through EF3
+    key_latch: u8,
+    // Total machine cycles spent in routines that returned
+    pub cycles: u64,
+}
+
+impl Cdp1802 {
+    pub fn new() -> Self {
+        Cdp1802::default()
+    }
+
+    // Runs the routine at `address` until it returns to the interpreter. Returns the
+    // machine cycles used, or None if it has not returned within `max_cycles`.
+    pub fn call(
+        &mut self,
+        address: u16,
+        memory: &mut Memory,
+        keyboard_state: &KeyboardState,
+        max_cycles: u64,
+    ) -> Option<u64> {
+        self.r[CALL_REGISTER as usize] = address;
+        self.p = CALL_REGISTER;
+
+        let mut cycles = 0;
+        while self.p != RETURN_REGISTER {
+            if cycles >= max_cycles {
+                return None;
+            }
+            cycles += self.step(memory, keyboard_state) as u64;
+        }
+        self.cycles += cycles;
+
+        Some(cycles)
+    }
+
+    fn read(&self, memory: &Memory, address: u16) -> u8 {
+        memory.read_byte((address as usize % memory.size()) as u16)
+    }
+
+    fn write(&self, memory: &mut Memory, address: u16, value: u8) {
+        memory.write_byte((address as usize % memory.size()) as u16, value);
+    }
+
+    fn fetch(&mut self, memory: &Memory) -> u8 {
+        let pc = self.r[self.p as usize];
+        self.r[self.p as usize] = pc.wrapping_add(1);
+        self.read(memory, pc)
+    }
+
+    fn ef(&self, line: u8, keyboard_state: &KeyboardState) -> bool {
+        match line {
+            3 => keyboard_state.is_key_pressed(self.key_latch),
+            _ => false,
+        }
+    }
+
+    // Executes one instruction and returns the machine cycles it took
+    pub fn step(&mut self, memory: &mut Memory, keyboard_state: &KeyboardState) -> u8 {
+        let instruction = self.fetch(memory);
+        let (i, n) = (instruction >> 4, instruction & 0xF);
+        let rn = n as usize;
+        let rx = self.x as usize;
+
+        match (i, n) {
+            // IDL waits for the next interrupt, which is not emulated while a routine runs
+            (0x0, 0x0) => {}
+            (0x0, _) => self.d = self.read(memory, self.r[rn]),
+            (0x1, _) => self.r[rn] = self.r[rn].wrapping_add(1),
+            (0x2, _) => self.r[rn] = self.r[rn].wrapping_sub(1),
+            (0x3, _) => {
+                let taken = match n {
+                    0x0 => true,
+                    0x1 => self.q,
+                    0x2 => self.d == 0,
+                    0x3 => self.df,
+                    0x4..=0x7 => self.ef(n - 3, keyboard_state),
+                    0x8 => false,
+                    0x9 => !self.q,
+                    0xA => self.d != 0,
+                    0xB => !self.df,
+                    _ => !self.ef(n - 0xB, keyboard_state),
+                };
+                self.short_branch(memory, taken);
+            }
+            (0x4, _) => {
+                self.d = self.read(memory, self.r[rn]);
+                self.r[rn] = self.r[rn].wrapping_add(1);
+            }
+            (0x5, _) => self.write(memory, self.r[rn], self.d),
+            (0x6, 0x0) => self.r[rx] = self.r[rx].wrapping_add(1),
+            (0x6, 0x1..=0x7) => {
+                let value = self.read(memory, self.r[rx]);
+                self.r[rx] = self.r[rx].wrapping_add(1);
+                self.output(n, value);
+            }
+            (0x6, 0x8) => {}
+            (0x6, _) => {
+                // No input devices are attached, so the bus reads as zero
+                self.write(memory, self.r[rx], 0);
+                self.d = 0;
+            }
+            (0x7, 0x0) | (0x7, 0x1) => {
+                let value = self.read(memory, self.r[rx]);
+                self.r[rx] = self.r[rx].wrapping_add(1);
+                self.x = value >> 4;
+                self.p = value & 0xF;
+                self.ie = n == 0x0;
+            }
+            (0x7, 0x2) => {
+                self.d = self.read(memory, self.r[rx]);
+                self.r[rx] = self.r[rx].wrapping_add(1);
+            }
+            (0x7, 0x3) => {
+                self.write(memory, self.r[rx], self.d);
+                self.r[rx] = self.r[rx].wrapping_sub(1);
+            }
+            (0x7, 0x4) => self.add(self.read(memory, self.r[rx]), self.df),
+            (0x7, 0x5) => self.subtract(self.read(memory, self.r[rx]), self.d, self.df),
+            (0x7, 0x6) => {
+                let carry = self.df;
+                self.df = self.d & 1 == 1;
+                self.d = (self.d >> 1) | if carry { 0x80 } else { 0 };
+            }
+            (0x7, 0x7) => self.subtract(self.d, self.read(memory, self.r[rx]), self.df),
+            (0x7, 0x8) => self.write(memory, self.r[rx], self.t),
+            (0x7, 0x9) => {
+                self.t = (self.x << 4) | self.p;
+                self.write(memory, self.r[2], self.t);
+                self.x = self.p;
+                self.r[2] = self.r[2].wrapping_sub(1);
+            }
+            (0x7, 0xA) => self.q = false,
+            (0x7, 0xB) => self.q = true,
+            (0x7, 0xC) => {
+                let value = self.fetch(memory);
+                self.add(value, self.df);
+            }
+            (0x7, 0xD) => {
+                let value = self.fetch(memory);
+                self.subtract(value, self.d, self.df);
+            }
+            (0x7, 0xE) => {
+                let carry = self.df;
+                self.df = self.d & 0x80 != 0;
+                self.d = (self.d << 1) | carry as u8;
+            }
+            (0x7, _) => {
+                let value = self.fetch(memory);
+                self.subtract(self.d, value, self.df);
+            }
+            (0x8, _) => self.d = self.r[rn] as u8,
+            (0x9, _) => self.d = (self.r[rn] >> 8) as u8,
+            (0xA, _) => self.r[rn] = (self.r[rn] & 0xFF00) | self.d as u16,
+            (0xB, _) => self.r[rn] = (self.r[rn] & 0x00FF) | ((self.d as u16) << 8),
+            (0xC, _) => {
+                let condition = match n & 0x3 {
+                    0x0 => true,
+                    0x1 => self.q,
+                    0x2 => self.d == 0,
+                    _ => self.df,
+                };
+                match n {
+                    // NOP, and long skips on IE, Q, D and DF
+                    0x4 => {}
+                    0x5..=0x7 => self.long_skip(!condition),
+                    0x8 => self.long_skip(true),
+                    0xC => self.long_skip(self.ie),
+                    0xD..=0xF => self.long_skip(condition),
+                    0x0..=0x3 => self.long_branch(memory, condition),
+                    _ => self.long_branch(memory, !condition),
+                }
+                return 3;
+            }
+            (0xD, _) => self.p = n,
+            (0xE, _) => self.x = n,
+            (0xF, _) => {
+                // F8-FF take an immediate operand, except SHL
+                let value = match n {
+                    0x0..=0x7 | 0xE => self.read(memory, self.r[rx]),
+                    _ => self.fetch(memory),
+                };
+                match n & 0x7 {
+                    0x0 => self.d = value,
+                    0x1 => self.d |= value,
+                    0x2 => self.d &= value,
+                    0x3 => self.d ^= value,
+                    0x4 => self.add(value, false),
+                    0x5 => self.subtract(value, self.d, true),
+                    0x6 if n == 0x6 => {
+                        self.df = self.d & 1 == 1;
+                        self.d >>= 1;
+                    }
+                    0x6 => {
+                        self.df = self.d & 0x80 != 0;
+                        self.d <<= 1;
+                    }
+                    _ => self.subtract(self.d, value, true),
+                }
+            }
+            _ => unreachable!(),
+        }
+
+        2
+    }
+
+    fn short_branch(&mut self, memory: &Memory, taken: bool) {
+        let pc = self.r[self.p as usize];
+        if taken {
+            let target = self.read(memory, pc);
+            self.r[self.p as usize] = (pc & 0xFF00) | target as u16;
+        } else {
+            self.r[self.p as usize] = pc.wrapping_add(1);
+        }
+    }
+
+    fn long_branch(&mut self, memory: &Memory, taken: bool) {
+        let pc = self.r[self.p as usize];
+        if taken {
+            let high = self.read(memory, pc) as u16;
+            let low = self.read(memory, pc.wrapping_add(1)) as u16;
+            self.r[self.p as usize] = (high << 8) | low;
+        } else {
+            self.r[self.p as usize] = pc.wrapping_add(2);
+        }
+    }
+
+    fn long_skip(&mut self, taken: bool) {
+        if taken {
+            let pc = self.r[self.p as usize];
+            self.r[self.p as usize] = pc.wrapping_add(2);
+        }
+    }
+
+    fn add(&mut self, value: u8, carry: bool) {
+        let sum = self.d as u16 + value as u16 + carry as u16;
+        self.d = sum as u8;
+        self.df = sum > 0xFF;
+    }
+
+    // DF is set when there was no borrow, and `carry` is the inverted borrow in
+    fn subtract(&mut self, minuend: u8, subtrahend: u8, carry: bool) {
+        let difference = minuend as i16 - subtrahend as i16 - !carry as i16;
+        self.d = difference as u8;
+        self.df = difference >= 0;
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        if port == 2 {
+            self.key_latch = value & 0xF;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(program: &[u8], keyboard_state: &KeyboardState) -> (Cdp1802, Memory) {
+        let mut cpu = Cdp1802::new();
+        let mut memory = Memory::new();
+        memory.write_chunk(0x300, program.to_vec().into_boxed_slice());
+        cpu.r[2] = VIP_STACK;
+        cpu.x = 2;
+        cpu.call(0x300, &mut memory, keyboard_state, 1000).unwrap();
+        (cpu, memory)
+    }
+
+    #[test]
+    fn returns_on_sep_r4() {
+        let (cpu, _) = run(&[0xF8, 0x42, 0xD4], &KeyboardState::default());
+
+        assert_eq!(cpu.d, 0x42);
+        assert_eq!(cpu.r[3], 0x303);
+    }
+
+    #[test]
+    fn adds_and_subtracts_with_carry() {
+        // LDI 0xF0, ADI 0x20, PLO RA, LDI 0x10, SMI 0x20, D4
+        let (cpu, _) = run(
+            &[0xF8, 0xF0, 0xFC, 0x20, 0xAA, 0xF8, 0x10, 0xFF, 0x20, 0xD4],
+            &KeyboardState::default(),
+        );
+
+        assert_eq!(cpu.r[0xA] & 0xFF, 0x10);
+        assert_eq!(cpu.d, 0xF0);
+        assert!(!cpu.df);
+    }
+
+    #[test]
+    fn loops_with_short_branches_and_stores_through_registers() {
+        // LDI 0x03, PLO R7, LDI 0x0E, PHI R8, LDI 0x00, PLO R8
+        // loop: GLO R7, STR R8, INC R8, DEC R7, GLO R7, BNZ loop, D4
+        let program = [
+            0xF8, 0x03, 0xA7, 0xF8, 0x0E, 0xB8, 0xF8, 0x00, 0xA8, //
+            0x87, 0x58, 0x18, 0x27, 0x87, 0x3A, 0x09, 0xD4,
+        ];
+        let (_, memory) = run(&program, &KeyboardState::default());
+
+        assert_eq!(memory.read_chunk(0xE00, 4), vec![3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn long_branch_costs_three_cycles() {
+        let mut cpu = Cdp1802::new();
+        let mut memory = Memory::new();
+        // LBR 0x0310, then D4 at the target
+        memory.write_chunk(0x300, Box::new([0xC0, 0x03, 0x10]));
+        memory.write_byte(0x310, 0xD4);
+
+        let cycles = cpu.call(0x300, &mut memory, &KeyboardState::default(), 100);
+        assert_eq!(cycles, Some(5));
+    }
+
+    #[test]
+    fn reads_keypad_through_ef3() {
+        let mut keyboard_state = KeyboardState::default();
+        keyboard_state.set_key(0x5, true);
+        // SEX R3, OUT 2 (latch key 5), B3 pressed, LDI 0, D4, pressed: LDI 1, D4
+        let program = [
+            0xE3, 0x62, 0x05, 0x36, 0x09, 0xF8, 0x00, 0xD4, 0x00, 0xF8, 0x01, 0xD4,
+        ];
+        let (cpu, _) = run(&program, &keyboard_state);
+
+        assert_eq!(cpu.d, 1);
+    }
+
+    #[test]
+    fn gives_up_on_routines_that_never_return() {
+        let mut cpu = Cdp1802::new();
+        let mut memory = Memory::new();
+        // BR to itself
+        memory.write_chunk(0x300, Box::new([0x30, 0x00]));
+
+        assert_eq!(
+            cpu.call(0x300, &mut memory, &KeyboardState::default(), 100),
+            None
+        );
+    }
+}
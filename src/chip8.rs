@@ -1,10 +1,27 @@
+use super::browser::RomBrowser;
 use super::clock::Clock;
-use super::cpu::Cpu;
+use super::cpu::{Cpu, CpuError, ProgramChange};
+use super::database::Database;
+use super::debugger::Debugger;
 use super::display::Display;
-use super::keyboard::KeyboardState;
+use super::gdb::{GdbRequest, GdbStub};
+use super::hotkeys::{self, Controls, Hotkey};
+use super::keyboard::{KeyMap, KeyboardState};
 use super::memory::Memory;
+use super::opcode::Opcode;
+use super::osd::{Overlay, Stats, Status};
+use super::quirks::Quirks;
+use super::rom::{self, Layout, RomError};
+use super::script::{Script, ScriptError};
+use super::strict::Strict;
+use super::symbols::SymbolMap;
+use super::timing::VipTiming;
+use super::trace::Tracer;
+
 
 use minifb::Window;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use minifb::{Key, KeyRepeat};
 
 
@@ -25,27 +42,347 @@ pub struct Chip8 {
     memory: Memory,
     display: Display,
     window: Window,
+    tracer: Option<Tracer>,
+    strict: Option<Strict>,
+    script: Option<Script>,
+    debugger: Debugger,
+    cycles: u64,
+    timing: Option<VipTiming>,
+    cpu_hz: u64,
+    // Background and foreground colours, 0RGB
+    palette: [u32; 2],
+    key_map: KeyMap,
+    // Kept to reload on reset
+    program: Box<[u8]>,
+    layout: Layout,
+    seed: u64,
+    controls: Controls,
+    title: String,
+    quit: bool,
+    // Window pixels per CHIP-8 pixel
+    scale: usize,
+    overlay: Overlay,
+    stats: Stats,
+    presented_cycles: u64,
+    // Where the ROM browser looks, what it lists first, and what was picked in it
+    rom_dir: PathBuf,
+    recent: Vec<PathBuf>,
+    next_rom: Option<PathBuf>,
 }
 
 impl Chip8 {
-    pub fn new(program: Box<[u8]>, window: Window) -> Self {
-        let cpu = Cpu::new();
+    pub fn new(program: Box<[u8]>, layout: Layout, window: Window) -> Result<Self, RomError> {
+        let seed = rand::random();
+        let mut cpu = Cpu::with_seed(seed);
         let mut memory = Memory::new();
-        memory.write_chunk(0x200, program);
+        rom::load(&mut cpu, &mut memory, &program, layout)?;
 
-        Chip8 {
+        Ok(Chip8 {
             cpu: cpu,
             memory: memory,
             display: Display::new(),
             window: window,
+            tracer: None,
+            strict: None,
+            script: None,
+            debugger: Debugger::new(),
+            cycles: 0,
+            timing: None,
+            cpu_hz: 500,
+            palette: [0x000000, 0xFFFF00],
+            key_map: KeyMap::default(),
+            program: program,
+            layout: layout,
+            seed: seed,
+            controls: Controls::new(),
+            title: "CHIP8".to_string(),
+            quit: false,
+            scale: 1,
+            overlay: Overlay::new(),
+            stats: Stats::new(Instant::now()),
+            presented_cycles: 0,
+            rom_dir: PathBuf::from("."),
+            recent: Vec::new(),
+            next_rom: None,
+        })
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.cpu.reseed(seed);
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.cpu.set_quirks(quirks);
+    }
+
+    pub fn set_cycles_per_frame(&mut self, cycles: u64) {
+        self.cpu_hz = cycles * 60;
+    }
+
+    pub fn set_symbols(&mut self, symbols: SymbolMap) {
+        self.debugger.set_symbols(symbols);
+    }
+
+    // Stops in the debugger before the instruction at a label or hex address
+    pub fn add_breakpoint(&mut self, target: &str) -> Result<u16, String> {
+        self.debugger.add_breakpoint(target)
+    }
+
+    pub fn set_rom_dir(&mut self, dir: &Path) {
+        self.rom_dir = dir.to_path_buf();
+    }
+
+    pub fn set_recent(&mut self, recent: &[PathBuf]) {
+        self.recent = recent.to_vec();
+    }
+
+    // The ROM chosen in the browser, if that is why `run` returned. The caller
+    // builds a new machine for it, reusing the window.
+    pub fn take_next_rom(&mut self) -> Option<PathBuf> {
+        self.next_rom.take()
+    }
+
+    pub fn into_window(self) -> Window {
+        self.window
+    }
+
+    pub fn set_scale(&mut self, scale: usize) {
+        self.scale = scale;
+    }
+
+    pub fn set_palette(&mut self, palette: [u32; 2]) {
+        self.palette = palette;
+    }
+
+    pub fn set_key_map(&mut self, key_map: KeyMap) {
+        self.key_map = key_map;
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        self.title = title.to_string();
+        self.update_title();
+    }
+
+    // Shows pause and speed changes after the title
+    fn update_title(&mut self) {
+        let status = self.controls.status();
+        if status.is_empty() {
+            self.window.set_title(&self.title);
+        } else {
+            self.window.set_title(&format!("{} [{}]", self.title, status));
+        }
+    }
+
+    // A soft reset reloads the program and restarts the CPU like the reset switch,
+    // leaving the rest of memory alone. A hard reset clears memory as well.
+    fn reset(&mut self, hard: bool) {
+        if hard {
+            self.memory = Memory::new();
+            if self.strict.is_some() {
+                self.strict = Some(Strict::new(&self.program, self.layout));
+            }
+            self.overlay.show_message("Hard reset");
+        } else {
+            self.overlay.show_message("Reset");
+        }
+        self.cpu.reset(self.seed);
+        rom::load(&mut self.cpu, &mut self.memory, &self.program, self.layout)
+            .expect("ROM loaded before");
+        self.display.clear();
+        if self.timing.is_some() {
+            self.timing = Some(VipTiming::new());
+        }
+        self.present();
+    }
+
+    // Shows the ROMs in the ROM directory until one is picked or the browser is
+    // closed. The machine is stopped meanwhile, like in the debugger.
+    fn browse(&mut self) -> Option<PathBuf> {
+        let database = Database::bundled();
+        let mut browser = match RomBrowser::scan(&self.rom_dir, &self.recent, &database) {
+            Ok(browser) => browser,
+            Err(e) => {
+                self.overlay
+                    .show_message(&format!("Cannot read {}: {}", self.rom_dir.display(), e));
+                return None;
+            }
+        };
+        let (width, height) = (64 * self.scale, 32 * self.scale);
+        let page = RomBrowser::page_size(height, width) as isize;
+        let mut buffer = vec![0; width * height];
+
+        while self.window.is_open() {
+            browser.render(&mut buffer, width);
+            self.window.update_with_buffer(&buffer, width, height).unwrap();
+
+            for key in self.window.get_keys_pressed(KeyRepeat::Yes).unwrap_or_default() {
+                match key {
+                    Key::Up => browser.move_selection(-1),
+                    Key::Down => browser.move_selection(1),
+                    Key::PageUp => browser.move_selection(-page),
+                    Key::PageDown => browser.move_selection(page),
+                    Key::Enter => return browser.selected().map(|entry| entry.path.clone()),
+                    Key::Escape | Key::F7 => return None,
+                    _ => {}
+                }
+            }
+            std::thread::sleep(Duration::from_millis(16));
         }
+        None
+    }
+
+    // Returns true if anything changed, so the clocks restart from now
+    fn handle_hotkeys(&mut self) -> bool {
+        let pressed = hotkeys::pressed(&self.window);
+        for hotkey in pressed.iter() {
+            match self.controls.apply(*hotkey) {
+                Some(Hotkey::SoftReset) => self.reset(false),
+                Some(Hotkey::HardReset) => self.reset(true),
+                Some(Hotkey::Overlay) => self.overlay.show_stats = !self.overlay.show_stats,
+                Some(Hotkey::Browse) => {
+                    if let Some(path) = self.browse() {
+                        self.next_rom = Some(path);
+                        self.quit = true;
+                    }
+                }
+                Some(Hotkey::Quit) => self.quit = true,
+                _ => {}
+            }
+        }
+        if !pressed.is_empty() {
+            self.update_title();
+            self.present();
+        }
+        !pressed.is_empty()
+    }
+
+    pub fn set_vip_hybrid(&mut self, enabled: bool) {
+        self.cpu.set_vip_hybrid(enabled);
+    }
+
+    // Charges instructions their COSMAC VIP cost against each 60 Hz frame instead
+    // of running them at a fixed rate
+    pub fn set_vip_timing(&mut self, enabled: bool) {
+        self.timing = if enabled { Some(VipTiming::new()) } else { None };
+    }
+
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    // Warns on standard error and on screen about suspicious things the ROM does
+    pub fn set_strict(&mut self, strict: Strict) {
+        self.strict = Some(strict);
+    }
+
+    // Runs the script's top level, then calls its hooks as the machine runs
+    pub fn set_script(&mut self, mut script: Script) -> Result<(), ScriptError> {
+        script.start(&mut self.cpu, &mut self.memory, &mut self.display)?;
+        self.script = Some(script);
+        Ok(())
+    }
+
+    // A script that fails is reported and dropped, and the game carries on
+    fn run_script<F>(&mut self, hook: F)
+    where
+        F: FnOnce(&mut Script, &mut Cpu, &mut Memory, &mut Display) -> Result<(), ScriptError>,
+    {
+        if let Some(script) = &mut self.script {
+            match hook(script, &mut self.cpu, &mut self.memory, &mut self.display) {
+                Ok(()) => self.quit |= script.stopped(),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    self.overlay.show_message(&e.to_string());
+                    self.script = None;
+                }
+            }
+        }
+    }
+
+    fn step(&mut self, keyboard_state: &KeyboardState) -> Result<ProgramChange, CpuError> {
+        self.run_script(Script::before_step);
+        let held;
+        let keyboard_state = match &self.script {
+            Some(script) => {
+                held = script.keys_over(keyboard_state);
+                &held
+            }
+            None => keyboard_state,
+        };
+        if let Some(strict) = &mut self.strict {
+            strict.check(&self.cpu, &self.memory);
+            for diagnostic in strict.take_new() {
+                eprintln!("warning: {}", diagnostic);
+                self.overlay.show_message(&diagnostic.to_string());
+            }
+        }
+        let program_change = match &mut self.tracer {
+            Some(tracer) => {
+                let before = self.cpu.state();
+                // Read before the step, which may overwrite the instruction it runs
+                let instruction = if self.memory.contains(before.reg_pc, 2) {
+                    self.memory.read_doublebyte(before.reg_pc)
+                } else {
+                    0
+                };
+                let program_change =
+                    self.cpu
+                        .step(&mut self.memory, &mut self.display, keyboard_state)?;
+                // The game carries on without the trace
+                if let Err(e) = tracer.trace(self.cycles, instruction, &before, &self.cpu.state()) {
+                    eprintln!("warning: trace stopped: {}", e);
+                    self.overlay.show_message("Trace stopped");
+                    self.tracer = None;
+                }
+                program_change
+            }
+            None => self
+                .cpu
+                .step(&mut self.memory, &mut self.display, keyboard_state)?,
+        };
+        self.cycles += 1;
+
+        Ok(program_change)
+    }
+
+    fn present(&mut self) {
+        let [background, foreground] = self.palette;
+        let (width, height) = (64 * self.scale, 32 * self.scale);
+        let mut buffer = Vec::with_capacity(width * height);
+        for row in self.display.framebuffer.chunks(64) {
+            let mut line = Vec::with_capacity(width);
+            for pixel in row.iter() {
+                let color = if *pixel != 0 { foreground } else { background };
+                for _ in 0..self.scale {
+                    line.push(color);
+                }
+            }
+            for _ in 0..self.scale {
+                buffer.extend_from_slice(&line);
+            }
+        }
+
+        self.stats
+            .record(Instant::now(), self.cycles - self.presented_cycles);
+        self.presented_cycles = self.cycles;
+        let status = Status {
+            paused: !self.controls.running(),
+            speed_percent: self.controls.speed_percent(),
+            quirks: self.cpu.quirks().name().to_string(),
+            fps: self.stats.fps,
+            ips: self.stats.ips,
+        };
+        self.overlay.render(&mut buffer, width, &status);
+
+        self.window.update_with_buffer(&buffer, width, height).unwrap();
     }
 
     fn draw_to_frame(&mut self, frame: &mut [u8]) {
 
     }
 
-    pub fn run(&mut self) {
+    pub fn run(&mut self) -> Result<(), CpuError> {
         // let event_loop = EventLoop::new();
         // let mut input = WinitInputHelper::new();
         // let window = {
@@ -78,33 +415,144 @@ impl Chip8 {
         //     }
         // });
 
-        
-        let mut cpu_clock = Clock::new(500);
+
+        self.run_until(|_| false)
+    }
+
+    pub fn run_gdb(&mut self, port: u16) -> Result<(), Box<dyn std::error::Error>> {
+        let mut stub = GdbStub::listen(port)?;
+
+        loop {
+            match stub.handle_packet(&mut self.cpu, &mut self.memory)? {
+                GdbRequest::Wait => {}
+                GdbRequest::Step => {
+                    let keyboard_state =
+                        KeyboardState::get_keyoard_state(&mut self.window, &self.key_map);
+                    let result = self.step(&keyboard_state);
+                    self.present();
+                    match result {
+                        Ok(_) => stub.report_stop()?,
+                        Err(e) => stub.report_error(&e)?,
+                    }
+                }
+                GdbRequest::Continue => {
+                    let mut error = None;
+                    let mut first_step = true;
+                    let result = self.run_until(|cpu| {
+                        if first_step {
+                            first_step = false;
+                            return false;
+                        }
+                        match stub.interrupted() {
+                            Ok(interrupted) => interrupted || stub.hit_breakpoint(cpu.state().reg_pc),
+                            Err(e) => {
+                                error = Some(e);
+                                true
+                            }
+                        }
+                    });
+                    if let Some(e) = error {
+                        return Err(e.into());
+                    }
+                    if self.quit || !self.window.is_open() {
+                        return Ok(());
+                    }
+                    match result {
+                        Ok(()) => stub.report_stop()?,
+                        Err(e) => stub.report_error(&e)?,
+                    }
+                }
+                GdbRequest::Detach => {
+                    self.run()?;
+                    return Ok(());
+                }
+                GdbRequest::Kill => return Ok(()),
+            }
+        }
+    }
+
+    // At a fixed rate that is one instruction per CPU clock tick. With VIP timing
+    // it is as many as fit in what is left of the current frame.
+    fn ready_to_step(&mut self, cpu_clock: &mut Clock) -> bool {
+        match &mut self.timing {
+            Some(timing) => {
+                let state = self.cpu.state();
+                let opcode = if self.memory.contains(state.reg_pc, 2) {
+                    self.memory.fetch_opcode(state.reg_pc)
+                } else {
+                    Opcode::NOP
+                };
+                timing.start(opcode, &state)
+            }
+            None => cpu_clock.tick(),
+        }
+    }
+
+    // Runs the machine until `stop` returns true, checked before every instruction
+    fn run_until<F: FnMut(&Cpu) -> bool>(&mut self, mut stop: F) -> Result<(), CpuError> {
+        let mut cpu_clock = Clock::new(self.controls.cpu_hz(self.cpu_hz));
         let mut timer_clock = Clock::new(60);
         let mut keyboard_poll_clock = Clock::new(10);
-        let mut keyboard_state = KeyboardState::get_keyoard_state(&mut self.window);
+        let mut keyboard_state = KeyboardState::get_keyoard_state(&mut self.window, &self.key_map);
 
-        loop {
+        while !self.quit && self.window.is_open() {
             if keyboard_poll_clock.tick() {
-                keyboard_state = KeyboardState::get_keyoard_state(&mut self.window);
+                keyboard_state = KeyboardState::get_keyoard_state(&mut self.window, &self.key_map);
+
+                if self.window.is_key_pressed(Key::F1, KeyRepeat::No) {
+                    self.debugger.repl(&self.cpu, &mut self.memory);
+                }
+                if self.handle_hotkeys() {
+                    cpu_clock = Clock::new(self.controls.cpu_hz(self.cpu_hz));
+                    timer_clock = Clock::new(60);
+                }
+            }
+
+            if !self.controls.running() {
+                Clock::sleep_until_next_tick(vec![&keyboard_poll_clock]);
+                continue;
             }
             
-            if cpu_clock.tick() {
-                let program_change =
-                self.cpu
-                .step(&mut self.memory, &mut self.display, &keyboard_state);
-                if program_change.redraw == true {
-                    self.window
-                    .update_with_buffer(&self.display.framebuffer, 64, 32)
-                    .unwrap();
+            // At most a frame's worth at a time, so hotkeys and the screen keep up at any speed.
+            // VIP timing already stops at the end of each frame.
+            let budget = match self.timing {
+                Some(_) => u64::MAX,
+                None => (self.controls.cpu_hz(self.cpu_hz) / 60).max(1),
+            };
+            let mut steps = 0;
+            while steps < budget && self.ready_to_step(&mut cpu_clock) {
+                steps += 1;
+                if stop(&self.cpu) {
+                    return Ok(());
+                }
+                if self.debugger.should_break(self.cpu.state().reg_pc) {
+                    self.debugger.repl(&self.cpu, &mut self.memory);
+                    cpu_clock = Clock::new(self.controls.cpu_hz(self.cpu_hz));
+                    timer_clock = Clock::new(60);
+                }
+
+                let machine_code_cycles = self.cpu.machine_code_cycles();
+                self.step(&keyboard_state)?;
+                if let Some(timing) = &mut self.timing {
+                    timing.charge(self.cpu.machine_code_cycles() - machine_code_cycles);
                 }
             }
             
+            // The screen and overlay go out once per frame, however often the program draws
             if timer_clock.tick() {
                 self.cpu.tick_timers();
+                if let Some(timing) = &mut self.timing {
+                    timing.begin_frame();
+                }
+                self.run_script(Script::end_frame);
+                self.controls.end_frame();
+                self.overlay.tick();
+                self.present();
             }
 
             Clock::sleep_until_next_tick(vec![&keyboard_poll_clock, &cpu_clock, &timer_clock]);
         }
+
+        Ok(())
     }
 }
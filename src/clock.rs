@@ -9,7 +9,8 @@ pub struct Clock {
 impl Clock {
   pub fn new(hz: u64) -> Self {
     Clock {
-      duration: Duration::from_micros(1000000 / hz),
+      // Never zero, which would tick on every check
+      duration: Duration::from_micros((1000000 / hz).max(1)),
       instant: Instant::now(),
     }
   }
@@ -48,3 +49,14 @@ impl Clock {
     };
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn ticks_take_time_at_any_rate() {
+    assert_eq!(Clock::new(60).duration, Duration::from_micros(16666));
+    assert_eq!(Clock::new(4_000_000).duration, Duration::from_micros(1));
+  }
+}
@@ -0,0 +1,202 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const MAX_RECENT: usize = 10;
+
+// Settings a ROM is run with. Anything left unset falls through to the next
+// source: command line, then the ROM's own section, the ROM database, the
+// defaults section and finally the built-in defaults.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RomConfig {
+    pub platform: Option<String>,
+    pub quirks: Option<String>,
+    // Instructions per 60 Hz frame
+    pub speed: Option<u64>,
+    // Background then foreground, as #RRGGBB
+    pub palette: Option<Vec<String>>,
+    // Window pixels per CHIP-8 pixel
+    pub scale: Option<u32>,
+    // Host key names bound to CHIP-8 keys, like Space = 5
+    pub keys: BTreeMap<String, u8>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // Where the ROM browser looks, by default the directory of the running ROM
+    pub rom_dir: Option<PathBuf>,
+    pub defaults: RomConfig,
+    // Keyed by the ROM's SHA-1, like the ROM database
+    pub roms: BTreeMap<String, RomConfig>,
+}
+
+// Most recent first. Kept apart from the config so that saving it never
+// rewrites a file the user edits by hand.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Recent {
+    pub roms: Vec<PathBuf>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        error: toml::de::Error,
+    },
+    Serialize {
+        path: PathBuf,
+        error: toml::ser::Error,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            ConfigError::Parse { path, error } => write!(f, "{}: {}", path.display(), error),
+            ConfigError::Serialize { path, error } => write!(f, "{}: {}", path.display(), error),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// $XDG_CONFIG_HOME/chip8-emu/config.toml on Linux, the platform's equivalent elsewhere
+pub fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("chip8-emu").join("config.toml"))
+}
+
+// recent.toml beside the config file
+pub fn recent_path(config_path: &Path) -> PathBuf {
+    config_path.with_file_name("recent.toml")
+}
+
+// A missing file reads as the default, so the first run needs no setup
+fn load<T: DeserializeOwned + Default>(path: &Path) -> Result<T, ConfigError> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(T::default()),
+        Err(e) => {
+            return Err(ConfigError::Io {
+                path: path.to_path_buf(),
+                error: e,
+            })
+        }
+    };
+    toml::from_str(&text).map_err(|e| ConfigError::Parse {
+        path: path.to_path_buf(),
+        error: e,
+    })
+}
+
+fn save<T: Serialize>(value: &T, path: &Path) -> Result<(), ConfigError> {
+    let io_error = |e| ConfigError::Io {
+        path: path.to_path_buf(),
+        error: e,
+    };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(io_error)?;
+    }
+    let text = toml::to_string_pretty(value).map_err(|e| ConfigError::Serialize {
+        path: path.to_path_buf(),
+        error: e,
+    })?;
+    fs::write(path, text).map_err(io_error)
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        load(path.as_ref())
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    pub fn rom(&self, sha1: &str) -> Option<&RomConfig> {
+        self.roms.get(sha1)
+    }
+}
+
+impl Recent {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        load(path.as_ref())
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ConfigError> {
+        save(self, path.as_ref())
+    }
+
+    // Moves `path` to the front of the list
+    pub fn add(&mut self, path: &Path) {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        self.roms.retain(|recent| *recent != path);
+        self.roms.insert(0, path);
+        self.roms.truncate(MAX_RECENT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_defaults_and_rom_sections() {
+        let config = Config::from_toml(
+            r##"
+            [defaults]
+            speed = 10
+            palette = ["#000000", "#00FF00"]
+            keys = { Space = 5 }
+
+            [roms.193915dcde1365ae054c4eaa21a35baa27cd3356]
+            quirks = "chip8"
+            speed = 12
+            "##,
+        )
+        .unwrap();
+
+        assert_eq!(config.defaults.speed, Some(10));
+        assert_eq!(config.defaults.keys["Space"], 5);
+        let rom = config
+            .rom("193915dcde1365ae054c4eaa21a35baa27cd3356")
+            .unwrap();
+        assert_eq!(rom.quirks.as_deref(), Some("chip8"));
+        assert_eq!(rom.speed, Some(12));
+        assert_eq!(rom.palette, None);
+    }
+
+    #[test]
+    fn rejects_unknown_settings() {
+        assert!(Config::from_toml("[defaults]\nsped = 10").is_err());
+    }
+
+    #[test]
+    fn keeps_recent_roms_in_their_own_file() {
+        let dir = std::env::temp_dir().join(format!("chip8-recent-{}", std::process::id()));
+        let config_path = dir.join("config.toml");
+        let mut recent = Recent::default();
+        for i in 0..12 {
+            recent.add(Path::new(&format!("/missing/{}.ch8", i)));
+        }
+        recent.add(Path::new("/missing/5.ch8"));
+        recent.save(recent_path(&config_path)).unwrap();
+
+        assert_eq!(recent.roms.len(), MAX_RECENT);
+        assert_eq!(recent.roms[0], PathBuf::from("/missing/5.ch8"));
+        assert_eq!(Recent::load(dir.join("recent.toml")).unwrap(), recent);
+        assert!(!config_path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,10 +1,18 @@
+use super::cdp1802::{self, Cdp1802};
 use super::display;
 use super::keyboard::KeyboardState;
 use super::memory;
 use super::opcode::Opcode;
+use super::quirks::Quirks;
 use rand::prelude::*;
+use rand::rngs::StdRng;
 use rand::Rng;
-#[derive(Debug, Default)]
+use std::fmt;
+
+// About four seconds of VIP time, after which a 0nnn routine is assumed to have hung
+const MAX_MACHINE_CODE_CYCLES: u64 = 1_000_000;
+
+#[derive(Debug)]
 pub struct Cpu {
     reg_gp: [u8; 16],
     reg_i: u16,
@@ -13,19 +21,133 @@ pub struct Cpu {
     reg_pc: u16,
     reg_sp: u8,
     stack: [u16; 16],
-    thread_rng: ThreadRng
+    rng: StdRng,
+    last_draw_size: u8,
+    // Present when 0nnn calls run native 1802 code, as on the COSMAC VIP
+    machine_code: Option<Cdp1802>,
+    quirks: Quirks,
 }
 
 pub struct ProgramChange {
     pub redraw: bool,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum CpuError {
+    InvalidInstruction { pc: u16, instruction: u16 },
+    StackOverflow { pc: u16 },
+    StackUnderflow { pc: u16 },
+    AddressOutOfRange { pc: u16, address: u16 },
+    MachineCodeTimeout { pc: u16, address: u16 },
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuError::InvalidInstruction { pc, instruction } => {
+                write!(f, "{:#05X}: invalid instruction {:#06X}", pc, instruction)
+            }
+            CpuError::StackOverflow { pc } => write!(f, "{:#05X}: stack overflow", pc),
+            CpuError::StackUnderflow { pc } => write!(f, "{:#05X}: return with empty stack", pc),
+            CpuError::AddressOutOfRange { pc, address } => {
+                write!(f, "{:#05X}: address {:#06X} out of range", pc, address)
+            }
+            CpuError::MachineCodeTimeout { pc, address } => {
+                write!(f, "{:#05X}: machine code at {:#05X} did not return", pc, address)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CpuError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CpuState {
+    pub reg_gp: [u8; 16],
+    pub reg_i: u16,
+    pub reg_sound_timer: u8,
+    pub reg_delay: u8,
+    pub reg_pc: u16,
+    pub reg_sp: u8,
+}
+
 impl Cpu {
     pub fn new() -> Self {
-        let mut cpu = Cpu::default();
-        cpu.reg_pc = 0x200;
+        Cpu::with_seed(rand::random())
+    }
+
+    // A fixed seed makes RND, and therefore whole runs, reproducible
+    pub fn with_seed(seed: u64) -> Self {
+        Cpu {
+            reg_gp: [0; 16],
+            reg_i: 0,
+            reg_sound_timer: 0,
+            reg_delay: 0,
+            reg_pc: 0x200,
+            reg_sp: 0,
+            stack: [0; 16],
+            rng: StdRng::seed_from_u64(seed),
+            last_draw_size: 0,
+            machine_code: None,
+            quirks: Quirks::default(),
+        }
+    }
+
+    pub fn state(&self) -> CpuState {
+        CpuState {
+            reg_gp: self.reg_gp,
+            reg_i: self.reg_i,
+            reg_sound_timer: self.reg_sound_timer,
+            reg_delay: self.reg_delay,
+            reg_pc: self.reg_pc,
+            reg_sp: self.reg_sp,
+        }
+    }
+
+    pub fn set_state(&mut self, state: &CpuState) {
+        self.reg_gp = state.reg_gp;
+        self.reg_i = state.reg_i;
+        self.reg_sound_timer = state.reg_sound_timer;
+        self.reg_delay = state.reg_delay;
+        self.reg_pc = state.reg_pc;
+        self.reg_sp = state.reg_sp;
+    }
+
+    // Back to the power-on state, keeping the quirks and platform
+    pub fn reset(&mut self, seed: u64) {
+        let quirks = self.quirks;
+        let vip_hybrid = self.machine_code.is_some();
+        *self = Cpu::with_seed(seed);
+        self.quirks = quirks;
+        self.set_vip_hybrid(vip_hybrid);
+    }
+
+    pub fn reseed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    pub fn set_pc(&mut self, addr: u16) {
+        self.reg_pc = addr;
+    }
 
-        cpu
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn set_vip_hybrid(&mut self, enabled: bool) {
+        self.machine_code = if enabled { Some(Cdp1802::new()) } else { None };
+    }
+
+    pub fn machine_code_cycles(&self) -> u64 {
+        self.machine_code.as_ref().map_or(0, |machine| machine.cycles)
+    }
+
+    pub fn last_draw_size(&self) -> u8 {
+        self.last_draw_size
     }
 
     pub fn tick_timers(&mut self) {
@@ -43,12 +165,17 @@ impl Cpu {
         memory: &mut memory::Memory,
         display: &mut display::Display,
         keyboard_state: &KeyboardState,
-    ) -> ProgramChange {
-        let instruction = memory.read_doublebyte(self.reg_pc);
-        let opcode = Opcode::decode(instruction);
+    ) -> Result<ProgramChange, CpuError> {
+        if !memory.contains(self.reg_pc, 2) {
+            return Err(CpuError::AddressOutOfRange {
+                pc: self.reg_pc,
+                address: self.reg_pc,
+            });
+        }
+        let opcode = memory.fetch_opcode(self.reg_pc);
+        self.check(opcode, memory)?;
         let mut program_change = ProgramChange { redraw: false };
 
-        println!("{:#X?} - {:#X?}: {:x?}", self.reg_pc, instruction, opcode);
         // pause();
         let program_counter = match opcode {
             Opcode::CALL { addr } => {
@@ -57,13 +184,21 @@ impl Cpu {
             }
             Opcode::CLS => self.cls(display),
             Opcode::RET => self.ret(),
+            Opcode::SYS { addr } => {
+                program_change.redraw = true;
+                self.sys(addr, memory, display, keyboard_state)?
+            }
             Opcode::JP { addr } => self.jp_addr(addr),
+            Opcode::JP_V0 { addr } => self.jp_v0_addr(addr),
             Opcode::SE { x, byte } => self.se_vx_byte(x, byte),
             Opcode::SNE { x, byte } => self.sne_vx_byte(x, byte),
+            Opcode::SE_R { x, y } => self.se_vx_vy(x, y),
+            Opcode::SNE_R { x, y } => self.sne_vx_vy(x, y),
             Opcode::LD_IMM { x, byte } => self.ld_vx_byte(x, byte),
             Opcode::ADD_IMM { x, byte } => self.add_vx_byte(x, byte),
             Opcode::ADD_R { x, y } => self.add_vx_vy(x, y),
             Opcode::SUB_R { x, y } => self.sub_vx_vy(x,y),
+            Opcode::SUBN_R { x, y } => self.subn_vx_vy(x, y),
             Opcode::LD_R { x, y } => self.ld_vx_vy(x, y),
             Opcode::LDI_IMM { addr } => self.ld_i_addr(addr),
             Opcode::DRW { x, y, size } => {
@@ -74,15 +209,20 @@ impl Cpu {
             Opcode::SKP { x } => self.skp_vx(x, keyboard_state),
             Opcode::ADDI_R { x } => self.add_i_vx(x),
             Opcode::LD_M { x } => self.ld_vx_i(x, memory),
+            Opcode::ST_M { x } => self.ld_i_vx(x, memory),
+            Opcode::LD_F { x } => self.ld_f_vx(x),
+            Opcode::LD_B { x } => self.ld_b_vx(x, memory),
             Opcode::SET_DT { x } => self.set_dt(x),
             Opcode::SET_ST { x } => self.set_st(x),
             Opcode::LD_DT { x } => self.ld_dt(x),
             Opcode::AND { x, y } => self.and(x, y),
-            Opcode::SHR { x } => self.shr(x),
+            Opcode::OR_R { x, y } => self.or(x, y),
+            Opcode::SHR { x, y } => self.shr(x, y),
+            Opcode::SHL { x, y } => self.shl(x, y),
             Opcode::XOR_R { x, y } => self.xor_vx_vy(x, y),
             Opcode::LD_R_K { x } => self.ld_vx_k(x, keyboard_state),
             Opcode::RND { x, byte } => self.rnd_vx_byte(x, byte),
-            _ => panic!("Instruction: {:#X?} - not handled", instruction),
+            Opcode::NOP => unreachable!(),
         };
 
         match program_counter {
@@ -94,13 +234,91 @@ impl Cpu {
 
         // println!("I: {:#X?} GP: {:X?}", self.reg_i, self.reg_gp);
 
-        program_change
+        Ok(program_change)
+    }
+
+    // Rejects instructions that would fault before any state is changed,
+    // so an error always leaves the CPU at the offending instruction
+    fn check(&self, opcode: Opcode, memory: &memory::Memory) -> Result<(), CpuError> {
+        let pc = self.reg_pc;
+        let memory_range = |len: usize| {
+            if memory.contains(self.reg_i, len) {
+                Ok(())
+            } else {
+                Err(CpuError::AddressOutOfRange {
+                    pc: pc,
+                    address: self.reg_i,
+                })
+            }
+        };
+
+        match opcode {
+            Opcode::NOP => Err(CpuError::InvalidInstruction {
+                pc: pc,
+                instruction: memory.read_doublebyte(pc),
+            }),
+            Opcode::CALL { .. } if self.reg_sp as usize >= self.stack.len() => {
+                Err(CpuError::StackOverflow { pc: pc })
+            }
+            Opcode::RET if self.reg_sp == 0 => Err(CpuError::StackUnderflow { pc: pc }),
+            Opcode::SYS { .. } if self.machine_code.is_none() => {
+                Err(CpuError::InvalidInstruction {
+                    pc: pc,
+                    instruction: memory.read_doublebyte(pc),
+                })
+            }
+            Opcode::DRW { size, .. } => memory_range(size as usize),
+            Opcode::LD_M { x } | Opcode::ST_M { x } => memory_range(x as usize + 1),
+            Opcode::LD_B { .. } => memory_range(3),
+            _ => Ok(()),
+        }
+    }
+
+    // Calls 1802 code the way the VIP interpreter does, with the registers, timers
+    // and display mirrored into the VIP memory map around the call
+    fn sys(
+        &mut self,
+        addr: u16,
+        memory: &mut memory::Memory,
+        display: &mut display::Display,
+        keyboard_state: &KeyboardState,
+    ) -> Result<ProgramCounter, CpuError> {
+        let pc = self.reg_pc;
+        memory.write_chunk(cdp1802::VIP_REGISTERS, Box::new(self.reg_gp));
+        memory.write_chunk(cdp1802::VIP_DISPLAY, display.to_bytes().into_boxed_slice());
+
+        let machine = self.machine_code.as_mut().unwrap();
+        machine.r[0x0] = cdp1802::VIP_DISPLAY;
+        machine.r[0x2] = cdp1802::VIP_STACK;
+        machine.x = 2;
+        machine.r[0x5] = pc + 2;
+        machine.r[0x8] = ((self.reg_delay as u16) << 8) | self.reg_sound_timer as u16;
+        machine.r[0xA] = self.reg_i;
+        machine.r[0xB] = cdp1802::VIP_DISPLAY & 0xFF00;
+        machine
+            .call(addr, memory, keyboard_state, MAX_MACHINE_CODE_CYCLES)
+            .ok_or(CpuError::MachineCodeTimeout { pc: pc, address: addr })?;
+
+        self.reg_gp
+            .copy_from_slice(&memory.read_chunk(cdp1802::VIP_REGISTERS, 16));
+        display.load_bytes(&memory.read_chunk(cdp1802::VIP_DISPLAY, 256));
+        self.reg_delay = (machine.r[0x8] >> 8) as u8;
+        self.reg_sound_timer = machine.r[0x8] as u8;
+        self.reg_i = machine.r[0xA] & 0xFFF;
+
+        // The routine may move the CHIP-8 program counter held in R5
+        Ok(ProgramCounter::Jump(machine.r[0x5] & 0xFFF))
     }
 
     fn jp_addr(&mut self, addr: u16) -> ProgramCounter {
         ProgramCounter::Jump(addr)
     }
 
+    fn jp_v0_addr(&mut self, addr: u16) -> ProgramCounter {
+        let x = if self.quirks.jump_vx { addr >> 8 } else { 0 };
+        ProgramCounter::Jump(addr + self.reg_gp[x as usize] as u16)
+    }
+
     fn call_addr(&mut self, addr: u16) -> ProgramCounter {
         self.stack[self.reg_sp as usize] = self.reg_pc;
         self.reg_sp += 1;
@@ -123,37 +341,56 @@ impl Cpu {
         }
     }
 
-    fn ld_vx_byte(&mut self, x: u8, byte: u8) -> ProgramCounter {
+    fn se_vx_vy(&mut self, x: u8, y: u8) -> ProgramCounter {
+        if self.reg_gp[x as usize] == self.reg_gp[y as usize] {
+            ProgramCounter::Skip
+        } else {
+            ProgramCounter::Next
+        }
+    }
+
+    fn sne_vx_vy(&mut self, x: u8, y: u8) -> ProgramCounter {
+        if self.reg_gp[x as usize] == self.reg_gp[y as usize] {
+            ProgramCounter::Next
+        } else {
+            ProgramCounter::Skip
+        }
+    }
+
+    pub(crate) fn ld_vx_byte(&mut self, x: u8, byte: u8) -> ProgramCounter {
         self.reg_gp[x as usize] = byte;
         ProgramCounter::Next
     }
 
-    fn add_vx_byte(&mut self, x: u8, byte: u8) -> ProgramCounter {
+    pub(crate) fn add_vx_byte(&mut self, x: u8, byte: u8) -> ProgramCounter {
         let val: u16 = (self.reg_gp[x as usize] as u16) + (byte as u16);
         self.reg_gp[x as usize] = (val & 0xFF) as u8;
         ProgramCounter::Next
     }
 
-    fn add_vx_vy(&mut self, x: u8, y: u8) -> ProgramCounter {
+    pub(crate) fn add_vx_vy(&mut self, x: u8, y: u8) -> ProgramCounter {
         let val: u16 = (self.reg_gp[x as usize] as u16) + (self.reg_gp[y as usize] as u16);
-        self.reg_gp[0xF] = if val > 0xFF { 1 } else { 0 };
         self.reg_gp[x as usize] = (val & 0xFF) as u8;
+        // VF is written last so the flag wins when VF is also the destination
+        self.reg_gp[0xF] = if val > 0xFF { 1 } else { 0 };
         ProgramCounter::Next
     }
 
-    fn sub_vx_vy(&mut self, x: u8, y: u8) -> ProgramCounter {
-        self.reg_gp[x as usize] = if self.reg_gp[x as usize] > self.reg_gp[y as usize] {
-            self.reg_gp[0xF] = 1;
-            self.reg_gp[x as usize] - self.reg_gp[y as usize]
-        } else {
-            self.reg_gp[0xF] = 0;
-            0
-        };
+    pub(crate) fn sub_vx_vy(&mut self, x: u8, y: u8) -> ProgramCounter {
+        let (vx, vy) = (self.reg_gp[x as usize], self.reg_gp[y as usize]);
+        self.reg_gp[x as usize] = vx.wrapping_sub(vy);
+        self.reg_gp[0xF] = if vx >= vy { 1 } else { 0 };
+        ProgramCounter::Next
+    }
 
+    pub(crate) fn subn_vx_vy(&mut self, x: u8, y: u8) -> ProgramCounter {
+        let (vx, vy) = (self.reg_gp[x as usize], self.reg_gp[y as usize]);
+        self.reg_gp[x as usize] = vy.wrapping_sub(vx);
+        self.reg_gp[0xF] = if vy >= vx { 1 } else { 0 };
         ProgramCounter::Next
     }
 
-    fn ld_i_addr(&mut self, addr: u16) -> ProgramCounter {
+    pub(crate) fn ld_i_addr(&mut self, addr: u16) -> ProgramCounter {
         self.reg_i = addr;
         ProgramCounter::Next
     }
@@ -167,25 +404,26 @@ impl Cpu {
         display: &mut display::Display,
     ) -> ProgramCounter {
         let sprite = memory.read_chunk(self.reg_i, nibble as usize);
+        self.last_draw_size = nibble;
         let mut set_vflag = false;
-        display.draw(
-            self.reg_gp[x as usize],
-            self.reg_gp[y as usize],
-            sprite,
-            &mut set_vflag,
-        );
+        let (x, y) = (self.reg_gp[x as usize], self.reg_gp[y as usize]);
+        if self.quirks.clip_sprites {
+            display.draw_clipped(x, y, sprite, &mut set_vflag);
+        } else {
+            display.draw(x, y, sprite, &mut set_vflag);
+        }
 
         self.reg_gp[0xF] = set_vflag as u8;
 
         ProgramCounter::Next
     }
 
-    fn add_i_vx(&mut self, x: u8) -> ProgramCounter {
-        self.reg_i += self.reg_gp[x as usize] as u16;
+    pub(crate) fn add_i_vx(&mut self, x: u8) -> ProgramCounter {
+        self.reg_i = self.reg_i.wrapping_add(self.reg_gp[x as usize] as u16);
         ProgramCounter::Next
     }
 
-    fn ld_vx_vy(&mut self, x: u8, y: u8) -> ProgramCounter {
+    pub(crate) fn ld_vx_vy(&mut self, x: u8, y: u8) -> ProgramCounter {
         self.reg_gp[x as usize] = self.reg_gp[y as usize];
         ProgramCounter::Next
     }
@@ -210,21 +448,48 @@ impl Cpu {
         for i in 0..x + 1 {
             self.reg_gp[i as usize] = memory.read_byte(self.reg_i + (i as u16));
         }
+        if self.quirks.memory_increment {
+            self.reg_i = self.reg_i.wrapping_add(x as u16 + 1);
+        }
 
         ProgramCounter::Next
     }
 
-    fn set_dt(&mut self, x: u8) -> ProgramCounter {
+    fn ld_i_vx(&mut self, x: u8, memory: &mut memory::Memory) -> ProgramCounter {
+        for i in 0..x + 1 {
+            memory.write_byte(self.reg_i + (i as u16), self.reg_gp[i as usize]);
+        }
+        if self.quirks.memory_increment {
+            self.reg_i = self.reg_i.wrapping_add(x as u16 + 1);
+        }
+
+        ProgramCounter::Next
+    }
+
+    pub(crate) fn ld_f_vx(&mut self, x: u8) -> ProgramCounter {
+        self.reg_i = memory::FONT_ADDRESS + (self.reg_gp[x as usize] & 0xF) as u16 * 5;
+        ProgramCounter::Next
+    }
+
+    fn ld_b_vx(&mut self, x: u8, memory: &mut memory::Memory) -> ProgramCounter {
+        let value = self.reg_gp[x as usize];
+        memory.write_byte(self.reg_i, value / 100);
+        memory.write_byte(self.reg_i + 1, value / 10 % 10);
+        memory.write_byte(self.reg_i + 2, value % 10);
+        ProgramCounter::Next
+    }
+
+    pub(crate) fn set_dt(&mut self, x: u8) -> ProgramCounter {
         self.reg_delay = self.reg_gp[x as usize];
         ProgramCounter::Next
     }
 
-    fn set_st(&mut self, x: u8) -> ProgramCounter {
+    pub(crate) fn set_st(&mut self, x: u8) -> ProgramCounter {
         self.reg_sound_timer = self.reg_gp[x as usize];
         ProgramCounter::Next
     }
 
-    fn ld_dt(&mut self, x: u8) -> ProgramCounter {
+    pub(crate) fn ld_dt(&mut self, x: u8) -> ProgramCounter {
         self.reg_gp[x as usize] = self.reg_delay;
         ProgramCounter::Next
     }
@@ -236,24 +501,55 @@ impl Cpu {
         ProgramCounter::Jump(addr + 2)
     }
 
-    fn cls(&mut self, display: &mut display::Display) -> ProgramCounter {
+    pub(crate) fn cls(&mut self, display: &mut display::Display) -> ProgramCounter {
         display.clear();
         ProgramCounter::Next
     }
 
-    fn and(&mut self, x: u8, y: u8) -> ProgramCounter {
+    pub(crate) fn and(&mut self, x: u8, y: u8) -> ProgramCounter {
         self.reg_gp[x as usize] &= self.reg_gp[y as usize];
+        self.reset_vf();
+        ProgramCounter::Next
+    }
+
+    pub(crate) fn or(&mut self, x: u8, y: u8) -> ProgramCounter {
+        self.reg_gp[x as usize] |= self.reg_gp[y as usize];
+        self.reset_vf();
+        ProgramCounter::Next
+    }
+
+    fn reset_vf(&mut self) {
+        if self.quirks.vf_reset {
+            self.reg_gp[0xF] = 0;
+        }
+    }
+
+    // The operand the shift instructions read, Vx or Vy depending on the quirks
+    fn shift_operand(&self, x: u8, y: u8) -> u8 {
+        if self.quirks.shift_vy {
+            self.reg_gp[y as usize]
+        } else {
+            self.reg_gp[x as usize]
+        }
+    }
+
+    pub(crate) fn shr(&mut self, x: u8, y: u8) -> ProgramCounter {
+        let value = self.shift_operand(x, y);
+        self.reg_gp[x as usize] = value >> 1;
+        self.reg_gp[0xF] = value & 0b1;
         ProgramCounter::Next
     }
 
-    fn shr(&mut self, x: u8) -> ProgramCounter {
-        self.reg_gp[0xF] = self.reg_gp[x as usize] & 0b1;
-        self.reg_gp[x as usize] /= 2;
+    pub(crate) fn shl(&mut self, x: u8, y: u8) -> ProgramCounter {
+        let value = self.shift_operand(x, y);
+        self.reg_gp[x as usize] = value << 1;
+        self.reg_gp[0xF] = value >> 7;
         ProgramCounter::Next
     }
 
-    fn xor_vx_vy(&mut self, x: u8, y: u8) -> ProgramCounter {
+    pub(crate) fn xor_vx_vy(&mut self, x: u8, y: u8) -> ProgramCounter {
         self.reg_gp[x as usize] ^= self.reg_gp[y as usize];
+        self.reset_vf();
         ProgramCounter::Next
     }
 
@@ -267,16 +563,493 @@ impl Cpu {
         }
     }
 
-    fn rnd_vx_byte(&mut self, x: u8, byte: u8) -> ProgramCounter {
-        let num: u8 = self.thread_rng.gen();
+    pub(crate) fn rnd_vx_byte(&mut self, x: u8, byte: u8) -> ProgramCounter {
+        let num: u8 = self.rng.gen();
         self.reg_gp[x as usize] = byte & num;
         ProgramCounter::Next
     }
 }
 
-enum ProgramCounter {
+pub(crate) enum ProgramCounter {
     Wait,
     Next,
     Skip,
     Jump(u16),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::Display;
+    use crate::memory::Memory;
+
+    struct Machine {
+        cpu: Cpu,
+        memory: Memory,
+        display: Display,
+        keyboard_state: KeyboardState,
+    }
+
+    impl Machine {
+        fn new(program: &[u16]) -> Self {
+            let mut memory = Memory::new();
+            let bytes: Vec<u8> = program.iter().flat_map(|i| i.to_be_bytes().to_vec()).collect();
+            memory.write_chunk(0x200, bytes.into_boxed_slice());
+
+            Machine {
+                cpu: Cpu::with_seed(0),
+                memory: memory,
+                display: Display::new(),
+                keyboard_state: KeyboardState::default(),
+            }
+        }
+
+        fn step(&mut self) -> ProgramChange {
+            self.try_step().unwrap()
+        }
+
+        fn try_step(&mut self) -> Result<ProgramChange, CpuError> {
+            self.cpu
+                .step(&mut self.memory, &mut self.display, &self.keyboard_state)
+        }
+
+        fn run(&mut self, steps: usize) {
+            for _ in 0..steps {
+                self.step();
+            }
+        }
+    }
+
+    #[test]
+    fn cls_clears_framebuffer() {
+        let mut machine = Machine::new(&[0x00E0]);
+        machine.display.framebuffer[5] = 0x44FFFF00;
+        machine.step();
+        assert!(machine.display.framebuffer.iter().all(|pixel| *pixel == 0));
+        assert_eq!(machine.cpu.reg_pc, 0x202);
+    }
+
+    #[test]
+    fn call_and_ret_use_stack() {
+        let mut machine = Machine::new(&[0x2206, 0x0000, 0x0000, 0x00EE]);
+        let change = machine.step();
+        assert!(change.redraw);
+        assert_eq!(machine.cpu.reg_pc, 0x206);
+        assert_eq!(machine.cpu.reg_sp, 1);
+        assert_eq!(machine.cpu.stack[0], 0x200);
+
+        machine.step();
+        assert_eq!(machine.cpu.reg_pc, 0x202);
+        assert_eq!(machine.cpu.reg_sp, 0);
+    }
+
+    #[test]
+    fn jp_sets_pc() {
+        let mut machine = Machine::new(&[0x1ABC]);
+        machine.step();
+        assert_eq!(machine.cpu.reg_pc, 0xABC);
+    }
+
+    #[test]
+    fn jp_v0_adds_offset() {
+        let mut machine = Machine::new(&[0x6010, 0xB300]);
+        machine.run(2);
+        assert_eq!(machine.cpu.reg_pc, 0x310);
+    }
+
+    #[test]
+    fn se_and_sne_byte_skip() {
+        let mut machine = Machine::new(&[0x6A05, 0x3A05]);
+        machine.run(2);
+        assert_eq!(machine.cpu.reg_pc, 0x206);
+
+        let mut machine = Machine::new(&[0x6A05, 0x3A06]);
+        machine.run(2);
+        assert_eq!(machine.cpu.reg_pc, 0x204);
+
+        let mut machine = Machine::new(&[0x6A05, 0x4A06]);
+        machine.run(2);
+        assert_eq!(machine.cpu.reg_pc, 0x206);
+
+        let mut machine = Machine::new(&[0x6A05, 0x4A05]);
+        machine.run(2);
+        assert_eq!(machine.cpu.reg_pc, 0x204);
+    }
+
+    #[test]
+    fn se_and_sne_register_skip() {
+        let mut machine = Machine::new(&[0x6103, 0x6203, 0x5120]);
+        machine.run(3);
+        assert_eq!(machine.cpu.reg_pc, 0x208);
+
+        let mut machine = Machine::new(&[0x6103, 0x6204, 0x5120]);
+        machine.run(3);
+        assert_eq!(machine.cpu.reg_pc, 0x206);
+
+        let mut machine = Machine::new(&[0x6103, 0x6204, 0x9120]);
+        machine.run(3);
+        assert_eq!(machine.cpu.reg_pc, 0x208);
+
+        let mut machine = Machine::new(&[0x6103, 0x6203, 0x9120]);
+        machine.run(3);
+        assert_eq!(machine.cpu.reg_pc, 0x206);
+    }
+
+    #[test]
+    fn ld_and_add_immediate() {
+        let mut machine = Machine::new(&[0x6AFE, 0x7A03]);
+        machine.run(2);
+        // ADD Vx, byte wraps and leaves VF alone
+        assert_eq!(machine.cpu.reg_gp[0xA], 0x01);
+        assert_eq!(machine.cpu.reg_gp[0xF], 0);
+    }
+
+    #[test]
+    fn ld_register() {
+        let mut machine = Machine::new(&[0x6142, 0x8010]);
+        machine.run(2);
+        assert_eq!(machine.cpu.reg_gp[0], 0x42);
+    }
+
+    #[test]
+    fn bitwise_operations() {
+        let mut machine = Machine::new(&[0x60F0, 0x613C, 0x8011]);
+        machine.run(3);
+        assert_eq!(machine.cpu.reg_gp[0], 0xFC);
+
+        let mut machine = Machine::new(&[0x60F0, 0x613C, 0x8012]);
+        machine.run(3);
+        assert_eq!(machine.cpu.reg_gp[0], 0x30);
+
+        let mut machine = Machine::new(&[0x60F0, 0x613C, 0x8013]);
+        machine.run(3);
+        assert_eq!(machine.cpu.reg_gp[0], 0xCC);
+    }
+
+    #[test]
+    fn add_register_sets_carry() {
+        let mut machine = Machine::new(&[0x60FF, 0x6102, 0x8014]);
+        machine.run(3);
+        assert_eq!(machine.cpu.reg_gp[0], 0x01);
+        assert_eq!(machine.cpu.reg_gp[0xF], 1);
+
+        let mut machine = Machine::new(&[0x60FE, 0x6101, 0x8014]);
+        machine.run(3);
+        assert_eq!(machine.cpu.reg_gp[0], 0xFF);
+        assert_eq!(machine.cpu.reg_gp[0xF], 0);
+    }
+
+    #[test]
+    fn add_register_flag_wins_over_vf_result() {
+        let mut machine = Machine::new(&[0x6FFF, 0x6102, 0x8F14]);
+        machine.run(3);
+        assert_eq!(machine.cpu.reg_gp[0xF], 1);
+    }
+
+    #[test]
+    fn sub_register_wraps_and_sets_not_borrow() {
+        let mut machine = Machine::new(&[0x6005, 0x6103, 0x8015]);
+        machine.run(3);
+        assert_eq!(machine.cpu.reg_gp[0], 0x02);
+        assert_eq!(machine.cpu.reg_gp[0xF], 1);
+
+        let mut machine = Machine::new(&[0x6003, 0x6105, 0x8015]);
+        machine.run(3);
+        assert_eq!(machine.cpu.reg_gp[0], 0xFE);
+        assert_eq!(machine.cpu.reg_gp[0xF], 0);
+
+        // Equal operands do not borrow
+        let mut machine = Machine::new(&[0x6007, 0x6107, 0x8015]);
+        machine.run(3);
+        assert_eq!(machine.cpu.reg_gp[0], 0x00);
+        assert_eq!(machine.cpu.reg_gp[0xF], 1);
+    }
+
+    #[test]
+    fn subn_register_wraps_and_sets_not_borrow() {
+        let mut machine = Machine::new(&[0x6003, 0x6105, 0x8017]);
+        machine.run(3);
+        assert_eq!(machine.cpu.reg_gp[0], 0x02);
+        assert_eq!(machine.cpu.reg_gp[0xF], 1);
+
+        let mut machine = Machine::new(&[0x6005, 0x6103, 0x8017]);
+        machine.run(3);
+        assert_eq!(machine.cpu.reg_gp[0], 0xFE);
+        assert_eq!(machine.cpu.reg_gp[0xF], 0);
+    }
+
+    #[test]
+    fn shifts_set_flag_from_shifted_out_bit() {
+        let mut machine = Machine::new(&[0x6005, 0x8006]);
+        machine.run(2);
+        assert_eq!(machine.cpu.reg_gp[0], 0x02);
+        assert_eq!(machine.cpu.reg_gp[0xF], 1);
+
+        let mut machine = Machine::new(&[0x6081, 0x800E]);
+        machine.run(2);
+        assert_eq!(machine.cpu.reg_gp[0], 0x02);
+        assert_eq!(machine.cpu.reg_gp[0xF], 1);
+
+        let mut machine = Machine::new(&[0x6040, 0x800E]);
+        machine.run(2);
+        assert_eq!(machine.cpu.reg_gp[0], 0x80);
+        assert_eq!(machine.cpu.reg_gp[0xF], 0);
+    }
+
+    #[test]
+    fn ld_i_and_add_i() {
+        let mut machine = Machine::new(&[0xA300, 0x6010, 0xF01E]);
+        machine.run(3);
+        assert_eq!(machine.cpu.reg_i, 0x310);
+    }
+
+    #[test]
+    fn rnd_masks_with_byte() {
+        let mut machine = Machine::new(&[0xC00F, 0xC100]);
+        machine.run(2);
+        assert_eq!(machine.cpu.reg_gp[0] & 0xF0, 0);
+        assert_eq!(machine.cpu.reg_gp[1], 0);
+    }
+
+    #[test]
+    fn rnd_is_reproducible_with_seed() {
+        let mut a = Machine::new(&[0xC0FF, 0xC1FF]);
+        let mut b = Machine::new(&[0xC0FF, 0xC1FF]);
+        a.run(2);
+        b.run(2);
+        assert_eq!(a.cpu.state(), b.cpu.state());
+    }
+
+    #[test]
+    fn drw_draws_and_reports_collision() {
+        // Draw the "0" font glyph twice at the same spot
+        let mut machine = Machine::new(&[0x6000, 0xF029, 0xD005, 0xD005]);
+        machine.run(3);
+        assert_eq!(machine.cpu.reg_gp[0xF], 0);
+        assert_eq!(machine.cpu.last_draw_size(), 5);
+        assert_ne!(machine.display.framebuffer[0], 0);
+        assert_eq!(machine.display.framebuffer[64 + 1], 0);
+
+        let change = machine.step();
+        assert!(change.redraw);
+        assert_eq!(machine.cpu.reg_gp[0xF], 1);
+        assert!(machine.display.framebuffer.iter().all(|pixel| *pixel == 0));
+    }
+
+    #[test]
+    fn skp_and_sknp_follow_keyboard() {
+        let mut machine = Machine::new(&[0x6007, 0xE09E]);
+        machine.run(2);
+        assert_eq!(machine.cpu.reg_pc, 0x204);
+
+        let mut machine = Machine::new(&[0x6007, 0xE0A1]);
+        machine.run(2);
+        assert_eq!(machine.cpu.reg_pc, 0x206);
+    }
+
+    #[test]
+    fn skp_and_sknp_with_key_pressed() {
+        let mut machine = Machine::new(&[0x6007, 0xE09E]);
+        machine.keyboard_state.set_key(0x7, true);
+        machine.run(2);
+        assert_eq!(machine.cpu.reg_pc, 0x206);
+
+        let mut machine = Machine::new(&[0x6007, 0xE0A1]);
+        machine.keyboard_state.set_key(0x7, true);
+        machine.run(2);
+        assert_eq!(machine.cpu.reg_pc, 0x204);
+    }
+
+    #[test]
+    fn ld_key_waits_for_keypress() {
+        let mut machine = Machine::new(&[0xF00A]);
+        machine.run(3);
+        assert_eq!(machine.cpu.reg_pc, 0x200);
+
+        machine.keyboard_state.set_key(0xB, true);
+        machine.step();
+        assert_eq!(machine.cpu.reg_pc, 0x202);
+        assert_eq!(machine.cpu.reg_gp[0], 0xB);
+    }
+
+    #[test]
+    fn timers_load_and_count_down() {
+        let mut machine = Machine::new(&[0x6002, 0xF015, 0xF018, 0xF107]);
+        machine.run(3);
+        assert_eq!(machine.cpu.reg_delay, 2);
+        assert_eq!(machine.cpu.reg_sound_timer, 2);
+
+        machine.cpu.tick_timers();
+        machine.step();
+        assert_eq!(machine.cpu.reg_gp[1], 1);
+
+        machine.cpu.tick_timers();
+        machine.cpu.tick_timers();
+        assert_eq!(machine.cpu.reg_delay, 0);
+        assert_eq!(machine.cpu.reg_sound_timer, 0);
+    }
+
+    #[test]
+    fn ld_f_points_at_font_glyph() {
+        let mut machine = Machine::new(&[0x600A, 0xF029]);
+        machine.run(2);
+        assert_eq!(machine.cpu.reg_i, crate::memory::FONT_ADDRESS + 0xA * 5);
+        assert_eq!(machine.memory.read_byte(machine.cpu.reg_i), 0xF0);
+    }
+
+    #[test]
+    fn ld_b_stores_bcd() {
+        let mut machine = Machine::new(&[0x60FE, 0xA300, 0xF033]);
+        machine.run(3);
+        assert_eq!(machine.memory.read_chunk(0x300, 3), vec![2, 5, 4]);
+    }
+
+    #[test]
+    fn store_and_load_registers() {
+        let mut machine = Machine::new(&[0x6011, 0x6122, 0x6233, 0xA300, 0xF155, 0xF265]);
+        machine.run(5);
+        assert_eq!(machine.memory.read_chunk(0x300, 3), vec![0x11, 0x22, 0x00]);
+        assert_eq!(machine.cpu.reg_i, 0x300);
+
+        machine.step();
+        assert_eq!(machine.cpu.reg_gp[..3], [0x11, 0x22, 0x00]);
+    }
+
+    #[test]
+    fn invalid_instruction_is_an_error() {
+        let mut machine = Machine::new(&[0xFFFF]);
+        assert_eq!(
+            machine.try_step().err(),
+            Some(CpuError::InvalidInstruction {
+                pc: 0x200,
+                instruction: 0xFFFF
+            })
+        );
+        assert_eq!(machine.cpu.reg_pc, 0x200);
+    }
+
+    #[test]
+    fn chip8_quirks() {
+        // LD V0, 0x0F; LD V1, 0x02; LD VF, 1; OR V0, V1; SHR V2, V1; LD I, 0x300; LD [I], V1
+        let mut machine = Machine::new(&[0x600F, 0x6102, 0x6F01, 0x8011, 0x8216, 0xA300, 0xF155]);
+        machine.cpu.set_quirks(Quirks::preset("chip8").unwrap());
+        machine.run(4);
+        assert_eq!(machine.cpu.reg_gp[0xF], 0);
+
+        machine.run(1);
+        assert_eq!(machine.cpu.reg_gp[2], 0x01);
+        assert_eq!(machine.cpu.reg_gp[0xF], 0);
+
+        machine.run(2);
+        assert_eq!(machine.cpu.reg_i, 0x302);
+    }
+
+    #[test]
+    fn schip_jump_quirk() {
+        // LD V2, 0x10; JP V0, 0x230 (as JP V2, 0x230)
+        let mut machine = Machine::new(&[0x6210, 0xB230]);
+        machine.cpu.set_quirks(Quirks::preset("schip").unwrap());
+        machine.run(2);
+
+        assert_eq!(machine.cpu.reg_pc, 0x240);
+    }
+
+    #[test]
+    fn sys_without_vip_hybrid_is_an_error() {
+        let mut machine = Machine::new(&[0x0300]);
+        assert_eq!(
+            machine.try_step().err(),
+            Some(CpuError::InvalidInstruction {
+                pc: 0x200,
+                instruction: 0x0300
+            })
+        );
+    }
+
+    #[test]
+    fn sys_runs_machine_code_on_mirrored_registers() {
+        let mut machine = Machine::new(&[0x6041, 0xA123, 0x0300]);
+        machine.cpu.set_vip_hybrid(true);
+        // LDI 0x0E, PHI R6, LDI 0xF0, PLO R6, LDN R6, ADI 1, STR R6 (V0 += 1),
+        // INC RA (I += 1), LDI 0x80, STR RB (top left pixel), D4
+        let routine = [
+            0xF8, 0x0E, 0xB6, 0xF8, 0xF0, 0xA6, 0x06, 0xFC, 0x01, 0x56, //
+            0x1A, 0xF8, 0x80, 0x5B, 0xD4,
+        ];
+        machine
+            .memory
+            .write_chunk(0x300, routine.to_vec().into_boxed_slice());
+        machine.run(3);
+
+        assert_eq!(machine.cpu.reg_gp[0], 0x42);
+        assert_eq!(machine.cpu.reg_i, 0x124);
+        assert_eq!(machine.cpu.reg_pc, 0x206);
+        assert_ne!(machine.display.framebuffer[0], 0);
+    }
+
+    #[test]
+    fn stack_overflow_is_an_error() {
+        let mut machine = Machine::new(&[0x2200]);
+        machine.run(16);
+        assert_eq!(
+            machine.try_step().err(),
+            Some(CpuError::StackOverflow { pc: 0x200 })
+        );
+        assert_eq!(machine.cpu.reg_sp, 16);
+    }
+
+    #[test]
+    fn stack_underflow_is_an_error() {
+        let mut machine = Machine::new(&[0x00EE]);
+        assert_eq!(
+            machine.try_step().err(),
+            Some(CpuError::StackUnderflow { pc: 0x200 })
+        );
+    }
+
+    #[test]
+    fn memory_access_past_end_is_an_error() {
+        let mut machine = Machine::new(&[0xAFFE, 0xD005]);
+        machine.step();
+        assert_eq!(
+            machine.try_step().err(),
+            Some(CpuError::AddressOutOfRange {
+                pc: 0x202,
+                address: 0xFFE
+            })
+        );
+
+        let mut machine = Machine::new(&[0xAFFF, 0xF155]);
+        machine.step();
+        assert!(machine.try_step().is_err());
+        assert_eq!(machine.memory.read_byte(0xFFF), 0);
+    }
+
+    #[test]
+    fn fetch_past_end_is_an_error() {
+        let mut machine = Machine::new(&[0x1FFF]);
+        machine.step();
+        assert_eq!(
+            machine.try_step().err(),
+            Some(CpuError::AddressOutOfRange {
+                pc: 0xFFF,
+                address: 0xFFF
+            })
+        );
+    }
+
+    #[test]
+    fn reset_keeps_quirks_and_platform() {
+        let quirks = Quirks::preset("chip8").unwrap();
+        let mut machine = Machine::new(&[0x6A05, 0x2300]);
+        machine.cpu.set_quirks(quirks);
+        machine.cpu.set_vip_hybrid(true);
+        machine.run(2);
+
+        machine.cpu.reset(0);
+        let state = machine.cpu.state();
+        assert_eq!(state, Cpu::with_seed(0).state());
+        assert_eq!(machine.cpu.quirks(), quirks);
+        assert!(machine.cpu.machine_code.is_some());
+    }
+}
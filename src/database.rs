@@ -0,0 +1,156 @@
+use super::keyboard::KeyMap;
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+// Laid out like the community CHIP-8 database: programs, each with the ROM
+// files known for it keyed by SHA-1
+const BUNDLED: &str = include_str!("../data/programs.json");
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Program {
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub authors: Vec<String>,
+    pub roms: BTreeMap<String, RomInfo>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RomInfo {
+    pub file: Option<String>,
+    pub platform: Option<String>,
+    pub quirks: Option<String>,
+    // Instructions per 60 Hz frame
+    pub tickrate: Option<u64>,
+    // Background then foreground, as #RRGGBB
+    #[serde(default)]
+    pub colors: Vec<String>,
+    // What each CHIP-8 key does in the game, like "left": 4
+    #[serde(default)]
+    pub keys: BTreeMap<String, u8>,
+    // Extra host keys to bind, like "Left": 4
+    #[serde(default)]
+    pub keymap: BTreeMap<String, u8>,
+}
+
+#[derive(Debug)]
+pub struct Entry<'a> {
+    pub program: &'a Program,
+    pub rom: &'a RomInfo,
+}
+
+impl<'a> Entry<'a> {
+    pub fn palette(&self) -> Option<[u32; 2]> {
+        match self.rom.colors.as_slice() {
+            [background, foreground, ..] => {
+                Some([parse_color(background)?, parse_color(foreground)?])
+            }
+            _ => None,
+        }
+    }
+
+    pub fn key_map(&self) -> Result<KeyMap, String> {
+        let mut key_map = KeyMap::default();
+        for (host_key, key) in self.rom.keymap.iter() {
+            key_map.bind(host_key, *key)?;
+        }
+        Ok(key_map)
+    }
+
+    // One line per action, naming the host keys that press it
+    pub fn controls(&self, key_map: &KeyMap) -> Vec<String> {
+        self.rom
+            .keys
+            .iter()
+            .map(|(action, key)| {
+                format!(
+                    "{}: {} (key {:X})",
+                    action,
+                    key_map.host_keys(*key).join("/"),
+                    key
+                )
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Database {
+    programs: Vec<Program>,
+}
+
+impl Database {
+    pub fn bundled() -> Self {
+        Database::from_json(BUNDLED).expect("Bundled ROM database is invalid")
+    }
+
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        Ok(Database {
+            programs: serde_json::from_str(json)?,
+        })
+    }
+
+    pub fn lookup(&self, program: &[u8]) -> Option<Entry<'_>> {
+        let hash = sha1_hex(program);
+        self.programs.iter().find_map(|entry| {
+            entry.roms.get(&hash).map(|rom| Entry {
+                program: entry,
+                rom: rom,
+            })
+        })
+    }
+}
+
+pub fn sha1_hex(bytes: &[u8]) -> String {
+    sha1::Sha1::from(bytes).digest().to_string()
+}
+
+pub fn parse_color(value: &str) -> Option<u32> {
+    let value = value.trim_start_matches('#');
+    if value.len() != 6 {
+        return None;
+    }
+    u32::from_str_radix(value, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_bundled_roms_by_hash() {
+        let database = Database::bundled();
+        let breakout = include_bytes!("../roms/breakout.ch8");
+        let entry = database.lookup(breakout).unwrap();
+
+        assert_eq!(entry.program.title, "Breakout");
+        assert_eq!(entry.rom.tickrate, Some(8));
+        assert_eq!(
+            entry.controls(&KeyMap::default()),
+            vec!["left: Q (key 4)", "right: E (key 6)"]
+        );
+        assert!(database.lookup(&breakout[1..]).is_none());
+    }
+
+    #[test]
+    fn reads_palette_and_key_map() {
+        let json = r##"[{
+            "title": "Test",
+            "roms": {
+                "da39a3ee5e6b4b0d3255bfef95601890afd80709": {
+                    "colors": ["#102030", "#FFAA00"],
+                    "keys": { "jump": 5 },
+                    "keymap": { "Space": 5 }
+                }
+            }
+        }]"##;
+        let database = Database::from_json(json).unwrap();
+        let entry = database.lookup(&[]).unwrap();
+        let key_map = entry.key_map().unwrap();
+
+        assert_eq!(entry.palette(), Some([0x102030, 0xFFAA00]));
+        assert_eq!(entry.controls(&key_map), vec!["jump: W/Space (key 5)"]);
+    }
+}
@@ -0,0 +1,389 @@
+use super::cpu::Cpu;
+use super::memory::Memory;
+use super::sprite::SpriteSheet;
+use super::symbols::SymbolMap;
+
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+
+const BYTES_PER_ROW: usize = 16;
+
+const HIGHLIGHT_PC: &str = "\x1b[7m";
+const HIGHLIGHT_I: &str = "\x1b[33m";
+const HIGHLIGHT_WRITE: &str = "\x1b[31m";
+const HIGHLIGHT_RESET: &str = "\x1b[0m";
+
+#[derive(Debug, Default)]
+pub struct Debugger {
+    last_address: u16,
+    symbols: Option<SymbolMap>,
+    breakpoints: BTreeSet<u16>,
+    // Stop again before the next instruction
+    stepping: bool,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger::default()
+    }
+
+    pub fn set_symbols(&mut self, symbols: SymbolMap) {
+        self.symbols = Some(symbols);
+    }
+
+    // `target` is a label or a hex address
+    pub fn add_breakpoint(&mut self, target: &str) -> Result<u16, String> {
+        let address = self.resolve(target)?;
+        self.breakpoints.insert(address);
+        Ok(address)
+    }
+
+    // Checked before every instruction
+    pub fn should_break(&self, pc: u16) -> bool {
+        self.stepping || self.breakpoints.contains(&pc)
+    }
+
+    fn resolve(&self, target: &str) -> Result<u16, String> {
+        self.symbols
+            .as_ref()
+            .and_then(|symbols| symbols.address_of(target))
+            .or_else(|| parse_number(target))
+            .ok_or_else(|| format!("No label or address {}", target))
+    }
+
+    fn describe(&self, address: u16) -> String {
+        match &self.symbols {
+            Some(symbols) => symbols.describe(address),
+            None => format!("{:#05X}", address),
+        }
+    }
+
+    // Blocks on stdin until the user resumes the machine
+    pub fn repl(&mut self, cpu: &Cpu, memory: &mut Memory) {
+        let stdin = io::stdin();
+        if !self.stepping {
+            println!("Paused. Type 'help' for a list of commands.");
+        }
+        println!("{}", self.describe(cpu.state().reg_pc));
+        prompt();
+
+        for line in stdin.lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            let args: Vec<&str> = line.split_whitespace().collect();
+
+            match args.as_slice() {
+                [] => {}
+                ["c"] | ["continue"] => {
+                    self.stepping = false;
+                    break;
+                }
+                ["n"] | ["step"] => {
+                    self.stepping = true;
+                    break;
+                }
+                ["where"] => println!("{}", self.describe(cpu.state().reg_pc)),
+                ["break", target] => match self.add_breakpoint(target) {
+                    Ok(address) => println!("Breakpoint at {}", self.describe(address)),
+                    Err(message) => println!("{}", message),
+                },
+                ["delete", target] => match self.resolve(target) {
+                    Ok(address) if self.breakpoints.remove(&address) => {}
+                    Ok(address) => println!("No breakpoint at {}", self.describe(address)),
+                    Err(message) => println!("{}", message),
+                },
+                ["breaks"] => {
+                    for address in self.breakpoints.iter() {
+                        println!("  {}", self.describe(*address));
+                    }
+                }
+                ["m", rest @ ..] | ["mem", rest @ ..] => match parse_view_args(rest, self.last_address) {
+                    Some((address, len)) => {
+                        print!("{}", hex_dump(cpu, memory, address, len));
+                        self.last_address = address.wrapping_add(len as u16);
+                    }
+                    None => println!("Usage: mem [addr] [len]"),
+                },
+                ["b", rest @ ..] | ["bits", rest @ ..] => match parse_view_args(rest, cpu.state().reg_i) {
+                    Some((address, len)) => print!("{}", bit_dump(memory, address, len)),
+                    None => println!("Usage: bits [addr] [len]"),
+                },
+                ["poke", address, values @ ..] if !values.is_empty() => {
+                    match poke(memory, address, values) {
+                        Ok(()) => {}
+                        Err(message) => println!("{}", message),
+                    }
+                }
+                ["s", rest @ ..] | ["sprite", rest @ ..] => match sprite_sheet(cpu, memory, rest) {
+                    Some(sheet) => print!("{}", sheet.to_text()),
+                    None => println!("Usage: sprite [addr] [height] [count]"),
+                },
+                ["export", format, path, rest @ ..] => match sprite_sheet(cpu, memory, rest) {
+                    Some(sheet) => {
+                        let result = match *format {
+                            "png" => sheet.export_png(path),
+                            "src" => sheet.export_source(path),
+                            _ => {
+                                println!("Unknown export format: {}", format);
+                                Ok(())
+                            }
+                        };
+                        if let Err(e) = result {
+                            println!("Export failed: {}", e);
+                        }
+                    }
+                    None => println!("Usage: export <png|src> <file> [addr] [height] [count]"),
+                },
+                ["r"] | ["regs"] => print_registers(cpu),
+                ["help"] => print_help(),
+                _ => println!("Unknown command: {}", line.trim()),
+            }
+
+            prompt();
+        }
+    }
+}
+
+fn prompt() {
+    print!("(chip8) ");
+    io::stdout().flush().unwrap();
+}
+
+fn print_help() {
+    println!("  mem [addr] [len]        hex dump with ASCII (PC inverted, I yellow, recent writes red)");
+    println!("  bits [addr] [len]       bit-pattern view, defaults to I");
+    println!("  poke <addr> <byte>...   write bytes starting at addr");
+    println!("  sprite [addr] [h] [n]   render n sprites of height h (0 = 16x16), defaults to I and the last DRW");
+    println!("  export <png|src> <file> [addr] [h] [n]");
+    println!("  regs                    show registers");
+    println!("  where                   show the PC with its label and source line");
+    println!("  break <label|addr>      stop before running the instruction there");
+    println!("  delete <label|addr>     remove a breakpoint");
+    println!("  breaks                  list breakpoints");
+    println!("  step                    run one instruction");
+    println!("  continue                resume emulation");
+}
+
+fn print_registers(cpu: &Cpu) {
+    let state = cpu.state();
+    for (i, value) in state.reg_gp.iter().enumerate() {
+        print!("V{:X}={:02X} ", i, value);
+    }
+    println!();
+    println!(
+        "PC={:03X} I={:03X} SP={:X} DT={:02X} ST={:02X}",
+        state.reg_pc, state.reg_i, state.reg_sp, state.reg_delay, state.reg_sound_timer
+    );
+}
+
+fn parse_number(value: &str) -> Option<u16> {
+    let value = value.trim_start_matches("0x");
+    u16::from_str_radix(value, 16).ok()
+}
+
+fn parse_view_args(args: &[&str], default_address: u16) -> Option<(u16, usize)> {
+    let address = match args.get(0) {
+        Some(address) => parse_number(address)?,
+        None => default_address,
+    };
+    let len = match args.get(1) {
+        Some(len) => parse_number(len)? as usize,
+        None => 0x80,
+    };
+
+    Some((address, len))
+}
+
+fn sprite_sheet(cpu: &Cpu, memory: &Memory, args: &[&str]) -> Option<SpriteSheet> {
+    let address = match args.get(0) {
+        Some(address) => parse_number(address)?,
+        None => cpu.state().reg_i,
+    };
+    let height = match args.get(1) {
+        Some(height) => parse_number(height).filter(|h| *h <= 0xF)? as u8,
+        None => cpu.last_draw_size(),
+    };
+    let count = match args.get(2) {
+        Some(count) => parse_number(count)? as usize,
+        None => 1,
+    };
+    let len = if height == 0 { 32 } else { height as usize } * count;
+    let len = clamp_len(memory, address, len);
+    if len == 0 {
+        return None;
+    }
+
+    Some(SpriteSheet::decode(address, &memory.read_chunk(address, len), height))
+}
+
+fn poke(memory: &mut Memory, address: &str, values: &[&str]) -> Result<(), String> {
+    let address = parse_number(address).ok_or(format!("Invalid address: {}", address))?;
+    if address as usize + values.len() > memory.size() {
+        return Err(format!("Address out of range: {:#X}", address));
+    }
+
+    // Nothing is written unless every byte is valid
+    let bytes = values
+        .iter()
+        .map(|value| {
+            parse_number(value)
+                .filter(|byte| *byte <= 0xFF)
+                .map(|byte| byte as u8)
+                .ok_or(format!("Invalid byte: {}", value))
+        })
+        .collect::<Result<Vec<u8>, String>>()?;
+    for (offset, byte) in bytes.into_iter().enumerate() {
+        memory.write_byte(address + offset as u16, byte);
+    }
+
+    Ok(())
+}
+
+fn clamp_len(memory: &Memory, address: u16, len: usize) -> usize {
+    len.min(memory.size().saturating_sub(address as usize))
+}
+
+pub fn hex_dump(cpu: &Cpu, memory: &Memory, address: u16, len: usize) -> String {
+    let state = cpu.state();
+    let bytes = memory.read_chunk(address, clamp_len(memory, address, len));
+    let mut out = String::new();
+
+    for (row, chunk) in bytes.chunks(BYTES_PER_ROW).enumerate() {
+        let row_address = address as usize + row * BYTES_PER_ROW;
+        out.push_str(&format!("{:03X}: ", row_address));
+
+        for (i, byte) in chunk.iter().enumerate() {
+            let byte_address = (row_address + i) as u16;
+            let highlight = if byte_address == state.reg_pc || byte_address == state.reg_pc + 1 {
+                Some(HIGHLIGHT_PC)
+            } else if byte_address == state.reg_i {
+                Some(HIGHLIGHT_I)
+            } else if memory.was_recently_written(byte_address) {
+                Some(HIGHLIGHT_WRITE)
+            } else {
+                None
+            };
+
+            match highlight {
+                Some(color) => out.push_str(&format!("{}{:02X}{} ", color, byte, HIGHLIGHT_RESET)),
+                None => out.push_str(&format!("{:02X} ", byte)),
+            }
+        }
+
+        for _ in chunk.len()..BYTES_PER_ROW {
+            out.push_str("   ");
+        }
+
+        out.push(' ');
+        for byte in chunk.iter() {
+            out.push(if byte.is_ascii_graphic() || *byte == b' ' {
+                *byte as char
+            } else {
+                '.'
+            });
+        }
+        out.push('\n');
+    }
+
+    out
+}
+
+pub fn bit_dump(memory: &Memory, address: u16, len: usize) -> String {
+    let bytes = memory.read_chunk(address, clamp_len(memory, address, len));
+    let mut out = String::new();
+
+    for (i, byte) in bytes.iter().enumerate() {
+        out.push_str(&format!("{:03X}: {:02X} ", address as usize + i, byte));
+        for bit_pos in 0..8 {
+            out.push(if (byte >> (7 - bit_pos)) & 1 == 1 { '#' } else { '.' });
+        }
+        out.push('\n');
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    #[test]
+    fn breakpoints_by_label_or_address() {
+        let assembly = asm::assemble("start: CLS\nloop: JP loop", 0x200).unwrap();
+        let mut debugger = Debugger::new();
+        assert!(debugger.add_breakpoint("loop").is_err());
+
+        debugger.set_symbols(SymbolMap::from_assembly(&assembly, None));
+        assert_eq!(debugger.add_breakpoint("loop"), Ok(0x202));
+        assert_eq!(debugger.add_breakpoint("0x300"), Ok(0x300));
+        assert!(debugger.should_break(0x202));
+        assert!(!debugger.should_break(0x200));
+        assert_eq!(debugger.describe(0x202), "0x202 <loop>, line 2");
+    }
+
+    #[test]
+    fn pokes_bytes_within_memory() {
+        let mut memory = Memory::new();
+        assert_eq!(poke(&mut memory, "0x300", &["12", "0xAB"]), Ok(()));
+        assert_eq!(memory.read_chunk(0x300, 2), [0x12, 0xAB]);
+        assert!(memory.was_recently_written(0x301));
+
+        assert_eq!(
+            poke(&mut memory, "zz", &["1"]),
+            Err("Invalid address: zz".to_string())
+        );
+        assert_eq!(
+            poke(&mut memory, "300", &["100"]),
+            Err("Invalid byte: 100".to_string())
+        );
+        assert_eq!(
+            poke(&mut memory, "310", &["12", "zz"]),
+            Err("Invalid byte: zz".to_string())
+        );
+        assert_eq!(memory.read_byte(0x310), 0);
+        assert_eq!(
+            poke(&mut memory, "FFF", &["1", "2"]),
+            Err("Address out of range: 0xFFF".to_string())
+        );
+        assert_eq!(memory.read_byte(0xFFF), 0);
+    }
+
+    #[test]
+    fn dumps_memory_as_hex_and_bits() {
+        let cpu = Cpu::with_seed(0);
+        let mut memory = Memory::new();
+        poke(&mut memory, "200", &["48", "69"]).unwrap();
+        poke(&mut memory, "203", &["7F"]).unwrap();
+
+        let dump = hex_dump(&cpu, &memory, 0x200, 4);
+        let bytes = format!(
+            "{pc}48{reset} {pc}69{reset} 00 {write}7F{reset} ",
+            pc = HIGHLIGHT_PC,
+            write = HIGHLIGHT_WRITE,
+            reset = HIGHLIGHT_RESET
+        );
+        assert_eq!(dump, format!("200: {}{} Hi..\n", bytes, " ".repeat(12 * 3)));
+
+        // The I register points at the font, which ends on the second row
+        let dump = hex_dump(&cpu, &memory, 0, 0x20);
+        assert!(dump.starts_with(&format!("000: {}F0{} 90 ", HIGHLIGHT_I, HIGHLIGHT_RESET)));
+        assert!(dump.contains("\n010: 10 F0 10 F0 90 "));
+
+        // Views stop at the end of memory
+        let dump = hex_dump(&cpu, &memory, 0xFF8, 0x80);
+        assert_eq!(dump.lines().count(), 1);
+        assert!(dump.starts_with("FF8: 00 00 00 00 00 00 00 00 "));
+        assert_eq!(
+            bit_dump(&memory, 0x200, 2),
+            "200: 48 .#..#...\n201: 69 .##.#..#\n"
+        );
+        assert_eq!(bit_dump(&memory, 0xFFF, 8).lines().count(), 1);
+
+        assert_eq!(parse_view_args(&[], 0x234), Some((0x234, 0x80)));
+        assert_eq!(parse_view_args(&["0x300", "10"], 0), Some((0x300, 0x10)));
+        assert_eq!(parse_view_args(&["x"], 0), None);
+    }
+}
@@ -0,0 +1,180 @@
+use super::asm;
+use super::flow::Flow;
+use super::opcode::Opcode;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+// Lists `program` one instruction per line in a form `asm::assemble` accepts,
+// with the address and raw word in a trailing comment. Words that don't decode
+// to an instruction, and a trailing odd byte, are written out as data.
+pub fn disassemble(program: &[u8], origin: u16) -> String {
+    disassemble_with_labels(program, origin, &BTreeMap::new())
+}
+
+// Also names addresses that have a label, as definitions and in jumps, calls
+// and loads of I. Labels the assembler couldn't read back are left out.
+pub fn disassemble_with_labels(
+    program: &[u8],
+    origin: u16,
+    labels: &BTreeMap<String, u16>,
+) -> String {
+    listing(program, origin, labels, None, &|_| None)
+}
+
+// Only decodes what the analysis found to be code, listing everything else as
+// data, so instructions stay aligned after odd-length data
+pub fn disassemble_with_flow(
+    program: &[u8],
+    origin: u16,
+    labels: &BTreeMap<String, u16>,
+    flow: &Flow,
+) -> String {
+    listing(program, origin, labels, Some(flow), &|_| None)
+}
+
+// Like `disassemble_with_flow`, adding what `note` says about an address to the
+// end of its line
+pub fn disassemble_annotated<F: Fn(u16) -> Option<String>>(
+    program: &[u8],
+    origin: u16,
+    labels: &BTreeMap<String, u16>,
+    flow: &Flow,
+    note: F,
+) -> String {
+    listing(program, origin, labels, Some(flow), &note)
+}
+
+fn listing(
+    program: &[u8],
+    origin: u16,
+    labels: &BTreeMap<String, u16>,
+    flow: Option<&Flow>,
+    note: &dyn Fn(u16) -> Option<String>,
+) -> String {
+    let mut names: BTreeMap<u16, &str> = BTreeMap::new();
+    for (name, address) in labels.iter().rev() {
+        if asm::is_label(name) {
+            names.insert(*address, name);
+        }
+    }
+    let is_code = |address: usize| match flow {
+        Some(flow) => flow.instructions.contains_key(&(address as u16)),
+        None => true,
+    };
+    let mut out = String::new();
+
+    let mut offset = 0;
+    while offset < program.len() {
+        let address = origin as usize + offset;
+        if let Some(name) = names.get(&(address as u16)) {
+            writeln!(out, "{}:", name).unwrap();
+        }
+        // Data stops short of the next instruction or label
+        let splits = is_code(address + 1) || names.contains_key(&(address as u16 + 1));
+        let length = if offset + 1 == program.len() || (!is_code(address) && splits) {
+            1
+        } else {
+            2
+        };
+        // A label on the second byte is inside the instruction, so it is only noted
+        if let (2, Some(name)) = (length, names.get(&(address as u16 + 1))) {
+            writeln!(out, "; {} = {:03X}", name, address + 1).unwrap();
+        }
+
+        let text = match &program[offset..offset + length] {
+            [high, low] => {
+                let instruction = u16::from_be_bytes([*high, *low]);
+                let line = match Opcode::decode(instruction) {
+                    Opcode::NOP => format!("db 0x{:02X}, 0x{:02X}", high, low),
+                    _ if !is_code(address) => format!("db 0x{:02X}, 0x{:02X}", high, low),
+                    opcode => match target(opcode).and_then(|addr| names.get(&addr)) {
+                        Some(name) => {
+                            let text = opcode.to_string();
+                            format!("{}{}", &text[..text.len() - 5], name)
+                        }
+                        None => opcode.to_string(),
+                    },
+                };
+                format!("    {:<20} ; {:03X}: {:04X}", line, address, instruction)
+            }
+            [byte] => {
+                let line = format!("db 0x{:02X}", byte);
+                format!("    {:<20} ; {:03X}: {:02X}", line, address, byte)
+            }
+            _ => unreachable!(),
+        };
+        match note(address as u16) {
+            Some(note) => writeln!(out, "{}  {}", text, note).unwrap(),
+            None => writeln!(out, "{}", text).unwrap(),
+        }
+        offset += length;
+    }
+
+    out
+}
+
+fn target(opcode: Opcode) -> Option<u16> {
+    match opcode {
+        Opcode::JP { addr }
+        | Opcode::CALL { addr }
+        | Opcode::JP_V0 { addr }
+        | Opcode::LDI_IMM { addr } => Some(addr),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    #[test]
+    fn lists_instructions_with_addresses() {
+        let listing = disassemble(&[0x6A, 0x02, 0xFF, 0xFF, 0x12], 0x200);
+        let lines: Vec<&str> = listing.lines().collect();
+
+        assert_eq!(lines[0], "    LD VA, 0x02          ; 200: 6A02");
+        assert_eq!(lines[1], "    db 0xFF, 0xFF        ; 202: FFFF");
+        assert_eq!(lines[2], "    db 0x12              ; 204: 12");
+    }
+
+    #[test]
+    fn names_labelled_addresses() {
+        let source = "start: CALL sub\nloop: JP loop\nsub: LD I, data\nRET\ndata: db 1\n";
+        let assembly = assemble(source, 0x200).unwrap();
+        let mut labels = assembly.labels.clone();
+        labels.insert("draw-player".to_string(), 0x202);
+        labels.insert("middle".to_string(), 0x203);
+        let listing = disassemble_with_labels(&assembly.bytes, 0x200, &labels);
+        let lines: Vec<&str> = listing.lines().collect();
+
+        assert_eq!(lines[0], "start:");
+        assert_eq!(lines[1], "    CALL sub             ; 200: 2204");
+        assert_eq!(lines[2], "loop:");
+        assert_eq!(lines[3], "; middle = 203");
+        assert_eq!(lines[4], "    JP loop              ; 202: 1202");
+        assert_eq!(lines[6], "    LD I, data           ; 204: A208");
+        assert_eq!(assemble(&listing, 0x200).unwrap().bytes, assembly.bytes);
+    }
+
+    #[test]
+    fn bundled_roms_reassemble() {
+        for name in &["breakout", "space_invaders", "chip8_emu_logo"] {
+            let rom = std::fs::read(format!("roms/{}.ch8", name)).unwrap();
+            let listing = disassemble(&rom, 0x200);
+            assert_eq!(assemble(&listing, 0x200).unwrap().bytes, rom, "{}", name);
+        }
+    }
+
+    #[test]
+    fn analyzed_listings_keep_code_aligned() {
+        // Space Invaders jumps over its title to an odd address
+        let rom = std::fs::read("roms/space_invaders.ch8").unwrap();
+        let flow = Flow::analyze(&rom, 0x200);
+        let listing = disassemble_with_flow(&rom, 0x200, &flow.labels(), &flow);
+
+        assert!(listing.contains("label_225:\n    LD V0, 0x00          ; 225: 6000"));
+        assert_eq!(assemble(&listing, 0x200).unwrap().bytes, rom);
+    }
+}
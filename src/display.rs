@@ -1,3 +1,7 @@
+pub fn sprite_bit(byte: u8, bit_pos: usize) -> u8 {
+    (byte >> (7 - bit_pos)) & 1
+}
+
 #[derive(Debug, Default)]
 pub struct Display {
     pub framebuffer: Box<Vec<u32>>,
@@ -15,12 +19,33 @@ impl Display {
     }
 
     pub fn draw(&mut self, x: u8, y: u8, bytes: Vec<u8>, v_flag: &mut bool) {
+        self.draw_sprite(x, y, bytes, v_flag, true);
+    }
+
+    // Like `draw`, but only the starting position wraps and the rest of the
+    // sprite is cut off at the edges
+    pub fn draw_clipped(&mut self, x: u8, y: u8, bytes: Vec<u8>, v_flag: &mut bool) {
+        self.draw_sprite(x, y, bytes, v_flag, false);
+    }
+
+    fn draw_sprite(&mut self, x: u8, y: u8, bytes: Vec<u8>, v_flag: &mut bool, wrap: bool) {
+        let (x, y) = if wrap {
+            (x as usize, y as usize)
+        } else {
+            (x as usize % 64, y as usize % 32)
+        };
         for byte_pos in 0..bytes.len() {
-            let y = (y as usize + byte_pos) % 32;
+            if !wrap && y + byte_pos >= 32 {
+                break;
+            }
+            let y = (y + byte_pos) % 32;
             for bit_pos in 0..8 {
-                let x = (x as usize + bit_pos) % 64;
+                if !wrap && x + bit_pos >= 64 {
+                    break;
+                }
+                let x = (x + bit_pos) % 64;
                 let buffer_pos = (y * 64) + x as usize;
-                let draw = (bytes[byte_pos] >> (7 - bit_pos)) & 1;
+                let draw = sprite_bit(bytes[byte_pos], bit_pos);
 
                 if draw == 1 && self.framebuffer[buffer_pos] != 0 {
                     *v_flag = true;
@@ -37,6 +62,30 @@ impl Display {
         }
     }
 
+    // One bit per pixel, MSB first, as in the VIP's display page
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.framebuffer
+            .chunks(8)
+            .map(|pixels| {
+                pixels
+                    .iter()
+                    .fold(0, |byte, pixel| (byte << 1) | (*pixel != 0) as u8)
+            })
+            .collect()
+    }
+
+    pub fn load_bytes(&mut self, bytes: &[u8]) {
+        for (i, byte) in bytes.iter().enumerate().take(64 * 32 / 8) {
+            for bit_pos in 0..8 {
+                self.framebuffer[i * 8 + bit_pos] = if sprite_bit(*byte, bit_pos) == 1 {
+                    0x44FFFF00
+                } else {
+                    0
+                };
+            }
+        }
+    }
+
     pub fn draw_to_frame(&self, frame: &mut [u8], scale_factor: usize) {
         for (i, pixel) in frame.chunks_exact_mut(4).enumerate() {
             let x = i % 64;
@@ -57,3 +106,97 @@ impl Display {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lit(display: &Display) -> usize {
+        display.framebuffer.iter().filter(|pixel| **pixel != 0).count()
+    }
+
+    #[test]
+    fn draw_sets_pixels_without_collision() {
+        let mut display = Display::new();
+        let mut v_flag = false;
+        display.draw(0, 0, vec![0b1010_0000], &mut v_flag);
+
+        assert!(!v_flag);
+        assert_ne!(display.framebuffer[0], 0);
+        assert_eq!(display.framebuffer[1], 0);
+        assert_ne!(display.framebuffer[2], 0);
+        assert_eq!(lit(&display), 2);
+    }
+
+    #[test]
+    fn draw_xors_and_reports_collision() {
+        let mut display = Display::new();
+        let mut v_flag = false;
+        display.draw(0, 0, vec![0b1100_0000], &mut v_flag);
+        display.draw(1, 0, vec![0b1000_0000], &mut v_flag);
+
+        assert!(v_flag);
+        assert_ne!(display.framebuffer[0], 0);
+        assert_eq!(display.framebuffer[1], 0);
+    }
+
+    #[test]
+    fn draw_without_overlap_keeps_flag_clear() {
+        let mut display = Display::new();
+        let mut v_flag = false;
+        display.draw(0, 0, vec![0b1000_0000], &mut v_flag);
+        display.draw(1, 0, vec![0b1000_0000], &mut v_flag);
+
+        assert!(!v_flag);
+        assert_eq!(lit(&display), 2);
+    }
+
+    #[test]
+    fn draw_wraps_around_edges() {
+        let mut display = Display::new();
+        let mut v_flag = false;
+        display.draw(62, 31, vec![0b1110_0000, 0b1000_0000], &mut v_flag);
+
+        assert_ne!(display.framebuffer[31 * 64 + 62], 0);
+        assert_ne!(display.framebuffer[31 * 64 + 63], 0);
+        assert_ne!(display.framebuffer[31 * 64], 0);
+        assert_ne!(display.framebuffer[62], 0);
+        assert_eq!(lit(&display), 4);
+    }
+
+    #[test]
+    fn clear_resets_framebuffer() {
+        let mut display = Display::new();
+        let mut v_flag = false;
+        display.draw(10, 10, vec![0xFF; 4], &mut v_flag);
+        display.clear();
+
+        assert_eq!(lit(&display), 0);
+    }
+
+    #[test]
+    fn packs_pixels_into_bytes() {
+        let mut display = Display::new();
+        let mut v_flag = false;
+        display.draw(8, 1, vec![0b1000_0001], &mut v_flag);
+
+        let bytes = display.to_bytes();
+        assert_eq!(bytes.len(), 256);
+        assert_eq!(bytes[8 + 1], 0b1000_0001);
+
+        let mut copy = Display::new();
+        copy.load_bytes(&bytes);
+        assert_eq!(copy.framebuffer, display.framebuffer);
+    }
+
+    #[test]
+    fn clipped_sprites_stop_at_edges() {
+        let mut display = Display::new();
+        let mut v_flag = false;
+        display.draw_clipped(62 + 64, 31, vec![0b1110_0000, 0b1000_0000], &mut v_flag);
+
+        assert_ne!(display.framebuffer[31 * 64 + 62], 0);
+        assert_ne!(display.framebuffer[31 * 64 + 63], 0);
+        assert_eq!(lit(&display), 2);
+    }
+}
@@ -0,0 +1,461 @@
+use super::opcode::Opcode;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::ops::Range;
+
+// The most entries a `JP V0` table can have, with V0 at 0xFF
+const MAX_TABLE_ENTRIES: u16 = 128;
+
+// A static walk of a ROM from its first instruction, following jumps, calls,
+// returns, skips and `JP V0` tables, into basic blocks joined by edges. Calls
+// are assumed to return. What I points at is only followed within a block,
+// which is enough for the usual `LD I, addr` before a DRW or store.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EdgeKind {
+    Next,
+    Jump,
+    Call,
+    Skip,
+    // To an entry of a `JP V0` jump table
+    Table,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Edge {
+    pub from: u16,
+    pub to: u16,
+    pub kind: EdgeKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Block {
+    pub start: u16,
+    // The address after the last instruction
+    pub end: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ByteKind {
+    Code,
+    // Drawn, loaded or stored through I, or loaded into I
+    Data,
+    Unreachable,
+}
+
+// An Fx55 or Fx33 that writes over instructions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Store {
+    pub pc: u16,
+    pub target: u16,
+    pub length: u16,
+}
+
+#[derive(Debug, Clone)]
+pub struct Flow {
+    pub origin: u16,
+    pub instructions: BTreeMap<u16, Opcode>,
+    // Indexed by start address
+    pub blocks: BTreeMap<u16, Block>,
+    pub edges: BTreeSet<Edge>,
+    pub subroutines: BTreeSet<u16>,
+    // Words that execution reaches but that aren't instructions
+    pub invalid: BTreeSet<u16>,
+    // Jumps and calls that leave the program, such as into the interpreter
+    pub external: BTreeSet<u16>,
+    pub self_modifying: Vec<Store>,
+    data: BTreeSet<u16>,
+    kinds: Vec<ByteKind>,
+}
+
+impl Flow {
+    pub fn analyze(program: &[u8], origin: u16) -> Self {
+        let mut flow = Flow {
+            origin: origin,
+            instructions: BTreeMap::new(),
+            blocks: BTreeMap::new(),
+            edges: BTreeSet::new(),
+            subroutines: BTreeSet::new(),
+            invalid: BTreeSet::new(),
+            external: BTreeSet::new(),
+            self_modifying: Vec::new(),
+            data: BTreeSet::new(),
+            kinds: vec![ByteKind::Unreachable; program.len()],
+        };
+        let leaders = flow.find_instructions(program);
+        flow.build_blocks(program, &leaders);
+        flow.follow_i();
+        flow.classify();
+        flow
+    }
+
+    fn fetch(program: &[u8], origin: u16, addr: u16) -> Option<Opcode> {
+        let offset = addr.checked_sub(origin)? as usize;
+        program
+            .get(offset..offset + 2)
+            .map(|bytes| Opcode::decode(u16::from_be_bytes([bytes[0], bytes[1]])))
+    }
+
+    // Where execution can go after the instruction at `addr`
+    fn successors(&self, program: &[u8], addr: u16, opcode: Opcode) -> Vec<(u16, EdgeKind)> {
+        let next = addr.wrapping_add(2);
+        match opcode {
+            Opcode::JP { addr } => vec![(addr, EdgeKind::Jump)],
+            Opcode::CALL { addr } => vec![(addr, EdgeKind::Call), (next, EdgeKind::Next)],
+            Opcode::RET => vec![],
+            Opcode::JP_V0 { addr } => {
+                // A table of jumps is followed entry by entry, otherwise only V0 = 0 is known
+                let mut targets = vec![(addr, EdgeKind::Table)];
+                for entry in 1..MAX_TABLE_ENTRIES {
+                    let entry_addr = addr + entry * 2;
+                    match Flow::fetch(program, self.origin, entry_addr) {
+                        Some(Opcode::JP { .. }) => targets.push((entry_addr, EdgeKind::Table)),
+                        _ => break,
+                    }
+                }
+                targets
+            }
+            Opcode::SE { .. }
+            | Opcode::SNE { .. }
+            | Opcode::SE_R { .. }
+            | Opcode::SNE_R { .. }
+            | Opcode::SKP { .. }
+            | Opcode::SKNP { .. } => {
+                vec![
+                    (next, EdgeKind::Next),
+                    (next.wrapping_add(2), EdgeKind::Skip),
+                ]
+            }
+            _ => vec![(next, EdgeKind::Next)],
+        }
+    }
+
+    // Returns the addresses that start a block
+    fn find_instructions(&mut self, program: &[u8]) -> BTreeSet<u16> {
+        let mut leaders = BTreeSet::new();
+        leaders.insert(self.origin);
+        let mut pending = vec![self.origin];
+
+        while let Some(addr) = pending.pop() {
+            if self.instructions.contains_key(&addr) || self.invalid.contains(&addr) {
+                continue;
+            }
+            let opcode = match Flow::fetch(program, self.origin, addr) {
+                Some(Opcode::NOP) => {
+                    self.invalid.insert(addr);
+                    continue;
+                }
+                Some(opcode) => opcode,
+                None => {
+                    self.external.insert(addr);
+                    continue;
+                }
+            };
+            self.instructions.insert(addr, opcode);
+
+            if let Opcode::CALL { addr } = opcode {
+                self.subroutines.insert(addr);
+            }
+            let successors = self.successors(program, addr, opcode);
+            let ends_block = !matches!(successors.as_slice(), [(_, EdgeKind::Next)]);
+            for (target, kind) in successors {
+                if ends_block || kind != EdgeKind::Next {
+                    leaders.insert(target);
+                }
+                pending.push(target);
+            }
+        }
+
+        leaders
+    }
+
+    fn build_blocks(&mut self, program: &[u8], leaders: &BTreeSet<u16>) {
+        for leader in leaders.iter() {
+            if !self.instructions.contains_key(leader) {
+                continue;
+            }
+            let mut addr = *leader;
+            loop {
+                let opcode = self.instructions[&addr];
+                let successors = self.successors(program, addr, opcode);
+                let next = addr.wrapping_add(2);
+                let falls_through = matches!(successors.as_slice(), [(_, EdgeKind::Next)]);
+
+                if falls_through
+                    && !leaders.contains(&next)
+                    && self.instructions.contains_key(&next)
+                {
+                    addr = next;
+                    continue;
+                }
+                for (target, kind) in successors {
+                    if self.instructions.contains_key(&target) {
+                        self.edges.insert(Edge {
+                            from: *leader,
+                            to: target,
+                            kind: kind,
+                        });
+                    }
+                }
+                self.blocks.insert(
+                    *leader,
+                    Block {
+                        start: *leader,
+                        end: next,
+                    },
+                );
+                break;
+            }
+        }
+    }
+
+    fn follow_i(&mut self) {
+        let mut data = BTreeSet::new();
+        let mut stores = Vec::new();
+        for block in self.blocks.values() {
+            let mut i = None;
+            let mut addr = block.start;
+            while addr != block.end {
+                match self.instructions[&addr] {
+                    Opcode::LDI_IMM { addr } => {
+                        data.insert(addr);
+                        i = Some(addr);
+                    }
+                    Opcode::DRW { size, .. } => {
+                        if let Some(i) = i {
+                            data.extend(i..i.saturating_add(size as u16));
+                        }
+                    }
+                    Opcode::LD_M { x } => {
+                        if let Some(i) = i {
+                            data.extend(i..i.saturating_add(x as u16 + 1));
+                        }
+                        // Whether I moves depends on the quirks
+                        i = None;
+                    }
+                    Opcode::ST_M { x } => {
+                        if let Some(i) = i {
+                            stores.push((addr, i, x as u16 + 1));
+                        }
+                        i = None;
+                    }
+                    Opcode::LD_B { .. } => {
+                        if let Some(i) = i {
+                            stores.push((addr, i, 3));
+                        }
+                    }
+                    Opcode::ADDI_R { .. } | Opcode::LD_F { .. } => i = None,
+                    _ => {}
+                }
+                addr = addr.wrapping_add(2);
+            }
+        }
+
+        self.data = data;
+        for (pc, target, length) in stores {
+            let end = target.saturating_add(length);
+            self.data.extend(target..end);
+            if (target..end).any(|byte| self.is_instruction_byte(byte)) {
+                self.self_modifying.push(Store {
+                    pc: pc,
+                    target: target,
+                    length: length,
+                });
+            }
+        }
+    }
+
+    fn is_instruction_byte(&self, addr: u16) -> bool {
+        self.instructions.contains_key(&addr)
+            || (addr > 0 && self.instructions.contains_key(&(addr - 1)))
+    }
+
+    fn classify(&mut self) {
+        for addr in self.data.iter() {
+            if let Some(offset) = addr.checked_sub(self.origin) {
+                if let Some(kind) = self.kinds.get_mut(offset as usize) {
+                    *kind = ByteKind::Data;
+                }
+            }
+        }
+        for addr in self.instructions.keys() {
+            let offset = (addr - self.origin) as usize;
+            self.kinds[offset] = ByteKind::Code;
+            self.kinds[offset + 1] = ByteKind::Code;
+        }
+    }
+
+    pub fn kind(&self, addr: u16) -> Option<ByteKind> {
+        let offset = addr.checked_sub(self.origin)?;
+        self.kinds.get(offset as usize).copied()
+    }
+
+    // Runs of bytes that are neither reached as code nor used as data
+    pub fn unreachable(&self) -> Vec<Range<u16>> {
+        let mut ranges: Vec<Range<u16>> = Vec::new();
+        for (offset, kind) in self.kinds.iter().enumerate() {
+            if *kind != ByteKind::Unreachable {
+                continue;
+            }
+            let addr = self.origin + offset as u16;
+            match ranges.last_mut() {
+                Some(range) if range.end == addr => range.end += 1,
+                _ => ranges.push(addr..addr + 1),
+            }
+        }
+        ranges
+    }
+
+    pub fn count(&self, kind: ByteKind) -> usize {
+        self.kinds.iter().filter(|k| **k == kind).count()
+    }
+
+    // Names for the addresses code refers to, for disassembly. Addresses
+    // inside an instruction or outside the program are left unnamed.
+    pub fn labels(&self) -> BTreeMap<String, u16> {
+        let mut names = BTreeMap::new();
+        for opcode in self.instructions.values() {
+            if let Opcode::LDI_IMM { addr } = opcode {
+                names.insert(*addr, format!("data_{:03X}", addr));
+            }
+        }
+        for edge in self.edges.iter() {
+            if edge.kind == EdgeKind::Jump || edge.kind == EdgeKind::Table {
+                names.insert(edge.to, format!("label_{:03X}", edge.to));
+            }
+        }
+        for addr in self.subroutines.iter() {
+            names.insert(*addr, format!("sub_{:03X}", addr));
+        }
+
+        names
+            .into_iter()
+            .filter(|(addr, _)| {
+                self.kind(*addr).is_some()
+                    && (self.instructions.contains_key(addr) || !self.is_instruction_byte(*addr))
+            })
+            .map(|(addr, name)| (name, addr))
+            .collect()
+    }
+
+    // Graphviz source with a node for each block. Subroutine entries have a
+    // double border and blocks written over by stores are red.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph flow {{").unwrap();
+        writeln!(out, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+
+        for block in self.blocks.values() {
+            let mut label = String::new();
+            let mut addr = block.start;
+            while addr != block.end {
+                write!(label, "{:03X}: {}\\l", addr, self.instructions[&addr]).unwrap();
+                addr = addr.wrapping_add(2);
+            }
+            let mut attributes = format!("label=\"{}\"", label);
+            if self.subroutines.contains(&block.start) {
+                attributes += ", peripheries=2";
+            }
+            let modified = self.self_modifying.iter().any(|store| {
+                store.target < block.end && block.start < store.target.saturating_add(store.length)
+            });
+            if modified {
+                attributes += ", color=red";
+            }
+            writeln!(out, "    b{:03X} [{}];", block.start, attributes).unwrap();
+        }
+
+        for edge in self.edges.iter() {
+            let style = match edge.kind {
+                EdgeKind::Next => "",
+                EdgeKind::Jump => "",
+                EdgeKind::Call => " [style=dashed]",
+                EdgeKind::Skip => " [label=\"skip\"]",
+                EdgeKind::Table => " [style=dotted]",
+            };
+            writeln!(out, "    b{:03X} -> b{:03X}{};", edge.from, edge.to, style).unwrap();
+        }
+        writeln!(out, "}}").unwrap();
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    const SOURCE: &str = "
+        start:  CALL draw
+                SE V0, 0x01
+                JP table
+                LD I, patch
+                LD [I], V1
+        loop:   JP loop
+        table:  JP V0, jumps
+        jumps:  JP start
+                JP loop
+        draw:   LD I, sprite
+                DRW V0, V1, 2
+        patch:  RET
+        sprite: db 0xF0, 0x90
+        unused: db 0x12, 0x34, 0x56
+    ";
+
+    #[test]
+    fn follows_calls_skips_and_tables() {
+        let program = assemble(SOURCE, 0x200).unwrap().bytes;
+        let flow = Flow::analyze(&program, 0x200);
+
+        assert_eq!(
+            flow.subroutines.iter().copied().collect::<Vec<_>>(),
+            [0x212]
+        );
+        assert!(flow.edges.contains(&Edge {
+            from: 0x202,
+            to: 0x206,
+            kind: EdgeKind::Skip
+        }));
+        assert!(flow.edges.contains(&Edge {
+            from: 0x20C,
+            to: 0x210,
+            kind: EdgeKind::Table
+        }));
+        assert_eq!(flow.blocks[&0x206].end, 0x20A);
+        assert_eq!(flow.kind(0x20E), Some(ByteKind::Code));
+        assert_eq!(flow.kind(0x219), Some(ByteKind::Data));
+        assert_eq!(
+            flow.unreachable(),
+            [Range {
+                start: 0x21A,
+                end: 0x21D
+            }]
+        );
+        assert!(flow.invalid.is_empty());
+    }
+
+    #[test]
+    fn finds_stores_into_code() {
+        let program = assemble(SOURCE, 0x200).unwrap().bytes;
+        let flow = Flow::analyze(&program, 0x200);
+
+        assert_eq!(
+            flow.self_modifying,
+            [Store {
+                pc: 0x208,
+                target: 0x216,
+                length: 2
+            }]
+        );
+        let labels = flow.labels();
+        assert_eq!(labels["sub_212"], 0x212);
+        assert_eq!(labels["data_218"], 0x218);
+        assert_eq!(labels["label_20C"], 0x20C);
+
+        let dot = flow.to_dot();
+        assert!(dot.starts_with("digraph flow {"));
+        assert!(dot.contains("b212 [label=\"212: LD I, 0x218\\l214: DRW V0, V1, 2\\l216: RET\\l\", peripheries=2, color=red];"));
+        assert!(dot.contains("b200 -> b212 [style=dashed];"));
+    }
+}
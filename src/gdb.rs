@@ -0,0 +1,522 @@
+use super::cpu::{Cpu, CpuError};
+use super::memory::Memory;
+
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+// Register numbering exposed to GDB: V0-VF, then I, PC, SP, DT and ST
+const REG_I: usize = 16;
+const REG_PC: usize = 17;
+const REG_SP: usize = 18;
+const REG_DT: usize = 19;
+const REG_ST: usize = 20;
+const REG_COUNT: usize = 21;
+
+const INTERRUPT: u8 = 0x03;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chip8.core">
+    <reg name="v0" bitsize="8"/>
+    <reg name="v1" bitsize="8"/>
+    <reg name="v2" bitsize="8"/>
+    <reg name="v3" bitsize="8"/>
+    <reg name="v4" bitsize="8"/>
+    <reg name="v5" bitsize="8"/>
+    <reg name="v6" bitsize="8"/>
+    <reg name="v7" bitsize="8"/>
+    <reg name="v8" bitsize="8"/>
+    <reg name="v9" bitsize="8"/>
+    <reg name="va" bitsize="8"/>
+    <reg name="vb" bitsize="8"/>
+    <reg name="vc" bitsize="8"/>
+    <reg name="vd" bitsize="8"/>
+    <reg name="ve" bitsize="8"/>
+    <reg name="vf" bitsize="8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8"/>
+    <reg name="dt" bitsize="8"/>
+    <reg name="st" bitsize="8"/>
+  </feature>
+</target>
+"#;
+
+#[derive(Debug, PartialEq)]
+pub enum GdbRequest {
+    Wait,
+    Step,
+    Continue,
+    Detach,
+    Kill,
+}
+
+#[derive(Debug)]
+pub struct GdbStub {
+    stream: TcpStream,
+    breakpoints: HashSet<u16>,
+}
+
+impl GdbStub {
+    pub fn listen(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        println!("Waiting for GDB connection on 127.0.0.1:{}", port);
+        let (stream, addr) = listener.accept()?;
+        println!("GDB connected from {}", addr);
+        stream.set_nodelay(true)?;
+
+        Ok(GdbStub {
+            stream: stream,
+            breakpoints: HashSet::new(),
+        })
+    }
+
+    pub fn hit_breakpoint(&self, pc: u16) -> bool {
+        self.breakpoints.contains(&pc)
+    }
+
+    // Non-blocking check for a Ctrl-C sent by the debugger while the machine runs
+    pub fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0u8; 1];
+        let result = match self.stream.peek(&mut byte) {
+            Ok(1) if byte[0] == INTERRUPT => {
+                self.stream.read_exact(&mut byte)?;
+                Ok(true)
+            }
+            Ok(_) => Ok(false),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        };
+        self.stream.set_nonblocking(false)?;
+
+        result
+    }
+
+    pub fn report_stop(&mut self) -> io::Result<()> {
+        self.send_packet("S05")
+    }
+
+    // The machine stays stopped on the faulting instruction: SIGILL for bad
+    // opcodes, SIGSEGV for stack and memory faults
+    pub fn report_error(&mut self, error: &CpuError) -> io::Result<()> {
+        println!("{}", error);
+        match error {
+            CpuError::InvalidInstruction { .. } => self.send_packet("S04"),
+            _ => self.send_packet("S0b"),
+        }
+    }
+
+    pub fn handle_packet(&mut self, cpu: &mut Cpu, memory: &mut Memory) -> io::Result<GdbRequest> {
+        let packet = match self.read_packet()? {
+            Some(packet) => packet,
+            None => return Ok(GdbRequest::Detach),
+        };
+
+        // Empty packets and ones starting with a multi-byte character get the empty reply
+        let command = match packet.get(..1) {
+            Some(command) => command,
+            None => {
+                self.send_packet("")?;
+                return Ok(GdbRequest::Wait);
+            }
+        };
+        let args = &packet[1..];
+        let reply = match command {
+            "?" => String::from("S05"),
+            "g" => read_registers(cpu),
+            "G" => write_registers(cpu, args),
+            "p" => read_register(cpu, args),
+            "P" => write_register(cpu, args),
+            "m" => read_memory(memory, args),
+            "M" => write_memory(memory, args),
+            "Z" | "z" => self.set_breakpoint(memory, command == "Z", args),
+            "H" => String::from("OK"),
+            "q" => query(args),
+            "s" => return self.resume(cpu, memory, args, GdbRequest::Step),
+            "c" => return self.resume(cpu, memory, args, GdbRequest::Continue),
+            "D" => {
+                self.send_packet("OK")?;
+                return Ok(GdbRequest::Detach);
+            }
+            "k" => return Ok(GdbRequest::Kill),
+            _ => String::new(),
+        };
+
+        self.send_packet(&reply)?;
+        Ok(GdbRequest::Wait)
+    }
+
+    // Resuming from outside memory is refused and the target stays stopped
+    fn resume(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &Memory,
+        args: &str,
+        request: GdbRequest,
+    ) -> io::Result<GdbRequest> {
+        if let Some(addr) = parse_hex(args) {
+            if addr >= memory.size() {
+                self.send_packet("E01")?;
+                return Ok(GdbRequest::Wait);
+            }
+            let mut state = cpu.state();
+            state.reg_pc = addr as u16;
+            cpu.set_state(&state);
+        }
+
+        Ok(request)
+    }
+
+    fn set_breakpoint(&mut self, memory: &Memory, insert: bool, args: &str) -> String {
+        let fields: Vec<&str> = args.split(',').collect();
+        match fields.as_slice() {
+            [kind, addr, _] if *kind == "0" || *kind == "1" => match parse_address(memory, addr) {
+                Some(addr) => {
+                    if insert {
+                        self.breakpoints.insert(addr);
+                    } else {
+                        self.breakpoints.remove(&addr);
+                    }
+                    String::from("OK")
+                }
+                None => String::from("E01"),
+            },
+            _ => String::new(),
+        }
+    }
+
+    // Corrupt packets are nacked until the client sends one intact
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        let mut byte = [0u8; 1];
+
+        loop {
+            loop {
+                if self.stream.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+
+            let mut data = Vec::new();
+            loop {
+                if self.stream.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+
+            let mut checksum = [0u8; 2];
+            self.stream.read_exact(&mut checksum)?;
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+
+            if expected == Some(checksum_of(&data)) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+            self.stream.flush()?;
+
+            let mut ack = [0u8; 1];
+            if self.stream.read(&mut ack)? == 0 || ack[0] != b'-' {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn parse_hex(value: &str) -> Option<usize> {
+    usize::from_str_radix(value, 16).ok()
+}
+
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    if value.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn register_bytes(cpu: &Cpu, reg: usize) -> Vec<u8> {
+    let state = cpu.state();
+    match reg {
+        0..=15 => vec![state.reg_gp[reg]],
+        REG_I => state.reg_i.to_le_bytes().to_vec(),
+        REG_PC => state.reg_pc.to_le_bytes().to_vec(),
+        REG_SP => vec![state.reg_sp],
+        REG_DT => vec![state.reg_delay],
+        REG_ST => vec![state.reg_sound_timer],
+        _ => vec![],
+    }
+}
+
+fn set_register_bytes(cpu: &mut Cpu, reg: usize, bytes: &[u8]) -> bool {
+    let mut state = cpu.state();
+    match (reg, bytes) {
+        (0..=15, [value]) => state.reg_gp[reg] = *value,
+        (REG_I, [lo, hi]) => state.reg_i = u16::from_le_bytes([*lo, *hi]),
+        (REG_PC, [lo, hi]) => state.reg_pc = u16::from_le_bytes([*lo, *hi]),
+        (REG_SP, [value]) if (*value as usize) < 16 => state.reg_sp = *value,
+        (REG_DT, [value]) => state.reg_delay = *value,
+        (REG_ST, [value]) => state.reg_sound_timer = *value,
+        _ => return false,
+    }
+    cpu.set_state(&state);
+
+    true
+}
+
+fn read_registers(cpu: &Cpu) -> String {
+    let bytes: Vec<u8> = (0..REG_COUNT).flat_map(|reg| register_bytes(cpu, reg)).collect();
+    encode_hex(&bytes)
+}
+
+fn write_registers(cpu: &mut Cpu, args: &str) -> String {
+    let bytes = match decode_hex(args) {
+        Some(bytes) => bytes,
+        None => return String::from("E01"),
+    };
+
+    let mut offset = 0;
+    for reg in 0..REG_COUNT {
+        let len = register_bytes(cpu, reg).len();
+        match bytes.get(offset..offset + len) {
+            Some(value) if set_register_bytes(cpu, reg, value) => offset += len,
+            _ => return String::from("E01"),
+        }
+    }
+
+    String::from("OK")
+}
+
+fn read_register(cpu: &Cpu, args: &str) -> String {
+    match parse_hex(args) {
+        Some(reg) if reg < REG_COUNT => encode_hex(&register_bytes(cpu, reg)),
+        _ => String::from("E01"),
+    }
+}
+
+fn write_register(cpu: &mut Cpu, args: &str) -> String {
+    let mut fields = args.splitn(2, '=');
+    let reg = fields.next().and_then(parse_hex);
+    let value = fields.next().and_then(decode_hex);
+
+    match (reg, value) {
+        (Some(reg), Some(value)) if set_register_bytes(cpu, reg, &value) => String::from("OK"),
+        _ => String::from("E01"),
+    }
+}
+
+fn parse_address(memory: &Memory, value: &str) -> Option<u16> {
+    parse_hex(value)
+        .filter(|addr| *addr < memory.size())
+        .map(|addr| addr as u16)
+}
+
+fn parse_range(memory: &Memory, args: &str) -> Option<(u16, usize)> {
+    let mut fields = args.splitn(2, ',');
+    let addr = fields.next().and_then(parse_hex)?;
+    let len = fields.next().and_then(parse_hex)?;
+
+    if addr.checked_add(len)? > memory.size() {
+        return None;
+    }
+
+    Some((addr as u16, len))
+}
+
+fn read_memory(memory: &Memory, args: &str) -> String {
+    match parse_range(memory, args) {
+        Some((addr, len)) => encode_hex(&memory.read_chunk(addr, len)),
+        None => String::from("E01"),
+    }
+}
+
+fn write_memory(memory: &mut Memory, args: &str) -> String {
+    let mut fields = args.splitn(2, ':');
+    let range = fields.next().and_then(|range| parse_range(memory, range));
+    let data = fields.next().and_then(decode_hex);
+
+    match (range, data) {
+        (Some((addr, len)), Some(data)) if data.len() == len => {
+            for (offset, byte) in data.iter().enumerate() {
+                memory.write_byte(addr + offset as u16, *byte);
+            }
+            String::from("OK")
+        }
+        _ => String::from("E01"),
+    }
+}
+
+fn query(args: &str) -> String {
+    if args.starts_with("Supported") {
+        return String::from("PacketSize=1000;qXfer:features:read+");
+    }
+    if args == "Attached" {
+        return String::from("1");
+    }
+    if args == "fThreadInfo" {
+        return String::from("m1");
+    }
+    if args == "sThreadInfo" {
+        return String::from("l");
+    }
+    if args == "C" {
+        return String::from("QC1");
+    }
+    if args.starts_with("Xfer:features:read:target.xml:") {
+        let range = &args["Xfer:features:read:target.xml:".len()..];
+        let mut fields = range.splitn(2, ',');
+        let offset = fields.next().and_then(parse_hex).unwrap_or(0);
+        let len = fields.next().and_then(parse_hex).unwrap_or(0);
+
+        if offset >= TARGET_XML.len() {
+            return String::from("l");
+        }
+        let end = offset.saturating_add(len).min(TARGET_XML.len());
+        let prefix = if end == TARGET_XML.len() { "l" } else { "m" };
+        return format!("{}{}", prefix, &TARGET_XML[offset..end]);
+    }
+
+    String::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A stub talking to a client socket on the loopback interface
+    fn connect() -> (GdbStub, TcpStream) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let stub = GdbStub {
+            stream: stream,
+            breakpoints: HashSet::new(),
+        };
+        (stub, client)
+    }
+
+    fn packet(data: &str) -> String {
+        format!("${}#{:02x}", data, checksum_of(data.as_bytes()))
+    }
+
+    // Sends `data` and the ack for the reply, so the stub never waits on it
+    fn send(client: &mut TcpStream, data: &str) {
+        client.write_all(packet(data).as_bytes()).unwrap();
+        client.write_all(b"+").unwrap();
+    }
+
+    // The stub's ack followed by its reply
+    fn receive(client: &mut TcpStream) -> String {
+        let mut reply = Vec::new();
+        let mut byte = [0u8; 1];
+        while reply.len() < 3 || reply[reply.len() - 3] != b'#' {
+            client.read_exact(&mut byte).unwrap();
+            reply.push(byte[0]);
+        }
+        String::from_utf8(reply).unwrap()
+    }
+
+    #[test]
+    fn checksums_and_parses_fields() {
+        assert_eq!(checksum_of(b"?"), 0x3f);
+        assert_eq!(checksum_of(b"m200,2"), 0x5d);
+        assert_eq!(packet("OK"), "$OK#9a");
+        assert_eq!(decode_hex("12ab"), Some(vec![0x12, 0xab]));
+        assert_eq!(decode_hex("123"), None);
+        assert_eq!(encode_hex(&[0x00, 0xfe]), "00fe");
+
+        let memory = Memory::new();
+        assert_eq!(parse_range(&memory, "200,10"), Some((0x200, 0x10)));
+        assert_eq!(parse_range(&memory, "ff0,20"), None);
+        assert_eq!(parse_range(&memory, "0,ffffffffffffffff"), None);
+        assert_eq!(parse_range(&memory, "200"), None);
+        assert_eq!(
+            query("Xfer:features:read:target.xml:0,ffffffffffffffff").len(),
+            TARGET_XML.len() + 1
+        );
+    }
+
+    #[test]
+    fn answers_over_loopback() {
+        let (mut stub, mut client) = connect();
+        let mut cpu = Cpu::with_seed(0);
+        let mut memory = Memory::new();
+        memory.write_byte(0x200, 0x12);
+        memory.write_byte(0x201, 0x00);
+
+        let mut exchange = |data: &str, stub: &mut GdbStub| {
+            send(&mut client, data);
+            assert_eq!(
+                stub.handle_packet(&mut cpu, &mut memory).unwrap(),
+                GdbRequest::Wait
+            );
+            receive(&mut client)
+        };
+        assert_eq!(exchange("?", &mut stub), format!("+{}", packet("S05")));
+        let registers = format!("{}00000002000000", "00".repeat(16));
+        assert_eq!(exchange("g", &mut stub), format!("+{}", packet(&registers)));
+        assert_eq!(
+            exchange("m200,2", &mut stub),
+            format!("+{}", packet("1200"))
+        );
+        assert_eq!(
+            exchange("m0,ffffffffffffffff", &mut stub),
+            format!("+{}", packet("E01"))
+        );
+        assert_eq!(
+            exchange("Z0,202,2", &mut stub),
+            format!("+{}", packet("OK"))
+        );
+        assert!(stub.hit_breakpoint(0x202));
+        assert_eq!(
+            exchange("Z0,10300,2", &mut stub),
+            format!("+{}", packet("E01"))
+        );
+        assert!(!stub.hit_breakpoint(0x300));
+        assert_eq!(exchange("c1000", &mut stub), format!("+{}", packet("E01")));
+        assert_eq!(exchange("", &mut stub), format!("+{}", packet("")));
+        assert_eq!(cpu.state().reg_pc, 0x200);
+    }
+
+    #[test]
+    fn asks_again_for_corrupt_packets() {
+        let (mut stub, mut client) = connect();
+        client.write_all(b"$?#00$?#zz").unwrap();
+        send(&mut client, "?");
+        assert_eq!(stub.read_packet().unwrap(), Some(String::from("?")));
+
+        let mut acks = [0u8; 3];
+        client.read_exact(&mut acks).unwrap();
+        assert_eq!(&acks, b"--+");
+    }
+}
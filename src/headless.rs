@@ -0,0 +1,267 @@
+use super::cpu::{Cpu, CpuError};
+use super::display::Display;
+use super::keyboard::KeyboardState;
+use super::memory::Memory;
+use super::profile::Profiler;
+use super::rom::{self, Layout, RomError};
+use super::script::{Script, ScriptError};
+use super::strict::{Diagnostic, Strict};
+use super::trace::Tracer;
+
+use std::io;
+
+const WIDTH: usize = 64;
+
+// The machine without a window: frames run as fast as the host allows and the
+// keyboard only changes when the caller says so
+#[derive(Debug)]
+pub struct Headless {
+    pub cpu: Cpu,
+    pub memory: Memory,
+    pub display: Display,
+    pub keyboard_state: KeyboardState,
+    cycles_per_frame: u64,
+    tracer: Option<Tracer>,
+    // Why tracing stopped, when the trace could not be written
+    trace_error: Option<io::Error>,
+    profiler: Option<Profiler>,
+    strict: Option<Strict>,
+    script: Option<Script>,
+    // Why the script stopped the machine, when it failed
+    script_error: Option<ScriptError>,
+    cycles: u64,
+}
+
+impl Headless {
+    pub fn new(program: &[u8], layout: Layout, seed: u64) -> Result<Self, RomError> {
+        let mut cpu = Cpu::with_seed(seed);
+        let mut memory = Memory::new();
+        rom::load(&mut cpu, &mut memory, program, layout)?;
+
+        Ok(Headless {
+            cpu: cpu,
+            memory: memory,
+            display: Display::new(),
+            keyboard_state: KeyboardState::default(),
+            cycles_per_frame: 8,
+            tracer: None,
+            trace_error: None,
+            profiler: None,
+            strict: None,
+            script: None,
+            script_error: None,
+            cycles: 0,
+        })
+    }
+
+    pub fn set_cycles_per_frame(&mut self, cycles: u64) {
+        self.cycles_per_frame = cycles;
+    }
+
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    pub fn set_profiler(&mut self, profiler: Profiler) {
+        self.profiler = Some(profiler);
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    pub fn set_strict(&mut self, strict: Strict) {
+        self.strict = Some(strict);
+    }
+
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        match &self.strict {
+            Some(strict) => strict.diagnostics(),
+            None => &[],
+        }
+    }
+
+    // Runs the script's top level, then calls its hooks as the machine runs
+    pub fn set_script(&mut self, mut script: Script) -> Result<(), ScriptError> {
+        script.start(&mut self.cpu, &mut self.memory, &mut self.display)?;
+        self.script = Some(script);
+        Ok(())
+    }
+
+    pub fn script(&self) -> Option<&Script> {
+        self.script.as_ref()
+    }
+
+    // True once the script has called stop() or failed, or the trace could not be written
+    pub fn stopped(&self) -> bool {
+        self.script_error.is_some()
+            || self.trace_error.is_some()
+            || self.script.as_ref().is_some_and(|s| s.stopped())
+    }
+
+    pub fn take_trace_error(&mut self) -> Option<io::Error> {
+        self.trace_error.take()
+    }
+
+    pub fn take_script_error(&mut self) -> Option<ScriptError> {
+        self.script_error.take()
+    }
+
+    fn run_script<F>(&mut self, hook: F)
+    where
+        F: FnOnce(&mut Script, &mut Cpu, &mut Memory, &mut Display) -> Result<(), ScriptError>,
+    {
+        if self.script_error.is_some() {
+            return;
+        }
+        if let Some(script) = &mut self.script {
+            if let Err(e) = hook(script, &mut self.cpu, &mut self.memory, &mut self.display) {
+                self.script_error = Some(e);
+            }
+        }
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn step(&mut self) -> Result<(), CpuError> {
+        self.run_script(Script::before_step);
+        if self.stopped() {
+            return Ok(());
+        }
+        let before = self.cpu.state();
+        // Read before the step, which may overwrite the instruction it runs
+        let instruction = if self.memory.contains(before.reg_pc, 2) {
+            self.memory.read_doublebyte(before.reg_pc)
+        } else {
+            0
+        };
+        if let Some(strict) = &mut self.strict {
+            strict.check(&self.cpu, &self.memory);
+        }
+        let held;
+        let keyboard_state = match &self.script {
+            Some(script) => {
+                held = script.keys_over(&self.keyboard_state);
+                &held
+            }
+            None => &self.keyboard_state,
+        };
+        self.cpu
+            .step(&mut self.memory, &mut self.display, keyboard_state)?;
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(instruction, &before);
+        }
+        if let Some(tracer) = &mut self.tracer {
+            if let Err(e) = tracer.trace(self.cycles, instruction, &before, &self.cpu.state()) {
+                self.tracer = None;
+                self.trace_error = Some(e);
+            }
+        }
+        self.cycles += 1;
+
+        Ok(())
+    }
+
+    pub fn run_frame(&mut self) -> Result<(), CpuError> {
+        for _ in 0..self.cycles_per_frame {
+            // Stops at the instruction the script stopped at
+            if self.stopped() {
+                return Ok(());
+            }
+            self.step()?;
+        }
+        self.cpu.tick_timers();
+        if let Some(profiler) = &mut self.profiler {
+            profiler.end_frame();
+        }
+        self.run_script(Script::end_frame);
+
+        Ok(())
+    }
+
+    // Ends early if the script stops the machine
+    pub fn run_frames(&mut self, frames: u64) -> Result<(), CpuError> {
+        for _ in 0..frames {
+            if self.stopped() {
+                break;
+            }
+            self.run_frame()?;
+        }
+
+        Ok(())
+    }
+
+    // The screen as text, '#' for lit pixels and '.' for dark ones
+    pub fn screen_text(&self) -> String {
+        let mut text = String::new();
+        for row in self.display.framebuffer.chunks(WIDTH) {
+            text.extend(row.iter().map(|pixel| if *pixel != 0 { '#' } else { '.' }));
+            text.push('\n');
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::{TraceFilter, TraceFormat};
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn runs_frames_and_renders_text() {
+        // Draw the font's "0" at the top left, then loop forever
+        let program = [0x60, 0x00, 0xF0, 0x29, 0xD0, 0x05, 0x12, 0x06];
+        let mut machine = Headless::new(&program, Layout::default(), 0).unwrap();
+        machine.run_frames(2).unwrap();
+
+        assert_eq!(machine.cycles(), 16);
+        let text = machine.screen_text();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 32);
+        assert!(lines[0].starts_with("####."));
+        assert!(lines[1].starts_with("#..#."));
+        assert!(lines[5].starts_with("....."));
+    }
+
+    #[test]
+    fn records_instructions_that_overwrite_themselves() {
+        // I points at the LD [I], V0, which stores V0 over its own first byte
+        let program = [0xA2, 0x02, 0xF0, 0x55, 0x12, 0x04];
+        let mut machine = Headless::new(&program, Layout::default(), 0).unwrap();
+        let buffer = Buffer::default();
+        machine.set_tracer(Tracer::new(
+            Box::new(buffer.clone()),
+            TraceFormat::Text,
+            TraceFilter::default(),
+        ));
+        machine.set_profiler(Profiler::new());
+        machine.step().unwrap();
+        machine.step().unwrap();
+
+        assert_eq!(machine.memory.read_doublebyte(0x202), 0x0055);
+        let profiler = machine.profiler().unwrap();
+        assert_eq!(profiler.opcode("ST_M").executions, 1);
+        assert_eq!(profiler.opcode("SYS").executions, 0);
+        let trace = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        assert!(trace.lines().nth(1).unwrap().starts_with("00000001 202 F055"));
+    }
+}
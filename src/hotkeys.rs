@@ -0,0 +1,167 @@
+use minifb::{Key, KeyRepeat, Window};
+
+// Host keys that control the emulator rather than the program. They stay clear
+// of the default keypad grid, and F1 is the debugger.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hotkey {
+    Pause,
+    FrameAdvance,
+    SoftReset,
+    HardReset,
+    SpeedDown,
+    SpeedUp,
+    Overlay,
+    Browse,
+    Quit,
+}
+
+pub const HOTKEYS: [(Key, Hotkey); 9] = [
+    (Key::F2, Hotkey::Pause),
+    (Key::F3, Hotkey::FrameAdvance),
+    (Key::F4, Hotkey::Overlay),
+    (Key::F5, Hotkey::SoftReset),
+    (Key::F6, Hotkey::HardReset),
+    (Key::F7, Hotkey::Browse),
+    (Key::Minus, Hotkey::SpeedDown),
+    (Key::Equal, Hotkey::SpeedUp),
+    (Key::Escape, Hotkey::Quit),
+];
+
+// Percentages of the configured speed that SpeedDown and SpeedUp step through
+const SPEEDS: [u64; 9] = [25, 50, 75, 100, 150, 200, 300, 400, 800];
+const NORMAL_SPEED: usize = 3;
+
+pub fn pressed(window: &Window) -> Vec<Hotkey> {
+    HOTKEYS
+        .iter()
+        .filter(|(key, _)| window.is_key_pressed(*key, KeyRepeat::No))
+        .map(|(_, hotkey)| *hotkey)
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RunState {
+    Running,
+    Paused,
+    // Running until the end of the current frame, then paused again
+    Advancing,
+}
+
+#[derive(Debug)]
+pub struct Controls {
+    pub state: RunState,
+    speed: usize,
+}
+
+impl Default for Controls {
+    fn default() -> Self {
+        Controls {
+            state: RunState::Running,
+            speed: NORMAL_SPEED,
+        }
+    }
+}
+
+impl Controls {
+    pub fn new() -> Self {
+        Controls::default()
+    }
+
+    // Handles the hotkeys that only change how the machine runs. Anything else is
+    // handed back for the caller to carry out.
+    pub fn apply(&mut self, hotkey: Hotkey) -> Option<Hotkey> {
+        match hotkey {
+            Hotkey::Pause => {
+                self.state = match self.state {
+                    RunState::Running => RunState::Paused,
+                    _ => RunState::Running,
+                }
+            }
+            Hotkey::FrameAdvance => self.state = RunState::Advancing,
+            Hotkey::SpeedDown => self.speed = self.speed.saturating_sub(1),
+            Hotkey::SpeedUp => self.speed = (self.speed + 1).min(SPEEDS.len() - 1),
+            _ => return Some(hotkey),
+        }
+        None
+    }
+
+    pub fn running(&self) -> bool {
+        self.state != RunState::Paused
+    }
+
+    // Called on every 60 Hz timer tick
+    pub fn end_frame(&mut self) {
+        if self.state == RunState::Advancing {
+            self.state = RunState::Paused;
+        }
+    }
+
+    pub fn speed_percent(&self) -> u64 {
+        SPEEDS[self.speed]
+    }
+
+    pub fn cpu_hz(&self, base_hz: u64) -> u64 {
+        (base_hz * self.speed_percent() / 100).max(1)
+    }
+
+    // Empty when running normally, otherwise something like "paused, 200%"
+    pub fn status(&self) -> String {
+        let mut status = Vec::new();
+        if self.state != RunState::Running {
+            status.push("paused".to_string());
+        }
+        if self.speed != NORMAL_SPEED {
+            status.push(format!("{}%", self.speed_percent()));
+        }
+        status.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pause_and_frame_advance() {
+        let mut controls = Controls::new();
+        assert_eq!(controls.apply(Hotkey::Pause), None);
+        assert!(!controls.running());
+        assert_eq!(controls.status(), "paused");
+
+        controls.apply(Hotkey::FrameAdvance);
+        assert!(controls.running());
+        controls.end_frame();
+        assert!(!controls.running());
+
+        controls.apply(Hotkey::Pause);
+        controls.end_frame();
+        assert!(controls.running());
+        assert_eq!(controls.status(), "");
+    }
+
+    #[test]
+    fn speed_steps_and_clamps() {
+        let mut controls = Controls::new();
+        controls.apply(Hotkey::SpeedUp);
+        assert_eq!(controls.cpu_hz(600), 900);
+        assert_eq!(controls.status(), "150%");
+
+        for _ in 0..20 {
+            controls.apply(Hotkey::SpeedDown);
+        }
+        assert_eq!(controls.speed_percent(), 25);
+        for _ in 0..20 {
+            controls.apply(Hotkey::SpeedUp);
+        }
+        assert_eq!(controls.speed_percent(), 800);
+    }
+
+    #[test]
+    fn hands_back_resets_and_quit() {
+        let mut controls = Controls::new();
+        assert_eq!(controls.apply(Hotkey::SoftReset), Some(Hotkey::SoftReset));
+        assert_eq!(controls.apply(Hotkey::HardReset), Some(Hotkey::HardReset));
+        assert_eq!(controls.apply(Hotkey::Quit), Some(Hotkey::Quit));
+        assert!(controls.running());
+    }
+}
@@ -0,0 +1,193 @@
+use super::cpu::{Cpu, CpuError, ProgramChange};
+use super::display::Display;
+use super::keyboard::KeyboardState;
+use super::memory::{Memory, PAGE_SIZE};
+use super::opcode::Opcode;
+
+const MAX_BLOCK_LEN: usize = 64;
+
+type Op = Box<dyn Fn(&mut Cpu, &mut Memory, &mut Display)>;
+
+// A run of straight-line instructions translated into closures. The instruction
+// that ends the block (jumps, calls, returns, skips, DRW and anything else that
+// decides the next PC or touches memory through I) is left at `end` for the
+// interpreter to execute, so a block can neither fault nor modify its own code
+// part way through.
+struct Block {
+    ops: Vec<Op>,
+    end: u16,
+    pages: Vec<(u16, u32)>,
+}
+
+impl Block {
+    fn is_valid(&self, memory: &Memory) -> bool {
+        self.pages
+            .iter()
+            .all(|(page, generation)| memory.page_generation(*page) == *generation)
+    }
+}
+
+#[derive(Default)]
+pub struct BlockCache {
+    // Indexed by start address
+    blocks: Vec<Option<Block>>,
+}
+
+impl std::fmt::Debug for BlockCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("BlockCache")
+            .field("blocks", &self.blocks.iter().filter(|b| b.is_some()).count())
+            .finish()
+    }
+}
+
+impl BlockCache {
+    pub fn new() -> Self {
+        BlockCache::default()
+    }
+
+    // Executes exactly `cycles` instructions, the same as calling `Cpu::step` that many times
+    pub fn run(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut Memory,
+        display: &mut Display,
+        keyboard_state: &KeyboardState,
+        cycles: u64,
+    ) -> Result<ProgramChange, CpuError> {
+        let mut program_change = ProgramChange { redraw: false };
+        let mut remaining = cycles;
+
+        if self.blocks.len() < memory.size() {
+            self.blocks.resize_with(memory.size(), || None);
+        }
+
+        while remaining > 0 {
+            let pc = cpu.state().reg_pc as usize;
+            // Past the end of memory, where the interpreter reports the error
+            if !memory.contains(pc as u16, 2) {
+                program_change.redraw |= cpu.step(memory, display, keyboard_state)?.redraw;
+                remaining -= 1;
+                continue;
+            }
+            let stale = match &self.blocks[pc] {
+                Some(block) => !block.is_valid(memory),
+                None => true,
+            };
+            if stale {
+                self.blocks[pc] = Some(translate(memory, pc as u16));
+            }
+
+            let block = self.blocks[pc].as_ref().unwrap();
+            if block.ops.len() as u64 >= remaining {
+                // Not enough budget left for the whole block
+                program_change.redraw |= cpu.step(memory, display, keyboard_state)?.redraw;
+                remaining -= 1;
+                continue;
+            }
+
+            for op in block.ops.iter() {
+                op(cpu, memory, display);
+            }
+            cpu.set_pc(block.end);
+            program_change.redraw |= cpu.step(memory, display, keyboard_state)?.redraw;
+            remaining -= block.ops.len() as u64 + 1;
+        }
+
+        Ok(program_change)
+    }
+}
+
+fn translate(memory: &mut Memory, start: u16) -> Block {
+    let mut ops = Vec::new();
+    let mut pages = Vec::new();
+    let mut addr = start;
+
+    loop {
+        if ops.len() == MAX_BLOCK_LEN || addr as usize + 3 >= memory.size() {
+            break;
+        }
+        for byte_addr in [addr, addr + 1].iter() {
+            let page = (*byte_addr as usize / PAGE_SIZE * PAGE_SIZE) as u16;
+            if !pages.iter().any(|(p, _)| *p == page) {
+                pages.push((page, memory.page_generation(page)));
+            }
+        }
+        match translate_op(memory.fetch_opcode(addr)) {
+            Some(op) => ops.push(op),
+            None => break,
+        }
+        addr += 2;
+    }
+
+    Block {
+        ops: ops,
+        end: addr,
+        pages: pages,
+    }
+}
+
+fn translate_op(opcode: Opcode) -> Option<Op> {
+    let op: Op = match opcode {
+        Opcode::CLS => Box::new(|cpu, _, display| {
+            cpu.cls(display);
+        }),
+        Opcode::LD_IMM { x, byte } => Box::new(move |cpu, _, _| {
+            cpu.ld_vx_byte(x, byte);
+        }),
+        Opcode::ADD_IMM { x, byte } => Box::new(move |cpu, _, _| {
+            cpu.add_vx_byte(x, byte);
+        }),
+        Opcode::ADD_R { x, y } => Box::new(move |cpu, _, _| {
+            cpu.add_vx_vy(x, y);
+        }),
+        Opcode::SUB_R { x, y } => Box::new(move |cpu, _, _| {
+            cpu.sub_vx_vy(x, y);
+        }),
+        Opcode::SUBN_R { x, y } => Box::new(move |cpu, _, _| {
+            cpu.subn_vx_vy(x, y);
+        }),
+        Opcode::LD_R { x, y } => Box::new(move |cpu, _, _| {
+            cpu.ld_vx_vy(x, y);
+        }),
+        Opcode::LDI_IMM { addr } => Box::new(move |cpu, _, _| {
+            cpu.ld_i_addr(addr);
+        }),
+        Opcode::ADDI_R { x } => Box::new(move |cpu, _, _| {
+            cpu.add_i_vx(x);
+        }),
+        Opcode::LD_F { x } => Box::new(move |cpu, _, _| {
+            cpu.ld_f_vx(x);
+        }),
+        Opcode::SET_DT { x } => Box::new(move |cpu, _, _| {
+            cpu.set_dt(x);
+        }),
+        Opcode::SET_ST { x } => Box::new(move |cpu, _, _| {
+            cpu.set_st(x);
+        }),
+        Opcode::LD_DT { x } => Box::new(move |cpu, _, _| {
+            cpu.ld_dt(x);
+        }),
+        Opcode::AND { x, y } => Box::new(move |cpu, _, _| {
+            cpu.and(x, y);
+        }),
+        Opcode::OR_R { x, y } => Box::new(move |cpu, _, _| {
+            cpu.or(x, y);
+        }),
+        Opcode::SHR { x, y } => Box::new(move |cpu, _, _| {
+            cpu.shr(x, y);
+        }),
+        Opcode::SHL { x, y } => Box::new(move |cpu, _, _| {
+            cpu.shl(x, y);
+        }),
+        Opcode::XOR_R { x, y } => Box::new(move |cpu, _, _| {
+            cpu.xor_vx_vy(x, y);
+        }),
+        Opcode::RND { x, byte } => Box::new(move |cpu, _, _| {
+            cpu.rnd_vx_byte(x, byte);
+        }),
+        _ => return None,
+    };
+
+    Some(op)
+}
@@ -5,38 +5,113 @@ pub struct KeyboardState {
   pressed_keys: [bool; 0x10],
 }
 
+// Host keys bound to each CHIP-8 key, starting from the usual 1234/QWER/ASDF/ZXCV grid
+#[derive(Debug, Clone)]
+pub struct KeyMap {
+  bindings: Vec<(Key, u8)>,
+}
+
+impl Default for KeyMap {
+  fn default() -> Self {
+    KeyMap {
+      bindings: vec![
+        (Key::Key1, 0x1), (Key::Key2, 0x2), (Key::Key3, 0x3), (Key::Key4, 0xC),
+        (Key::Q, 0x4), (Key::W, 0x5), (Key::E, 0x6), (Key::R, 0xD),
+        (Key::A, 0x7), (Key::S, 0x8), (Key::D, 0x9), (Key::F, 0xE),
+        (Key::Z, 0xA), (Key::X, 0x0), (Key::C, 0xB), (Key::V, 0xF),
+      ],
+    }
+  }
+}
+
+impl KeyMap {
+  // Adds `host_key` (a name like "Left", "Space" or "K") as another way to press `key`
+  pub fn bind(&mut self, host_key: &str, key: u8) -> Result<(), String> {
+    let host_key = parse_key(host_key).ok_or(format!("Unknown key: {}", host_key))?;
+    if key > 0xF {
+      return Err(format!("Invalid CHIP-8 key: {:#X}", key));
+    }
+    self.bindings.retain(|(bound, _)| *bound != host_key);
+    self.bindings.push((host_key, key));
+    Ok(())
+  }
+
+  pub fn host_keys(&self, key: u8) -> Vec<String> {
+    self.bindings
+      .iter()
+      .filter(|(_, bound)| *bound == key)
+      .map(|(host_key, _)| key_name(*host_key))
+      .collect()
+  }
+}
+
+const NAMED_KEYS: [(&str, Key); 9] = [
+  ("Up", Key::Up),
+  ("Down", Key::Down),
+  ("Left", Key::Left),
+  ("Right", Key::Right),
+  ("Space", Key::Space),
+  ("Enter", Key::Enter),
+  ("Tab", Key::Tab),
+  ("LeftShift", Key::LeftShift),
+  ("LeftCtrl", Key::LeftCtrl),
+];
+
+const LETTER_KEYS: [Key; 26] = [
+  Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I, Key::J, Key::K, Key::L, Key::M,
+  Key::N, Key::O, Key::P, Key::Q, Key::R, Key::S, Key::T, Key::U, Key::V, Key::W, Key::X, Key::Y, Key::Z,
+];
+
+const DIGIT_KEYS: [Key; 10] = [
+  Key::Key0, Key::Key1, Key::Key2, Key::Key3, Key::Key4, Key::Key5, Key::Key6, Key::Key7, Key::Key8, Key::Key9,
+];
+
+fn parse_key(name: &str) -> Option<Key> {
+  if let Some((_, key)) = NAMED_KEYS.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)) {
+    return Some(*key);
+  }
+
+  let mut chars = name.chars();
+  match (chars.next(), chars.next()) {
+    (Some(c), None) if c.is_ascii_alphabetic() => {
+      Some(LETTER_KEYS[(c.to_ascii_uppercase() as u8 - b'A') as usize])
+    }
+    (Some(c), None) if c.is_ascii_digit() => Some(DIGIT_KEYS[(c as u8 - b'0') as usize]),
+    _ => None,
+  }
+}
+
+fn key_name(key: Key) -> String {
+  let name = format!("{:?}", key);
+  match DIGIT_KEYS.iter().position(|digit| *digit == key) {
+    Some(digit) => digit.to_string(),
+    None => name,
+  }
+}
+
 impl KeyboardState {
-  pub fn get_keyoard_state(window: &mut Window) -> Self {
+  pub fn get_keyoard_state(window: &mut Window, key_map: &KeyMap) -> Self {
     window.update();
     let mut keyboard_state = KeyboardState::default();
     let keys = window.get_keys_pressed(KeyRepeat::Yes).unwrap();
     for key in keys.iter() {
-      match key {
-        Key::Key1 => keyboard_state.pressed_keys[0x1] = true,
-        Key::Key2 => keyboard_state.pressed_keys[0x2] = true,
-        Key::Key3 => keyboard_state.pressed_keys[0x3] = true,
-        Key::Key4 => keyboard_state.pressed_keys[0xC] = true,
-        Key::Q => keyboard_state.pressed_keys[0x4] = true,
-        Key::W => keyboard_state.pressed_keys[0x5] = true,
-        Key::E => keyboard_state.pressed_keys[0x6] = true,
-        Key::R => keyboard_state.pressed_keys[0xD] = true,
-        Key::A => keyboard_state.pressed_keys[0x7] = true,
-        Key::S => keyboard_state.pressed_keys[0x8] = true,
-        Key::D => keyboard_state.pressed_keys[0x9] = true,
-        Key::F => keyboard_state.pressed_keys[0xE] = true,
-        Key::Z => keyboard_state.pressed_keys[0xA] = true,
-        Key::X => keyboard_state.pressed_keys[0x0] = true,
-        Key::C => keyboard_state.pressed_keys[0xB] = true,
-        Key::V => keyboard_state.pressed_keys[0xF] = true,
-        _ => {}
+      for (host_key, chip8_key) in key_map.bindings.iter() {
+        if host_key == key {
+          keyboard_state.pressed_keys[*chip8_key as usize] = true;
+        }
       }
     }
 
     keyboard_state
   }
 
+  pub fn set_key(&mut self, key_index: u8, pressed: bool) {
+    self.pressed_keys[(key_index & 0xF) as usize] = pressed;
+  }
+
   pub fn is_key_pressed(&self, key_index: u8) -> bool {
-    return self.pressed_keys[key_index as usize];
+    // Only the low nibble selects a key, as on the original interpreter
+    return self.pressed_keys[(key_index & 0xF) as usize];
   }
 
   pub fn get_pressed_keys(&self) -> Vec<u8> {
@@ -50,3 +125,29 @@ impl KeyboardState {
     keys_pressed
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn binds_named_keys() {
+    let mut key_map = KeyMap::default();
+    key_map.bind("left", 0x4).unwrap();
+    key_map.bind("k", 0x4).unwrap();
+
+    assert_eq!(key_map.host_keys(0x4), vec!["Q", "Left", "K"]);
+    assert_eq!(key_map.host_keys(0x1), vec!["1"]);
+    assert!(key_map.bind("Nope", 0x1).is_err());
+    assert!(key_map.bind("Up", 0x10).is_err());
+  }
+
+  #[test]
+  fn rebinding_a_host_key_replaces_it() {
+    let mut key_map = KeyMap::default();
+    key_map.bind("Q", 0x5).unwrap();
+
+    assert!(key_map.host_keys(0x4).is_empty());
+    assert_eq!(key_map.host_keys(0x5), vec!["W", "Q"]);
+  }
+}
@@ -0,0 +1,33 @@
+pub mod asm;
+pub mod browser;
+pub mod cartridge;
+pub mod cdp1802;
+pub mod chip8;
+pub mod clock;
+pub mod config;
+pub mod cpu;
+pub mod database;
+pub mod debugger;
+pub mod disasm;
+pub mod display;
+pub mod flow;
+pub mod gdb;
+pub mod headless;
+pub mod hotkeys;
+pub mod jit;
+pub mod keyboard;
+pub mod loader;
+pub mod memory;
+pub mod octo;
+pub mod opcode;
+pub mod osd;
+pub mod png;
+pub mod profile;
+pub mod quirks;
+pub mod rom;
+pub mod script;
+pub mod sprite;
+pub mod strict;
+pub mod symbols;
+pub mod timing;
+pub mod trace;
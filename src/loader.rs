@@ -0,0 +1,218 @@
+use super::browser;
+use super::cartridge::{self, CartridgeError, OctoOptions};
+use super::octo::{self, OctoError};
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Loaded {
+    Program(Vec<u8>),
+    // Compiled from an Octo cartridge, with the options it was saved with
+    Cartridge {
+        program: Vec<u8>,
+        options: OctoOptions,
+    },
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io { path: PathBuf, error: io::Error },
+    Zip(ZipError),
+    NoRoms,
+    NotChosen,
+    Cartridge(CartridgeError),
+    Octo(OctoError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            LoadError::Zip(e) => write!(f, "could not read the archive: {}", e),
+            LoadError::NoRoms => write!(f, "the archive holds no ROMs or cartridges"),
+            LoadError::NotChosen => write!(f, "no ROM was chosen from the archive"),
+            LoadError::Cartridge(e) => write!(f, "{}", e),
+            LoadError::Octo(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<ZipError> for LoadError {
+    fn from(error: ZipError) -> Self {
+        LoadError::Zip(error)
+    }
+}
+
+impl From<CartridgeError> for LoadError {
+    fn from(error: CartridgeError) -> Self {
+        LoadError::Cartridge(error)
+    }
+}
+
+impl From<OctoError> for LoadError {
+    fn from(error: OctoError) -> Self {
+        LoadError::Octo(error)
+    }
+}
+
+// Reads a ROM, Octo source or cartridge, or a zip archive of them. When an archive
+// holds more than one, `choose` is given their names and returns the index of
+// the one to load.
+pub fn load<P, F>(path: P, choose: F) -> Result<Loaded, LoadError>
+where
+    P: AsRef<Path>,
+    F: FnOnce(&[String]) -> Option<usize>,
+{
+    let path = path.as_ref();
+    let bytes = fs::read(path).map_err(|e| LoadError::Io {
+        path: path.to_path_buf(),
+        error: e,
+    })?;
+    from_bytes(path, bytes, choose)
+}
+
+// `path` is only used to recognise Octo source by its extension
+pub fn from_bytes<P, F>(path: P, bytes: Vec<u8>, choose: F) -> Result<Loaded, LoadError>
+where
+    P: AsRef<Path>,
+    F: FnOnce(&[String]) -> Option<usize>,
+{
+    if !bytes.starts_with(ZIP_MAGIC) {
+        return unpack(path.as_ref(), bytes);
+    }
+
+    let mut archive = ZipArchive::new(Cursor::new(bytes))?;
+    let mut names: Vec<String> = archive
+        .file_names()
+        .filter(|name| is_loadable(name))
+        .map(String::from)
+        .collect();
+    names.sort();
+    let name = match names.len() {
+        0 => return Err(LoadError::NoRoms),
+        1 => &names[0],
+        _ => choose(&names)
+            .and_then(|i| names.get(i))
+            .ok_or(LoadError::NotChosen)?,
+    };
+
+    let mut file = archive.by_name(name)?;
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents).map_err(ZipError::Io)?;
+    unpack(Path::new(name), contents)
+}
+
+// Archives nest no further than one level
+fn unpack(path: &Path, bytes: Vec<u8>) -> Result<Loaded, LoadError> {
+    if cartridge::is_gif(&bytes) {
+        let cartridge = cartridge::decode(&bytes)?;
+        Ok(Loaded::Cartridge {
+            program: octo::compile(&cartridge.program)?.bytes,
+            options: cartridge.options,
+        })
+    } else if browser::is_octo_source(path) {
+        let source = String::from_utf8_lossy(&bytes);
+        Ok(Loaded::Program(octo::compile(&source)?.bytes))
+    } else {
+        Ok(Loaded::Program(bytes))
+    }
+}
+
+// ROMs and cartridges, skipping the resource forks macOS adds to archives
+fn is_loadable(name: &str) -> bool {
+    let path = Path::new(name);
+    !name.starts_with("__MACOSX/") && browser::is_rom(path) && !browser::is_archive(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    fn archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, contents) in files {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(contents).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn never_asked(_: &[String]) -> Option<usize> {
+        panic!("only one ROM to choose from")
+    }
+
+    #[test]
+    fn passes_raw_roms_through() {
+        assert_eq!(
+            from_bytes("game.ch8", vec![0x00, 0xE0], never_asked).unwrap(),
+            Loaded::Program(vec![0x00, 0xE0])
+        );
+    }
+
+    #[test]
+    fn picks_the_only_rom_in_an_archive() {
+        let zip = archive(&[
+            ("readme.txt", b"Have fun"),
+            ("__MACOSX/._game.ch8", b"junk"),
+            ("game/game.ch8", &[0x12, 0x00]),
+        ]);
+        assert_eq!(
+            from_bytes("pack.zip", zip, never_asked).unwrap(),
+            Loaded::Program(vec![0x12, 0x00])
+        );
+    }
+
+    #[test]
+    fn asks_when_an_archive_holds_several() {
+        let zip = archive(&[("b.sc8", &[0xBB]), ("a.ch8", &[0xAA])]);
+        let loaded = from_bytes("pack.zip", zip.clone(), |names| {
+            assert_eq!(names, ["a.ch8", "b.sc8"]);
+            Some(1)
+        });
+        assert_eq!(loaded.unwrap(), Loaded::Program(vec![0xBB]));
+
+        assert!(matches!(
+            from_bytes("pack.zip", zip, |_| None),
+            Err(LoadError::NotChosen)
+        ));
+        assert!(matches!(
+            from_bytes("pack.zip", archive(&[("notes.txt", b"")]), never_asked),
+            Err(LoadError::NoRoms)
+        ));
+    }
+
+    #[test]
+    fn compiles_octo_source() {
+        let source = b": main\n  v0 := 1\n  loop again\n";
+        assert_eq!(
+            from_bytes("game.8o", source.to_vec(), never_asked).unwrap(),
+            Loaded::Program(vec![0x60, 0x01, 0x12, 0x02])
+        );
+        assert_eq!(
+            from_bytes("game.ch8", source.to_vec(), never_asked).unwrap(),
+            Loaded::Program(source.to_vec())
+        );
+
+        let zip = archive(&[("src/game.8o", b": main\n  jump main\n")]);
+        assert_eq!(
+            from_bytes("pack.zip", zip, never_asked).unwrap(),
+            Loaded::Program(vec![0x12, 0x00])
+        );
+        assert!(matches!(
+            from_bytes("bad.8o", b": main\n  v0 := 300".to_vec(), never_asked),
+            Err(LoadError::Octo(OctoError { line: 2, .. }))
+        ));
+    }
+}
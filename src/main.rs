@@ -1,39 +1,1030 @@
-mod chip8;
-mod clock;
-mod cpu;
-mod display;
-mod keyboard;
-mod memory;
-mod opcode;
-
-use chip8::Chip8;
-use std::fs::File;
-use std::io::Read;
-use std::path::Path;
+use chip8_emu::asm;
+use chip8_emu::browser;
+use chip8_emu::cartridge::OctoOptions;
+use chip8_emu::chip8::Chip8;
+use chip8_emu::config::{self, Config, Recent, RomConfig};
+use chip8_emu::database::{self, Database, Entry};
+use chip8_emu::disasm;
+use chip8_emu::flow::{ByteKind, Flow};
+use chip8_emu::headless::Headless;
+use chip8_emu::jit::BlockCache;
+use chip8_emu::keyboard::KeyMap;
+use chip8_emu::loader::{self, LoadError, Loaded};
+use chip8_emu::octo;
+use chip8_emu::png;
+use chip8_emu::profile::Profiler;
+use chip8_emu::quirks::{self, Quirks};
+use chip8_emu::rom::Layout;
+use chip8_emu::script::Script;
+use chip8_emu::strict::Strict;
+use chip8_emu::symbols::{self, SymbolMap};
+use chip8_emu::trace::{TraceFilter, TraceFormat, Tracer};
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
+use clap::{value_t, App, AppSettings, Arg, ArgMatches, SubCommand};
 use minifb::{Window, WindowOptions};
 const WIDTH: usize = 64;
 const HEIGHT: usize = 32;
 
+const PLATFORMS: [&str; 5] = ["chip8", "vip", "eti660", "schip", "xochip"];
+const SCALES: [&str; 6] = ["1", "2", "4", "8", "16", "32"];
+
+const HOTKEY_HELP: &str = "HOTKEYS:
+    F1        Debugger
+    F2        Pause or resume
+    F3        Advance one frame
+    F4        Show frame rate, speed and quirks
+    F5        Reset
+    F6        Reset and clear memory
+    F7        Choose another ROM
+    - and =   Slower and faster
+    Escape    Quit";
+
 fn main() {
-    let program_file = std::env::args().nth(1).unwrap();
-    let program = read_bin(program_file);
-    let mut window_options = WindowOptions::default();
-    window_options.scale = minifb::Scale::X16;
-    let mut window = Window::new("CHIP8", WIDTH, HEIGHT, window_options).unwrap_or_else(|e| {
-        panic!("{}", e);
+    let matches = app().get_matches();
+    let result = match matches.subcommand() {
+        ("run", Some(matches)) => run(matches),
+        ("headless", Some(matches)) => headless(matches),
+        ("trace", Some(matches)) => trace(matches),
+        ("bench", Some(matches)) => bench(matches),
+        ("profile", Some(matches)) => profile(matches),
+        ("info", Some(matches)) => info(matches),
+        ("disasm", Some(matches)) => disassemble(matches),
+        ("flow", Some(matches)) => flow(matches),
+        ("asm", Some(matches)) => assemble(matches),
+        _ => unreachable!(),
+    };
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn app() -> App<'static, 'static> {
+    App::new("chip8-emu")
+        .about("CHIP-8 emulator, debugger and assembler")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("FILE")
+                .global(true)
+                .help("Settings file to use instead of the one in the user config directory"),
+        )
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("Runs a ROM in a window")
+                .after_help(HOTKEY_HELP)
+                .args(&machine_args())
+                .arg(
+                    Arg::with_name("scale")
+                        .long("scale")
+                        .value_name("N")
+                        .possible_values(&SCALES)
+                        .help("Window pixels per CHIP-8 pixel"),
+                )
+                .arg(
+                    Arg::with_name("gdb")
+                        .long("gdb")
+                        .value_name("PORT")
+                        .validator(|v| parse_number::<u16>(&v).map(drop))
+                        .help("Waits for a GDB connection on PORT before running"),
+                )
+                .arg(
+                    Arg::with_name("vip-timing")
+                        .long("vip-timing")
+                        .help("Charges instructions their COSMAC VIP cycle cost"),
+                )
+                .arg(
+                    Arg::with_name("trace")
+                        .long("trace")
+                        .value_name("FILE")
+                        .help("Writes an instruction trace to FILE"),
+                )
+                .args(&trace_args())
+                .arg(symbols_arg())
+                .arg(
+                    Arg::with_name("break")
+                        .long("break")
+                        .value_name("LABEL|ADDR")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Stops in the debugger before the instruction at a label or hex address"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("headless")
+                .about("Runs a ROM without a window and prints the screen")
+                .args(&machine_args())
+                .arg(frames_arg())
+                .arg(
+                    Arg::with_name("screenshot")
+                        .long("screenshot")
+                        .value_name("PNG")
+                        .help("Saves the final screen as a PNG"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("trace")
+                .about("Runs a ROM without a window, tracing every instruction")
+                .args(&machine_args())
+                .arg(frames_arg())
+                .arg(
+                    Arg::with_name("out")
+                        .short("o")
+                        .long("out")
+                        .value_name("FILE")
+                        .help("Writes the trace to FILE instead of standard output"),
+                )
+                .args(&trace_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("bench")
+                .about("Measures how fast a ROM runs")
+                .args(&machine_args())
+                .arg(
+                    Arg::with_name("cycles")
+                        .long("cycles")
+                        .value_name("N")
+                        .default_value("1000000")
+                        .validator(|v| parse_number::<u64>(&v).map(drop))
+                        .help("Instructions to run"),
+                )
+                .arg(
+                    Arg::with_name("jit")
+                        .long("jit")
+                        .help("Runs translated blocks instead of interpreting"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("profile")
+                .about("Runs a ROM without a window and reports where its time goes")
+                .args(&machine_args())
+                .arg(frames_arg())
+                .arg(symbols_arg())
+                .arg(
+                    Arg::with_name("out")
+                        .short("o")
+                        .long("out")
+                        .value_name("FILE")
+                        .help("Writes the report to FILE instead of standard output"),
+                )
+                .arg(
+                    Arg::with_name("listing")
+                        .long("listing")
+                        .value_name("FILE")
+                        .help("Writes a disassembly showing how often each instruction ran"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("info")
+                .about("Shows what the ROM database knows about a ROM")
+                .arg(rom_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("disasm")
+                .about("Disassembles a ROM into source `asm` accepts")
+                .arg(rom_arg())
+                .arg(origin_arg())
+                .arg(symbols_arg())
+                .arg(
+                    Arg::with_name("analyze")
+                        .long("analyze")
+                        .help("Decodes only code reached from the start and names its targets"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("flow")
+                .about("Follows a ROM's code to find subroutines, data and unreachable bytes")
+                .arg(rom_arg())
+                .arg(origin_arg())
+                .arg(
+                    Arg::with_name("dot")
+                        .long("dot")
+                        .value_name("FILE")
+                        .help("Writes the control-flow graph as Graphviz DOT"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("asm")
+                .about("Assembles source, or compiles Octo source ending in .8o, into a ROM")
+                .arg(
+                    Arg::with_name("SOURCE")
+                        .required(true)
+                        .validator_os(file_exists),
+                )
+                .arg(
+                    Arg::with_name("out")
+                        .short("o")
+                        .long("out")
+                        .value_name("FILE")
+                        .help("Where to write the ROM, by default SOURCE with a .ch8 extension"),
+                )
+                .arg(origin_arg()),
+        )
+}
+
+fn symbols_arg() -> Arg<'static, 'static> {
+    Arg::with_name("symbols")
+        .long("symbols")
+        .value_name("FILE")
+        .validator_os(file_exists)
+        .help("Symbol map written by `asm`, by default the ROM's name ending in .sym")
+}
+
+fn rom_arg() -> Arg<'static, 'static> {
+    Arg::with_name("ROM")
+        .required(true)
+        .validator_os(file_exists)
+}
+
+fn origin_arg() -> Arg<'static, 'static> {
+    Arg::with_name("origin")
+        .long("origin")
+        .value_name("ADDR")
+        .default_value("200")
+        .validator(|v| parse_address(&v).map(drop))
+        .help("Address the program is loaded at, in hex")
+}
+
+fn frames_arg() -> Arg<'static, 'static> {
+    Arg::with_name("frames")
+        .long("frames")
+        .value_name("N")
+        .default_value("300")
+        .validator(|v| parse_number::<u64>(&v).map(drop))
+        .help("60 Hz frames to run")
+}
+
+// Options for every subcommand that runs a ROM. Anything left out comes from the
+// ROM database, then from the defaults.
+fn machine_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        rom_arg(),
+        Arg::with_name("platform")
+            .long("platform")
+            .value_name("NAME")
+            .possible_values(&PLATFORMS)
+            .help("Machine to emulate"),
+        Arg::with_name("quirks")
+            .long("quirks")
+            .value_name("PRESET")
+            .possible_values(&quirks::PRESETS)
+            .help("Interpreter behaviours to follow"),
+        Arg::with_name("speed")
+            .long("speed")
+            .value_name("N")
+            .validator(|v| parse_number::<u64>(&v).map(drop))
+            .help("Instructions per 60 Hz frame"),
+        Arg::with_name("palette")
+            .long("palette")
+            .value_name("BG,FG")
+            .validator(|v| parse_palette(&v).map(drop))
+            .help("Background and foreground colours, like #000000,#FFFF00"),
+        Arg::with_name("seed")
+            .long("seed")
+            .value_name("N")
+            .validator(|v| parse_number::<u64>(&v).map(drop))
+            .help("Seeds the random number generator so runs repeat"),
+        Arg::with_name("key")
+            .long("key")
+            .value_name("HOST=KEY")
+            .multiple(true)
+            .number_of_values(1)
+            .validator(|v| parse_binding(&v).map(drop))
+            .help("Binds a host key to a CHIP-8 key, like Space=5"),
+        Arg::with_name("load-addr")
+            .long("load-addr")
+            .value_name("ADDR")
+            .validator(|v| parse_address(&v).map(drop))
+            .help("Address to load the ROM at, in hex"),
+        Arg::with_name("entry")
+            .long("entry")
+            .value_name("ADDR")
+            .validator(|v| parse_address(&v).map(drop))
+            .help("Address to start running at, in hex"),
+        Arg::with_name("strict")
+            .long("strict")
+            .help("Warns about suspicious memory use, jumps and stack depth"),
+        Arg::with_name("script")
+            .long("script")
+            .value_name("FILE")
+            .help("Runs a Rhai script that can watch and drive the machine"),
+    ]
+}
+
+fn trace_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("format")
+            .long("format")
+            .value_name("FORMAT")
+            .possible_values(&["text", "json"])
+            .default_value("text")
+            .help("Trace line format"),
+        Arg::with_name("addr")
+            .long("addr")
+            .value_name("START-END")
+            .validator(|v| parse_address_range(&v).map(drop))
+            .help("Only traces instructions in this address range, in hex"),
+        Arg::with_name("op")
+            .long("op")
+            .value_name("NAME,...")
+            .help("Only traces these instructions, by mnemonic like JP or LD"),
+        Arg::with_name("cycles")
+            .long("cycles")
+            .value_name("START-END")
+            .validator(|v| parse_range(&v, 10).map(drop))
+            .help("Only traces this range of cycles"),
+    ]
+}
+
+// What to run and how, with command line options winning over the config file
+// and the ROM database
+struct Settings<'a> {
+    program: Vec<u8>,
+    entry: Option<Entry<'a>>,
+    platform: String,
+    layout: Layout,
+    quirks: Option<Quirks>,
+    cycles_per_frame: Option<u64>,
+    palette: Option<[u32; 2]>,
+    scale: Option<u32>,
+    key_map: KeyMap,
+    seed: Option<u64>,
+    strict: bool,
+    script: Option<PathBuf>,
+}
+
+fn settings<'a>(
+    matches: &ArgMatches,
+    rom_path: &Path,
+    config: &Config,
+    database: &'a Database,
+) -> Result<Settings<'a>, Box<dyn Error>> {
+    let (program, options) = read_rom(rom_path)?;
+    let entry = database.lookup(&program);
+    let rom = entry.as_ref().map(|entry| entry.rom);
+    let empty = RomConfig::default();
+    let user = config.rom(&database::sha1_hex(&program)).unwrap_or(&empty);
+    let defaults = &config.defaults;
+
+    let platform = matches
+        .value_of("platform")
+        .map(String::from)
+        .or_else(|| user.platform.clone())
+        .or_else(|| rom.and_then(|rom| rom.platform.clone()))
+        .or_else(|| defaults.platform.clone())
+        .unwrap_or_else(|| "chip8".to_string());
+    // Only the database or the config can name a platform not listed here
+    let mut layout = Layout::preset(&platform).unwrap_or_else(|| {
+        eprintln!("warning: unknown platform {}, loading at 0x200", platform);
+        Layout::default()
     });
+    if let Some(load_address) = matches.value_of("load-addr") {
+        layout = Layout::at(parse_address(load_address)?);
+    }
+    if let Some(entry_point) = matches.value_of("entry") {
+        layout.entry_point = parse_address(entry_point)?;
+    }
+
+    // A cartridge's options rank just above the database
+    let preset =
+        |name: &str| Quirks::preset(name).ok_or_else(|| format!("Unknown quirk preset: {}", name));
+    let quirks = match (
+        matches.value_of("quirks").or(user.quirks.as_deref()),
+        &options,
+    ) {
+        (Some(name), _) => Some(preset(name)?),
+        (None, Some(options)) => Some(options.quirks()),
+        (None, None) => rom
+            .and_then(|rom| rom.quirks.as_deref())
+            .or(defaults.quirks.as_deref())
+            .map(preset)
+            .transpose()?,
+    };
+    let cycles_per_frame = match matches.value_of("speed") {
+        Some(speed) => Some(parse_number(speed)?),
+        None => user
+            .speed
+            .or_else(|| options.as_ref().and_then(|options| options.tickrate))
+            .or_else(|| rom.and_then(|rom| rom.tickrate))
+            .or(defaults.speed),
+    };
+    let user_palette = user.palette.as_deref().map(config_palette).transpose()?;
+    let default_palette = defaults
+        .palette
+        .as_deref()
+        .map(config_palette)
+        .transpose()?;
+    let palette = match matches.value_of("palette") {
+        Some(palette) => Some(parse_palette(palette)?),
+        None => user_palette
+            .or_else(|| options.as_ref().and_then(|options| options.palette()))
+            .or_else(|| entry.as_ref().and_then(|entry| entry.palette()))
+            .or(default_palette),
+    };
+    let scale = match matches.value_of("scale") {
+        Some(scale) => Some(parse_number(scale)?),
+        None => user.scale.or(defaults.scale),
+    };
+    let mut key_map = match &entry {
+        Some(entry) => entry.key_map()?,
+        None => KeyMap::default(),
+    };
+    for (host_key, key) in defaults.keys.iter().chain(user.keys.iter()) {
+        key_map.bind(host_key, *key)?;
+    }
+    for binding in matches.values_of("key").into_iter().flatten() {
+        let (host_key, key) = parse_binding(binding)?;
+        key_map.bind(&host_key, key)?;
+    }
+    let seed = match matches.value_of("seed") {
+        Some(seed) => Some(parse_number(seed)?),
+        None => None,
+    };
+
+    Ok(Settings {
+        program: program,
+        entry: entry,
+        platform: platform,
+        layout: layout,
+        quirks: quirks,
+        cycles_per_frame: cycles_per_frame,
+        palette: palette,
+        scale: scale,
+        key_map: key_map,
+        seed: seed,
+        strict: matches.is_present("strict"),
+        script: matches.value_of_os("script").map(PathBuf::from),
+    })
+}
+
+impl<'a> Settings<'a> {
+    fn headless(&self) -> Result<Headless, Box<dyn Error>> {
+        let mut machine = Headless::new(&self.program, self.layout, self.seed.unwrap_or(0))?;
+        if let Some(quirks) = self.quirks {
+            machine.cpu.set_quirks(quirks);
+        }
+        if let Some(cycles) = self.cycles_per_frame {
+            machine.set_cycles_per_frame(cycles);
+        }
+        machine.cpu.set_vip_hybrid(self.platform == "vip");
+        if self.strict {
+            machine.set_strict(Strict::new(&self.program, self.layout));
+        }
+        if let Some(path) = &self.script {
+            machine.set_script(Script::load(path)?)?;
+        }
+        Ok(machine)
+    }
+
+    // Prints what the game is and how to play it
+    fn describe(&self) {
+        if let Some(entry) = &self.entry {
+            println!("{}", entry.program.title);
+            if !entry.program.description.is_empty() {
+                println!("{}", entry.program.description);
+            }
+            for line in entry.controls(&self.key_map) {
+                println!("  {}", line);
+            }
+        }
+    }
+}
+
+fn run(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let (config, config_path) = load_config(matches)?;
+    let database = Database::bundled();
+    let mut rom_path = PathBuf::from(matches.value_of_os("ROM").unwrap());
+    let initial = settings(matches, &rom_path, &config, &database)?;
+
+    // Scaled in software so the overlay text can be finer than CHIP-8 pixels.
+    // The window is kept for any ROM picked later, at this size.
+    let scale = initial.scale.unwrap_or(16) as usize;
+    if !(1..=32).contains(&scale) {
+        return Err(format!("Unsupported scale: {}", scale).into());
+    }
+    let mut window = Window::new(
+        "CHIP8",
+        WIDTH * scale,
+        HEIGHT * scale,
+        WindowOptions::default(),
+    )?;
 
     // Limit to max ~60 fps update rate
     window.limit_update_rate(None);
-    let mut chip8 = Chip8::new(program, window);
-    chip8.run();
+    let mut initial = Some(initial);
+
+    loop {
+        let settings = match initial.take() {
+            Some(settings) => settings,
+            None => settings(matches, &rom_path, &config, &database)?,
+        };
+        settings.describe();
+
+        let mut recent = Recent::default();
+        if let Some(path) = &config_path {
+            let recent_path = config::recent_path(path);
+            match Recent::load(&recent_path) {
+                Ok(loaded) => recent = loaded,
+                Err(e) => eprintln!("warning: could not read recent ROMs: {}", e),
+            }
+            recent.add(&rom_path);
+            if let Err(e) = recent.save(&recent_path) {
+                eprintln!("warning: could not save recent ROMs: {}", e);
+            }
+        }
+
+        let mut chip8 = Chip8::new(
+            settings.program.clone().into_boxed_slice(),
+            settings.layout,
+            window,
+        )?;
+        chip8.set_scale(scale);
+        chip8.set_title(&match &settings.entry {
+            Some(entry) => format!("CHIP8 - {}", entry.program.title),
+            None => "CHIP8".to_string(),
+        });
+        if let Some(quirks) = settings.quirks {
+            chip8.set_quirks(quirks);
+        }
+        if let Some(cycles) = settings.cycles_per_frame {
+            chip8.set_cycles_per_frame(cycles);
+        }
+        if let Some(palette) = settings.palette {
+            chip8.set_palette(palette);
+        }
+        if let Some(seed) = settings.seed {
+            chip8.set_seed(seed);
+        }
+        chip8.set_key_map(settings.key_map.clone());
+        // The VIP runs 0nnn machine code routines, the others treat them as invalid
+        chip8.set_vip_hybrid(settings.platform == "vip");
+        chip8.set_vip_timing(matches.is_present("vip-timing"));
+        if settings.strict {
+            chip8.set_strict(Strict::new(&settings.program, settings.layout));
+        }
+        chip8.set_recent(&recent.roms);
+        match &config.rom_dir {
+            Some(dir) => chip8.set_rom_dir(dir),
+            None => chip8.set_rom_dir(rom_path.parent().unwrap_or_else(|| Path::new("."))),
+        }
+
+        // Debugging and tracing options are for the ROM named on the command line
+        let started_from_command_line = matches.value_of_os("ROM") == Some(rom_path.as_os_str());
+        if let Some(symbols) = load_symbols(matches, &rom_path, started_from_command_line)? {
+            chip8.set_symbols(symbols);
+        }
+        if started_from_command_line {
+            for target in matches.values_of("break").into_iter().flatten() {
+                chip8.add_breakpoint(target)?;
+            }
+        }
+        if let (Some(path), true) = (matches.value_of("trace"), started_from_command_line) {
+            let (format, filter) = trace_options(matches)?;
+            chip8.set_tracer(Tracer::to_file(path, format, filter)?);
+        }
+        if let (Some(path), true) = (&settings.script, started_from_command_line) {
+            chip8.set_script(Script::load(path)?)?;
+        }
+        match (matches.value_of("gdb"), started_from_command_line) {
+            (Some(port), true) => chip8.run_gdb(parse_number(port)?)?,
+            _ => chip8.run()?,
+        }
+
+        rom_path = match chip8.take_next_rom() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        window = chip8.into_window();
+    }
+}
+
+fn headless(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let (config, _) = load_config(matches)?;
+    let database = Database::bundled();
+    let rom_path = Path::new(matches.value_of_os("ROM").unwrap());
+    let settings = settings(matches, rom_path, &config, &database)?;
+    let mut machine = settings.headless()?;
+    let result = run_frames(&mut machine, value_t!(matches, "frames", u64)?);
+    warn_diagnostics(&machine);
+
+    print!("{}", machine.screen_text());
+    if let Some(path) = matches.value_of("screenshot") {
+        let [background, foreground] = settings.palette.unwrap_or([0x000000, 0xFFFF00]);
+        let pixels: Vec<u32> = machine
+            .display
+            .framebuffer
+            .iter()
+            .map(|pixel| if *pixel != 0 { foreground } else { background })
+            .collect();
+        let mut file = BufWriter::new(File::create(path)?);
+        png::write_png(&mut file, WIDTH, HEIGHT, &pixels)?;
+    }
+    result
+}
+
+fn trace(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let (config, _) = load_config(matches)?;
+    let database = Database::bundled();
+    let rom_path = Path::new(matches.value_of_os("ROM").unwrap());
+    let settings = settings(matches, rom_path, &config, &database)?;
+    let mut machine = settings.headless()?;
+
+    let (format, filter) = trace_options(matches)?;
+    let tracer = match matches.value_of("out") {
+        Some(path) => Tracer::to_file(path, format, filter)?,
+        None => Tracer::new(Box::new(BufWriter::new(io::stdout())), format, filter),
+    };
+    machine.set_tracer(tracer);
+    let result = run_frames(&mut machine, value_t!(matches, "frames", u64)?);
+    warn_diagnostics(&machine);
+    result
+}
+
+// A script that fails or a trace that can't be written ends the run like a CPU error
+fn run_frames(machine: &mut Headless, frames: u64) -> Result<(), Box<dyn Error>> {
+    machine.run_frames(frames)?;
+    if let Some(e) = machine.take_script_error() {
+        return Err(e.into());
+    }
+    match machine.take_trace_error() {
+        // Whatever reads the trace has seen enough, like `trace rom.ch8 | head`
+        Some(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        Some(e) => Err(e.into()),
+        None => Ok(()),
+    }
+}
+
+// What --strict found, after the run so it doesn't mix with the output
+fn warn_diagnostics(machine: &Headless) {
+    for diagnostic in machine.diagnostics() {
+        eprintln!("warning: {}", diagnostic);
+    }
 }
 
-fn read_bin<P: AsRef<Path>>(path: P) -> Box<[u8]> {
-    let mut file = File::open(path).unwrap();
-    let mut file_buf = Vec::new();
+fn bench(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let (config, _) = load_config(matches)?;
+    let database = Database::bundled();
+    let rom_path = Path::new(matches.value_of_os("ROM").unwrap());
+    let settings = settings(matches, rom_path, &config, &database)?;
+    let mut machine = settings.headless()?;
+    let cycles = value_t!(matches, "cycles", u64)?;
+
+    let start = Instant::now();
+    if matches.is_present("jit") {
+        let mut jit = BlockCache::new();
+        jit.run(
+            &mut machine.cpu,
+            &mut machine.memory,
+            &mut machine.display,
+            &machine.keyboard_state,
+            cycles,
+        )?;
+    } else {
+        for _ in 0..cycles {
+            machine.step()?;
+        }
+        if let Some(e) = machine.take_script_error() {
+            return Err(e.into());
+        }
+    }
+    let elapsed = start.elapsed();
+    warn_diagnostics(&machine);
+
+    println!(
+        "{} instructions in {:.3} s, {:.0} instructions per second",
+        cycles,
+        elapsed.as_secs_f64(),
+        cycles as f64 / elapsed.as_secs_f64()
+    );
+    Ok(())
+}
+
+fn profile(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let (config, _) = load_config(matches)?;
+    let database = Database::bundled();
+    let rom_path = Path::new(matches.value_of_os("ROM").unwrap());
+    let settings = settings(matches, rom_path, &config, &database)?;
+    let mut machine = settings.headless()?;
+    machine.set_profiler(Profiler::new());
+    let result = run_frames(&mut machine, value_t!(matches, "frames", u64)?);
+    warn_diagnostics(&machine);
+
+    let labels = match load_symbols(matches, rom_path, true)? {
+        Some(symbols) => symbols.labels,
+        None => BTreeMap::new(),
+    };
+    let profiler = machine.profiler().unwrap();
+    let report = profiler.report(&labels);
+    match matches.value_of_os("out") {
+        Some(path) => fs::write(path, report)?,
+        None => print!("{}", report),
+    }
+    if let Some(path) = matches.value_of_os("listing") {
+        let origin = settings.layout.load_address;
+        fs::write(path, profiler.annotate(&settings.program, origin, &labels))?;
+    }
+    // Whatever ran before an error is still worth reporting
+    result
+}
+
+fn info(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let (program, _) = read_rom(matches.value_of_os("ROM").unwrap())?;
+    println!("size: {} bytes", program.len());
+    println!("sha1: {}", database::sha1_hex(&program));
+
+    let database = Database::bundled();
+    let entry = match database.lookup(&program) {
+        Some(entry) => entry,
+        None => {
+            println!("not in the ROM database");
+            return Ok(());
+        }
+    };
+    println!("title: {}", entry.program.title);
+    if !entry.program.description.is_empty() {
+        println!("description: {}", entry.program.description);
+    }
+    if !entry.program.authors.is_empty() {
+        println!("authors: {}", entry.program.authors.join(", "));
+    }
+    if let Some(platform) = &entry.rom.platform {
+        println!("platform: {}", platform);
+    }
+    if let Some(quirks) = &entry.rom.quirks {
+        println!("quirks: {}", quirks);
+    }
+    if let Some(tickrate) = entry.rom.tickrate {
+        println!("speed: {} instructions per frame", tickrate);
+    }
+    let controls = entry.controls(&entry.key_map()?);
+    if !controls.is_empty() {
+        println!("controls:");
+        for line in controls {
+            println!("  {}", line);
+        }
+    }
+    Ok(())
+}
+
+fn disassemble(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let rom_path = Path::new(matches.value_of_os("ROM").unwrap());
+    let (program, _) = read_rom(rom_path)?;
+    let origin = parse_address(matches.value_of("origin").unwrap())?;
+    let mut labels = match load_symbols(matches, rom_path, true)? {
+        Some(symbols) => symbols.labels,
+        None => BTreeMap::new(),
+    };
+    let listing = if matches.is_present("analyze") {
+        let flow = Flow::analyze(&program, origin);
+        // Names from the symbol map win
+        for (name, address) in flow.labels() {
+            if !labels.values().any(|a| *a == address) {
+                labels.insert(name, address);
+            }
+        }
+        disasm::disassemble_with_flow(&program, origin, &labels, &flow)
+    } else {
+        disasm::disassemble_with_labels(&program, origin, &labels)
+    };
+    print!("{}", listing);
+    Ok(())
+}
+
+fn flow(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let (program, _) = read_rom(matches.value_of_os("ROM").unwrap())?;
+    let origin = parse_address(matches.value_of("origin").unwrap())?;
+    let flow = Flow::analyze(&program, origin);
+
+    println!(
+        "code: {} bytes in {} blocks",
+        flow.count(ByteKind::Code),
+        flow.blocks.len()
+    );
+    println!("data: {} bytes", flow.count(ByteKind::Data));
+    println!("unreachable: {} bytes", flow.count(ByteKind::Unreachable));
+    let addresses = |set: &BTreeSet<u16>| {
+        let list: Vec<String> = set.iter().map(|a| format!("{:03X}", a)).collect();
+        list.join(" ")
+    };
+    if !flow.subroutines.is_empty() {
+        println!("subroutines: {}", addresses(&flow.subroutines));
+    }
+    for store in flow.self_modifying.iter() {
+        println!(
+            "self-modifying: {:03X} writes {:03X}-{:03X}",
+            store.pc,
+            store.target,
+            store.target + store.length - 1
+        );
+    }
+    for range in flow.unreachable() {
+        println!("unreachable: {:03X}-{:03X}", range.start, range.end - 1);
+    }
+    if !flow.invalid.is_empty() {
+        println!("invalid instructions: {}", addresses(&flow.invalid));
+    }
+    if !flow.external.is_empty() {
+        println!("outside the program: {}", addresses(&flow.external));
+    }
+
+    if let Some(path) = matches.value_of_os("dot") {
+        fs::write(path, flow.to_dot())
+            .map_err(|e| format!("{}: {}", Path::new(path).display(), e))?;
+    }
+    Ok(())
+}
+
+// The map named by --symbols if `use_option`, otherwise one saved next to the
+// ROM. Octo source has its symbols compiled fresh.
+fn load_symbols(
+    matches: &ArgMatches,
+    rom_path: &Path,
+    use_option: bool,
+) -> Result<Option<SymbolMap>, Box<dyn Error>> {
+    if let (Some(path), true) = (matches.value_of_os("symbols"), use_option) {
+        return Ok(Some(SymbolMap::load(path)?));
+    }
+    let path = symbols::path_for(rom_path);
+    if path.is_file() {
+        return Ok(Some(SymbolMap::load(path)?));
+    }
+    if browser::is_octo_source(rom_path) {
+        let source = fs::read_to_string(rom_path)?;
+        let assembly = octo::compile(&source)?;
+        let mut symbols = SymbolMap::from_assembly(&assembly, Some(rom_path));
+        symbols.set_source_text(&source);
+        return Ok(Some(symbols));
+    }
+    Ok(None)
+}
+
+fn assemble(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let source_path = Path::new(matches.value_of_os("SOURCE").unwrap());
+    let source =
+        fs::read_to_string(source_path).map_err(|e| format!("{}: {}", source_path.display(), e))?;
+    let origin = parse_address(matches.value_of("origin").unwrap())?;
+    // Octo programs always start at 0x200
+    let assembly = if browser::is_octo_source(source_path) {
+        octo::compile(&source).map_err(|e| format!("{}:{}", source_path.display(), e))?
+    } else {
+        asm::assemble(&source, origin).map_err(|e| format!("{}:{}", source_path.display(), e))?
+    };
+
+    let out_path = match matches.value_of_os("out") {
+        Some(path) => Path::new(path).to_path_buf(),
+        None => source_path.with_extension("ch8"),
+    };
+    fs::write(&out_path, &assembly.bytes)?;
+    println!("{}: {} bytes", out_path.display(), assembly.bytes.len());
+
+    // The debugger finds the source through the map, so keep the path relative
+    // when they sit together
+    let source = if source_path.parent() == out_path.parent() {
+        PathBuf::from(source_path.file_name().unwrap())
+    } else {
+        source_path.canonicalize()?
+    };
+    let symbols_path = symbols::path_for(&out_path);
+    SymbolMap::from_assembly(&assembly, Some(&source)).save(&symbols_path)?;
+    println!(
+        "{}: {} labels",
+        symbols_path.display(),
+        assembly.labels.len()
+    );
+    Ok(())
+}
+
+// The file named by --config, or the one in the user config directory if there is one
+fn load_config(matches: &ArgMatches) -> Result<(Config, Option<PathBuf>), Box<dyn Error>> {
+    let path = match matches.value_of_os("config") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => config::default_path(),
+    };
+    let config = match &path {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    Ok((config, path))
+}
+
+fn trace_options(matches: &ArgMatches) -> Result<(TraceFormat, TraceFilter), Box<dyn Error>> {
+    let format = match matches.value_of("format") {
+        Some("json") => TraceFormat::Json,
+        _ => TraceFormat::Text,
+    };
+    let mut filter = TraceFilter::default();
+    if let Some(range) = matches.value_of("addr") {
+        filter.addresses = Some(parse_address_range(range)?);
+    }
+    if let Some(names) = matches.value_of("op") {
+        filter.opcodes = Some(names.split(',').map(String::from).collect());
+    }
+    if let Some(range) = matches.value_of("cycles") {
+        filter.cycles = Some(parse_range(range, 10)?);
+    }
+    Ok((format, filter))
+}
+
+// Accepts ROMs, Octo source and cartridges, and zip archives of them. Cartridges
+// come with the options they were saved with.
+fn read_rom<P: AsRef<Path>>(path: P) -> Result<(Vec<u8>, Option<OctoOptions>), String> {
+    let path = path.as_ref();
+    match loader::load(path, choose_rom) {
+        Ok(Loaded::Program(program)) => Ok((program, None)),
+        Ok(Loaded::Cartridge { program, options }) => Ok((program, Some(options))),
+        Err(e @ LoadError::Io { .. }) => Err(e.to_string()),
+        Err(e) => Err(format!("{}: {}", path.display(), e)),
+    }
+}
+
+// Asks on the terminal which ROM in an archive to load
+fn choose_rom(names: &[String]) -> Option<usize> {
+    eprintln!("The archive holds {} ROMs:", names.len());
+    for (i, name) in names.iter().enumerate() {
+        eprintln!("{:>4}  {}", i + 1, name);
+    }
+    eprint!("Load which one? ");
+    io::stderr().flush().ok()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer).ok()?;
+    match answer.trim().parse::<usize>() {
+        Ok(choice) if (1..=names.len()).contains(&choice) => Some(choice - 1),
+        _ => None,
+    }
+}
+
+fn file_exists(path: &std::ffi::OsStr) -> Result<(), std::ffi::OsString> {
+    if Path::new(path).is_file() {
+        Ok(())
+    } else {
+        Err(format!("{} is not a file", Path::new(path).display()).into())
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} is not a valid number", value))
+}
+
+fn parse_address(value: &str) -> Result<u16, String> {
+    u16::from_str_radix(value.trim_start_matches("0x"), 16)
+        .map_err(|_| format!("{} is not a valid hex address", value))
+}
+
+fn parse_palette(value: &str) -> Result<[u32; 2], String> {
+    let colors: Vec<Option<u32>> = value.split(',').map(database::parse_color).collect();
+    match colors.as_slice() {
+        [Some(background), Some(foreground)] => Ok([*background, *foreground]),
+        _ => Err(format!("{} is not two #RRGGBB colours", value)),
+    }
+}
+
+fn config_palette(colors: &[String]) -> Result<[u32; 2], String> {
+    parse_palette(&colors.join(","))
+}
+
+fn parse_binding(value: &str) -> Result<(String, u8), String> {
+    let invalid = || format!("{} is not HOST=KEY, like Space=5", value);
+    let mut parts = value.splitn(2, '=');
+    let host_key = parts.next().ok_or_else(invalid)?;
+    let key = parts
+        .next()
+        .and_then(|key| u8::from_str_radix(key, 16).ok())
+        .filter(|key| *key <= 0xF)
+        .ok_or_else(invalid)?;
+    // Check the host key name now so mistakes show up next to the option
+    KeyMap::default().bind(host_key, key)?;
+    Ok((host_key.to_string(), key))
+}
+
+// Within the 4K of CHIP-8 memory
+fn parse_address_range(value: &str) -> Result<RangeInclusive<u16>, String> {
+    let range = parse_range(value, 16)?;
+    if *range.end() > 0xFFF {
+        return Err(format!("{} goes past the end of memory at FFF", value));
+    }
+    Ok(*range.start() as u16..=*range.end() as u16)
+}
 
-    file.read_to_end(&mut file_buf).unwrap();
-    file_buf.into_boxed_slice()
+fn parse_range(value: &str, radix: u32) -> Result<RangeInclusive<u64>, String> {
+    let bounds = value
+        .splitn(2, '-')
+        .map(|bound| u64::from_str_radix(bound.trim_start_matches("0x"), radix))
+        .collect::<Result<Vec<u64>, _>>()
+        .map_err(|_| format!("{} is not a valid range", value))?;
+    match bounds.as_slice() {
+        [start, end] => Ok(*start..=*end),
+        _ => Err(format!("{} is not a valid range", value)),
+    }
 }
@@ -1,17 +1,114 @@
+use super::opcode::Opcode;
+
+use std::collections::VecDeque;
+
+const RECENT_WRITES: usize = 32;
+pub const PAGE_SIZE: usize = 64;
+pub const FONT_ADDRESS: u16 = 0x000;
+pub const FONT_SIZE: usize = 80;
+
+const FONT: [u8; FONT_SIZE] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x20, 0x60, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
 #[derive(Debug, Default)]
 pub struct Memory {
     ram: Box<[u8]>,
+    recent_writes: VecDeque<u16>,
+    // Decoded instructions by address, cleared for any address a write touches
+    decoded: Box<[Option<Opcode>]>,
+    decode_cache: bool,
+    // Bumped on every write to a page, so translated code can detect self-modification
+    page_generations: Box<[u32]>,
 }
 
 impl Memory {
     pub fn new() -> Self {
-        Memory {
+        let mut memory = Memory {
             ram: vec![0; 4 * 1024].into_boxed_slice(),
+            recent_writes: VecDeque::with_capacity(RECENT_WRITES),
+            decoded: vec![None; 4 * 1024].into_boxed_slice(),
+            decode_cache: true,
+            page_generations: vec![0; 4 * 1024 / PAGE_SIZE].into_boxed_slice(),
+        };
+        memory.write_chunk(FONT_ADDRESS, Box::new(FONT));
+
+        memory
+    }
+
+    pub fn page_generation(&self, address: u16) -> u32 {
+        self.page_generations[address as usize / PAGE_SIZE]
+    }
+
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.decode_cache = enabled;
+        for entry in self.decoded.iter_mut() {
+            *entry = None;
+        }
+    }
+
+    pub fn fetch_opcode(&mut self, address: u16) -> Opcode {
+        if !self.decode_cache {
+            return Opcode::decode(self.read_doublebyte(address));
+        }
+
+        match self.decoded[address as usize] {
+            Some(opcode) => opcode,
+            None => {
+                let opcode = Opcode::decode(self.read_doublebyte(address));
+                self.decoded[address as usize] = Some(opcode);
+                opcode
+            }
+        }
+    }
+
+    fn invalidate(&mut self, address: u16) {
+        let page = address as usize / PAGE_SIZE;
+        self.page_generations[page] = self.page_generations[page].wrapping_add(1);
+
+        if self.decode_cache {
+            self.decoded[address as usize] = None;
+            if address > 0 {
+                self.decoded[address as usize - 1] = None;
+            }
         }
     }
 
+    pub fn size(&self) -> usize {
+        self.ram.len()
+    }
+
+    pub fn contains(&self, address: u16, len: usize) -> bool {
+        address as usize + len <= self.ram.len()
+    }
+
     pub fn write_byte(&mut self, address: u16, value: u8) {
         self.ram[address as usize] = value;
+        self.invalidate(address);
+
+        if self.recent_writes.len() == RECENT_WRITES {
+            self.recent_writes.pop_front();
+        }
+        self.recent_writes.push_back(address);
+    }
+
+    pub fn was_recently_written(&self, address: u16) -> bool {
+        self.recent_writes.contains(&address)
     }
 
     pub fn read_byte(&self, address: u16) -> u8 {
@@ -25,12 +122,13 @@ impl Memory {
     pub fn write_chunk(&mut self, address: u16, chunk: Box<[u8]>) {
         let mut count = 0;
         for byte in chunk.iter() {
-            self.write_byte(address + count, *byte);
+            self.ram[(address + count) as usize] = *byte;
+            self.invalidate(address + count);
             count += 1;
         }
     }
 
-    pub fn read_chunk(&mut self, address: u16, size: usize) -> Vec<u8> {
+    pub fn read_chunk(&self, address: u16, size: usize) -> Vec<u8> {
         let mut chunk = vec![0; size];
         
         for i in 0..size {
@@ -41,3 +139,37 @@ impl Memory {
         chunk
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ADD V0, 1 rewritten to ADD V0, 3 after it has been fetched
+    fn rewrite_fetched_code(memory: &mut Memory) {
+        memory.write_chunk(0x200, Box::new([0x70, 0x01]));
+        assert_eq!(memory.fetch_opcode(0x200), Opcode::ADD_IMM { x: 0, byte: 1 });
+
+        let generation = memory.page_generation(0x200);
+        memory.write_byte(0x201, 0x03);
+        assert_eq!(memory.fetch_opcode(0x200), Opcode::ADD_IMM { x: 0, byte: 3 });
+        assert_eq!(memory.page_generation(0x200), generation + 1);
+        assert_eq!(memory.page_generation(0x200 + PAGE_SIZE as u16), 0);
+
+        // Writing the first byte clears the instruction starting there too
+        memory.write_byte(0x200, 0x60);
+        assert_eq!(memory.fetch_opcode(0x200), Opcode::LD_IMM { x: 0, byte: 3 });
+        assert_eq!(memory.page_generation(0x200), generation + 2);
+    }
+
+    #[test]
+    fn writes_invalidate_decoded_instructions() {
+        rewrite_fetched_code(&mut Memory::new());
+    }
+
+    #[test]
+    fn writes_bump_page_generations_without_the_cache() {
+        let mut memory = Memory::new();
+        memory.set_decode_cache(false);
+        rewrite_fetched_code(&mut memory);
+    }
+}
@@ -0,0 +1,1309 @@
+use super::asm::Assembly;
+use super::opcode::Opcode;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+// Compiles Octo source (https://github.com/JohnEarnest/Octo) into a ROM. Only
+// the CHIP-8 instruction set is covered, since that is what the emulator runs;
+// SCHIP and XO-CHIP statements are reported as errors.
+
+const START: usize = 0x200;
+const MEMORY_SIZE: usize = 0x1000;
+// Macros that expand into themselves would never finish
+const MAX_MACRO_DEPTH: usize = 64;
+
+const UNSUPPORTED: &[&str] = &[
+    "hires",
+    "lores",
+    "scroll-down",
+    "scroll-up",
+    "scroll-left",
+    "scroll-right",
+    "exit",
+    "saveflags",
+    "loadflags",
+    "plane",
+    "audio",
+    "pitch",
+    ":string",
+    ":stringmode",
+];
+
+const KEYWORDS: &[&str] = &[
+    ":",
+    ":next",
+    ":const",
+    ":alias",
+    ":unpack",
+    ":org",
+    ":byte",
+    ":call",
+    ":macro",
+    ":calc",
+    ":proto",
+    ":breakpoint",
+    ":monitor",
+    ":=",
+    "+=",
+    "-=",
+    "=-",
+    "|=",
+    "&=",
+    "^=",
+    ">>=",
+    "<<=",
+    "==",
+    "!=",
+    "<",
+    ">",
+    "<=",
+    ">=",
+    "key",
+    "-key",
+    "return",
+    ";",
+    "clear",
+    "bcd",
+    "save",
+    "load",
+    "sprite",
+    "jump",
+    "jump0",
+    "native",
+    "delay",
+    "buzzer",
+    "random",
+    "if",
+    "then",
+    "begin",
+    "else",
+    "end",
+    "loop",
+    "while",
+    "again",
+    "i",
+    "hex",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OctoError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for OctoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+impl std::error::Error for OctoError {}
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+    // How many macro expansions produced this token
+    depth: usize,
+}
+
+impl Token {
+    fn error(&self, message: String) -> OctoError {
+        OctoError {
+            line: self.line,
+            column: self.column,
+            message: message,
+        }
+    }
+}
+
+// Words are separated by whitespace, and `#` comments run to the end of the line
+fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        let mut start = None;
+        let line = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        for (column, c) in line
+            .char_indices()
+            .chain(std::iter::once((line.len(), ' ')))
+        {
+            match (c.is_whitespace(), start) {
+                (false, None) => start = Some(column),
+                (true, Some(from)) => {
+                    tokens.push(Token {
+                        text: line[from..column].to_string(),
+                        line: i + 1,
+                        column: line[..from].chars().count() + 1,
+                        depth: 0,
+                    });
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+    }
+    tokens
+}
+
+fn number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    if !digits.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()? as f64
+    } else {
+        digits.parse().ok()?
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn register(text: &str) -> Option<u8> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('v'), Some(digit), None) | (Some('V'), Some(digit), None) => {
+            digit.to_digit(16).map(|x| x as u8)
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Rhs {
+    Register(u8),
+    Byte(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Test {
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessOrEqual,
+    GreaterOrEqual,
+    Key,
+    NotKey,
+}
+
+impl Test {
+    fn negate(self) -> Self {
+        match self {
+            Test::Equal => Test::NotEqual,
+            Test::NotEqual => Test::Equal,
+            Test::Less => Test::GreaterOrEqual,
+            Test::GreaterOrEqual => Test::Less,
+            Test::Greater => Test::LessOrEqual,
+            Test::LessOrEqual => Test::Greater,
+            Test::Key => Test::NotKey,
+            Test::NotKey => Test::Key,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Condition {
+    x: u8,
+    test: Test,
+    rhs: Rhs,
+}
+
+impl Condition {
+    fn negate(self) -> Self {
+        Condition {
+            test: self.test.negate(),
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum PatchKind {
+    // The low 12 bits of a jump, call or i := instruction
+    Address,
+    // The immediate bytes of :unpack's two loads
+    UnpackHigh(u8),
+    UnpackLow,
+}
+
+struct Patch {
+    address: usize,
+    kind: PatchKind,
+    name: Token,
+}
+
+enum Block {
+    Loop { start: u16, exits: Vec<usize> },
+    // The jump past the taken branch, patched by else or end
+    If { jump: usize, has_else: bool },
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+}
+
+struct Compiler {
+    // Reversed, so the next token is at the end
+    tokens: Vec<Token>,
+    // For errors at the end of the source
+    last: Token,
+    rom: Vec<u8>,
+    written: Vec<bool>,
+    here: usize,
+    end: usize,
+    labels: BTreeMap<String, u16>,
+    lines: BTreeMap<u16, usize>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    patches: Vec<Patch>,
+    blocks: Vec<Block>,
+    // Whether 0x200 may still turn out to be main, making the jump to it unnecessary
+    main_pending: bool,
+}
+
+pub fn compile(source: &str) -> Result<Assembly, OctoError> {
+    let mut tokens = tokenize(source);
+    tokens.reverse();
+    let mut compiler = Compiler {
+        tokens: tokens,
+        last: Token {
+            text: String::new(),
+            line: 1,
+            column: 1,
+            depth: 0,
+        },
+        rom: vec![0; MEMORY_SIZE],
+        written: vec![false; MEMORY_SIZE],
+        here: START,
+        end: START,
+        labels: BTreeMap::new(),
+        lines: BTreeMap::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::new(),
+        macros: HashMap::new(),
+        patches: Vec::new(),
+        blocks: Vec::new(),
+        main_pending: true,
+    };
+    compiler.program()?;
+
+    let end = compiler.end;
+    compiler.rom.truncate(end);
+    Ok(Assembly {
+        bytes: compiler.rom.split_off(START),
+        labels: compiler.labels,
+        lines: compiler.lines,
+    })
+}
+
+impl Compiler {
+    fn program(&mut self) -> Result<(), OctoError> {
+        while let Some(token) = self.next() {
+            self.statement(token)?;
+        }
+        if self.main_pending {
+            let end = self.here;
+            self.here = START;
+            self.claim_start()?;
+            self.here = end;
+        }
+
+        if let Some(block) = self.blocks.last() {
+            let open = match block {
+                Block::Loop { .. } => "loop without again",
+                Block::If { .. } => "begin without end",
+            };
+            return Err(self.last.error(format!("Unclosed block: {}", open)));
+        }
+        if !self.labels.contains_key("main") {
+            return Err(self
+                .last
+                .error("This program is missing a 'main' label".to_string()));
+        }
+        for patch in std::mem::take(&mut self.patches) {
+            let target = match self.labels.get(&patch.name.text) {
+                Some(target) => *target,
+                None => {
+                    return Err(patch
+                        .name
+                        .error(format!("Undefined name: {}", patch.name.text)))
+                }
+            };
+            self.apply(&patch, target);
+        }
+        Ok(())
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.pop()?;
+        self.last = token.clone();
+        Some(token)
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.last().map(|token| token.text.as_str())
+    }
+
+    fn expect_token(&mut self) -> Result<Token, OctoError> {
+        match self.next() {
+            Some(token) => Ok(token),
+            None => Err(self.last.error("Unexpected end of source".to_string())),
+        }
+    }
+
+    fn expect(&mut self, text: &str) -> Result<Token, OctoError> {
+        let token = self.expect_token()?;
+        if token.text != text {
+            return Err(token.error(format!("Expected '{}', found '{}'", text, token.text)));
+        }
+        Ok(token)
+    }
+
+    fn name(&mut self) -> Result<Token, OctoError> {
+        let token = self.expect_token()?;
+        if number(&token.text).is_some()
+            || register(&token.text).is_some()
+            || KEYWORDS.contains(&token.text.as_str())
+            || token.text.starts_with(|c| "{}()".contains(c))
+        {
+            return Err(token.error(format!("'{}' can't be used as a name", token.text)));
+        }
+        Ok(token)
+    }
+
+    fn register(&mut self) -> Result<u8, OctoError> {
+        let token = self.expect_token()?;
+        self.register_of(&token)
+            .ok_or_else(|| token.error(format!("Expected a register, found '{}'", token.text)))
+    }
+
+    fn register_of(&self, token: &Token) -> Option<u8> {
+        self.aliases
+            .get(&token.text)
+            .copied()
+            .or_else(|| register(&token.text))
+    }
+
+    // A number, constant, known label or { calculation }
+    fn value_of(&mut self, token: &Token) -> Result<f64, OctoError> {
+        if token.text == "{" {
+            let value = self.calc()?;
+            self.expect("}")?;
+            return Ok(value);
+        }
+        self.known_value(&token.text)
+            .ok_or_else(|| token.error(format!("Undefined name: {}", token.text)))
+    }
+
+    fn known_value(&self, text: &str) -> Option<f64> {
+        number(text)
+            .or_else(|| self.constants.get(text).copied())
+            .or_else(|| self.labels.get(text).map(|address| *address as f64))
+    }
+
+    fn value(&mut self) -> Result<(f64, Token), OctoError> {
+        let token = self.expect_token()?;
+        Ok((self.value_of(&token)?, token))
+    }
+
+    // Bytes may be written signed, so -1 is 0xFF
+    fn byte(&mut self) -> Result<u8, OctoError> {
+        let (value, token) = self.value()?;
+        let value = value as i64;
+        if !(-128..=255).contains(&value) {
+            return Err(token.error(format!("Value out of range for a byte: {}", value)));
+        }
+        Ok(value as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u8, OctoError> {
+        let (value, token) = self.value()?;
+        let value = value as i64;
+        if !(0..=15).contains(&value) {
+            return Err(token.error(format!("Value out of range for a nibble: {}", value)));
+        }
+        Ok(value as u8)
+    }
+
+    // Execution starts at 0x200, so anything else landing there is preceded by a
+    // jump to main
+    fn claim_start(&mut self) -> Result<(), OctoError> {
+        if self.main_pending && self.here == START {
+            self.main_pending = false;
+            let main = Token {
+                text: "main".to_string(),
+                ..self.last.clone()
+            };
+            self.emit_address(Opcode::JP { addr: 0 }, &main)?;
+        }
+        Ok(())
+    }
+
+    fn emit_byte(&mut self, byte: u8) -> Result<(), OctoError> {
+        self.claim_start()?;
+        if self.here >= MEMORY_SIZE {
+            return Err(self
+                .last
+                .error("Program does not fit in memory".to_string()));
+        }
+        if self.written[self.here] {
+            return Err(self
+                .last
+                .error(format!("Data overlap at {:#05X}", self.here)));
+        }
+        self.rom[self.here] = byte;
+        self.written[self.here] = true;
+        self.lines.insert(self.here as u16, self.last.line);
+        self.here += 1;
+        self.end = self.end.max(self.here);
+        Ok(())
+    }
+
+    fn emit(&mut self, opcode: Opcode) -> Result<(), OctoError> {
+        let [high, low] = opcode.encode().to_be_bytes();
+        self.emit_byte(high)?;
+        self.emit_byte(low)
+    }
+
+    // Emits `opcode` with the address of `target`, which may be a label defined later
+    fn emit_address(&mut self, opcode: Opcode, target: &Token) -> Result<(), OctoError> {
+        let address = self.here;
+        self.emit(opcode)?;
+        let is_name = target.text != "{" && number(&target.text).is_none();
+        match self.labels.get(&target.text) {
+            None if is_name && !self.constants.contains_key(&target.text) => {
+                self.patches.push(Patch {
+                    address: address,
+                    kind: PatchKind::Address,
+                    name: target.clone(),
+                });
+                Ok(())
+            }
+            _ => {
+                let value = self.value_of(target)? as i64;
+                if !(0..=0xFFF).contains(&value) {
+                    return Err(target.error(format!("Address out of range: {:#X}", value)));
+                }
+                self.apply_address(address, value as u16);
+                Ok(())
+            }
+        }
+    }
+
+    fn apply_address(&mut self, address: usize, target: u16) {
+        self.rom[address] = (self.rom[address] & 0xF0) | (target >> 8) as u8;
+        self.rom[address + 1] = target as u8;
+    }
+
+    // A jump to be pointed somewhere later on
+    fn emit_placeholder(&mut self) -> Result<usize, OctoError> {
+        let address = self.here;
+        self.emit(Opcode::JP { addr: 0 })?;
+        Ok(address)
+    }
+
+    fn apply(&mut self, patch: &Patch, target: u16) {
+        match patch.kind {
+            PatchKind::Address => self.apply_address(patch.address, target),
+            PatchKind::UnpackHigh(nibble) => {
+                self.rom[patch.address + 1] = (nibble << 4) | (target >> 8) as u8
+            }
+            PatchKind::UnpackLow => self.rom[patch.address + 1] = target as u8,
+        }
+    }
+
+    fn define_label(&mut self, name: &Token, address: usize) -> Result<(), OctoError> {
+        if name.text == "main" && address == START {
+            self.main_pending = false;
+        }
+        self.claim_start()?;
+        if self.labels.contains_key(&name.text) || self.constants.contains_key(&name.text) {
+            return Err(name.error(format!("The name '{}' is already defined", name.text)));
+        }
+        self.labels.insert(name.text.clone(), address as u16);
+        Ok(())
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), OctoError> {
+        if UNSUPPORTED.contains(&token.text.as_str()) {
+            return Err(token.error(format!(
+                "'{}' is SCHIP or XO-CHIP, which the emulator does not run",
+                token.text
+            )));
+        }
+        if let Some(x) = self.register_of(&token) {
+            return self.assignment(x);
+        }
+
+        match token.text.as_str() {
+            ":" => {
+                let name = self.name()?;
+                self.define_label(&name, self.here)?;
+            }
+            ":next" => {
+                let name = self.name()?;
+                self.define_label(&name, self.here + 1)?;
+            }
+            ":const" => {
+                let name = self.name()?;
+                let (value, _) = self.value()?;
+                self.constants.insert(name.text, value);
+            }
+            ":calc" => {
+                let name = self.name()?;
+                self.expect("{")?;
+                let value = self.calc()?;
+                self.expect("}")?;
+                self.constants.insert(name.text, value);
+            }
+            ":alias" => {
+                let name = self.name()?;
+                let x = self.register()?;
+                self.aliases.insert(name.text, x);
+            }
+            ":unpack" => {
+                if self.peek() == Some("long") {
+                    let long = self.expect_token()?;
+                    return Err(long.error(
+                        "':unpack long' is XO-CHIP, which the emulator does not run".to_string(),
+                    ));
+                }
+                let nibble = self.nibble()?;
+                let target = self.expect_token()?;
+                let (high, low) = (self.here, self.here + 2);
+                self.emit(Opcode::LD_IMM { x: 0, byte: 0 })?;
+                self.emit(Opcode::LD_IMM { x: 1, byte: 0 })?;
+                match self.labels.get(&target.text) {
+                    Some(address) => {
+                        let address = *address;
+                        self.rom[high + 1] = (nibble << 4) | (address >> 8) as u8;
+                        self.rom[low + 1] = address as u8;
+                    }
+                    None => {
+                        self.patches.push(Patch {
+                            address: high,
+                            kind: PatchKind::UnpackHigh(nibble),
+                            name: target.clone(),
+                        });
+                        self.patches.push(Patch {
+                            address: low,
+                            kind: PatchKind::UnpackLow,
+                            name: target,
+                        });
+                    }
+                }
+            }
+            ":org" => {
+                let (value, token) = self.value()?;
+                let address = value as i64;
+                if !(START as i64..MEMORY_SIZE as i64).contains(&address) {
+                    return Err(token.error(format!("Address out of range: {:#X}", address)));
+                }
+                self.here = address as usize;
+            }
+            ":byte" => {
+                let byte = self.byte()?;
+                self.emit_byte(byte)?;
+            }
+            ":call" => {
+                let target = self.expect_token()?;
+                self.emit_address(Opcode::CALL { addr: 0 }, &target)?;
+            }
+            ":macro" => self.define_macro()?,
+            // Only for the debugger in Octo itself
+            ":proto" | ":breakpoint" => {
+                self.expect_token()?;
+            }
+            ":monitor" => {
+                self.expect_token()?;
+                self.expect_token()?;
+            }
+            "return" | ";" => self.emit(Opcode::RET)?,
+            "clear" => self.emit(Opcode::CLS)?,
+            "bcd" => {
+                let x = self.register()?;
+                self.emit(Opcode::LD_B { x: x })?;
+            }
+            "save" | "load" => {
+                let x = self.register()?;
+                if self.peek() == Some("-") {
+                    return Err(token.error(format!(
+                        "'{} vx - vy' is XO-CHIP, which the emulator does not run",
+                        token.text
+                    )));
+                }
+                self.emit(match token.text.as_str() {
+                    "save" => Opcode::ST_M { x: x },
+                    _ => Opcode::LD_M { x: x },
+                })?;
+            }
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let size = self.nibble()?;
+                self.emit(Opcode::DRW {
+                    x: x,
+                    y: y,
+                    size: size,
+                })?;
+            }
+            "jump" => {
+                let target = self.expect_token()?;
+                self.emit_address(Opcode::JP { addr: 0 }, &target)?;
+            }
+            "jump0" => {
+                let target = self.expect_token()?;
+                self.emit_address(Opcode::JP_V0 { addr: 0 }, &target)?;
+            }
+            "native" => {
+                let target = self.expect_token()?;
+                self.emit_address(Opcode::SYS { addr: 0 }, &target)?;
+            }
+            "delay" | "buzzer" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                self.emit(match token.text.as_str() {
+                    "delay" => Opcode::SET_DT { x: x },
+                    _ => Opcode::SET_ST { x: x },
+                })?;
+            }
+            "i" => self.assign_i()?,
+            "if" => self.branch()?,
+            "else" => {
+                let jump = self.emit_placeholder()?;
+                match self.blocks.last_mut() {
+                    Some(Block::If {
+                        jump: previous,
+                        has_else: has_else @ false,
+                    }) => {
+                        let previous = std::mem::replace(previous, jump);
+                        *has_else = true;
+                        self.apply_address(previous, self.here as u16);
+                    }
+                    _ => return Err(token.error("else without begin".to_string())),
+                }
+            }
+            "end" => match self.blocks.pop() {
+                Some(Block::If { jump, .. }) => self.apply_address(jump, self.here as u16),
+                _ => return Err(token.error("end without begin".to_string())),
+            },
+            "loop" => self.blocks.push(Block::Loop {
+                start: self.here as u16,
+                exits: Vec::new(),
+            }),
+            "while" => {
+                let condition = self.condition()?;
+                self.skip_unless(condition.negate())?;
+                let exit = self.emit_placeholder()?;
+                let innermost = self.blocks.iter_mut().rev().find_map(|block| match block {
+                    Block::Loop { exits, .. } => Some(exits),
+                    _ => None,
+                });
+                match innermost {
+                    Some(exits) => exits.push(exit),
+                    None => return Err(token.error("while outside a loop".to_string())),
+                }
+            }
+            "again" => match self.blocks.pop() {
+                Some(Block::Loop { start, exits }) => {
+                    self.emit(Opcode::JP { addr: start })?;
+                    for exit in exits {
+                        self.apply_address(exit, self.here as u16);
+                    }
+                }
+                _ => return Err(token.error("again without loop".to_string())),
+            },
+            _ if self.macros.contains_key(&token.text) => self.expand(&token)?,
+            // Numbers and constants on their own are data
+            text if text == "{" || number(text).is_some() || self.constants.contains_key(text) => {
+                let value = self.value_of(&token)? as i64;
+                if !(-128..=255).contains(&value) {
+                    return Err(token.error(format!("Value out of range for a byte: {}", value)));
+                }
+                self.emit_byte(value as u8)?;
+            }
+            text if KEYWORDS.contains(&text) || text.starts_with(|c| "{}()".contains(c)) => {
+                return Err(token.error(format!("Unexpected '{}'", text)))
+            }
+            // Anything else names a subroutine
+            _ => self.emit_address(Opcode::CALL { addr: 0 }, &token)?,
+        }
+        Ok(())
+    }
+
+    fn assignment(&mut self, x: u8) -> Result<(), OctoError> {
+        let operator = self.expect_token()?;
+        let operand = self.expect_token()?;
+        let y = self.register_of(&operand);
+
+        let opcode = match (operator.text.as_str(), y) {
+            (":=", Some(y)) => Opcode::LD_R { x: x, y: y },
+            (":=", None) => match operand.text.as_str() {
+                "key" => Opcode::LD_R_K { x: x },
+                "delay" => Opcode::LD_DT { x: x },
+                "random" => Opcode::RND {
+                    x: x,
+                    byte: self.byte()?,
+                },
+                _ => Opcode::LD_IMM {
+                    x: x,
+                    byte: self.operand_byte(&operand)?,
+                },
+            },
+            ("+=", Some(y)) => Opcode::ADD_R { x: x, y: y },
+            ("+=", None) => Opcode::ADD_IMM {
+                x: x,
+                byte: self.operand_byte(&operand)?,
+            },
+            ("-=", Some(y)) => Opcode::SUB_R { x: x, y: y },
+            ("-=", None) => Opcode::ADD_IMM {
+                x: x,
+                byte: self.operand_byte(&operand)?.wrapping_neg(),
+            },
+            ("=-", Some(y)) => Opcode::SUBN_R { x: x, y: y },
+            ("|=", Some(y)) => Opcode::OR_R { x: x, y: y },
+            ("&=", Some(y)) => Opcode::AND { x: x, y: y },
+            ("^=", Some(y)) => Opcode::XOR_R { x: x, y: y },
+            (">>=", Some(y)) => Opcode::SHR { x: x, y: y },
+            ("<<=", Some(y)) => Opcode::SHL { x: x, y: y },
+            _ => {
+                return Err(operator.error(format!(
+                    "Can't use '{}' with '{}'",
+                    operator.text, operand.text
+                )))
+            }
+        };
+        self.emit(opcode)
+    }
+
+    fn operand_byte(&mut self, token: &Token) -> Result<u8, OctoError> {
+        let value = self.value_of(token)? as i64;
+        if !(-128..=255).contains(&value) {
+            return Err(token.error(format!("Value out of range for a byte: {}", value)));
+        }
+        Ok(value as u8)
+    }
+
+    fn assign_i(&mut self) -> Result<(), OctoError> {
+        let operator = self.expect_token()?;
+        match operator.text.as_str() {
+            ":=" => {
+                let target = self.expect_token()?;
+                match target.text.as_str() {
+                    "hex" => {
+                        let x = self.register()?;
+                        self.emit(Opcode::LD_F { x: x })
+                    }
+                    "bighex" | "long" => Err(target.error(format!(
+                        "'i := {}' is SCHIP or XO-CHIP, which the emulator does not run",
+                        target.text
+                    ))),
+                    _ => self.emit_address(Opcode::LDI_IMM { addr: 0 }, &target),
+                }
+            }
+            "+=" => {
+                let x = self.register()?;
+                self.emit(Opcode::ADDI_R { x: x })
+            }
+            _ => Err(operator.error(format!("Can't use '{}' with i", operator.text))),
+        }
+    }
+
+    fn condition(&mut self) -> Result<Condition, OctoError> {
+        let x = self.register()?;
+        let operator = self.expect_token()?;
+        let test = match operator.text.as_str() {
+            "==" => Test::Equal,
+            "!=" => Test::NotEqual,
+            "<" => Test::Less,
+            ">" => Test::Greater,
+            "<=" => Test::LessOrEqual,
+            ">=" => Test::GreaterOrEqual,
+            "key" => Test::Key,
+            "-key" => Test::NotKey,
+            _ => return Err(operator.error(format!("Unknown comparison: {}", operator.text))),
+        };
+        let rhs = match test {
+            Test::Key | Test::NotKey => Rhs::Register(x),
+            _ => {
+                let operand = self.expect_token()?;
+                match self.register_of(&operand) {
+                    Some(y) => Rhs::Register(y),
+                    None => Rhs::Byte(self.operand_byte(&operand)?),
+                }
+            }
+        };
+        Ok(Condition {
+            x: x,
+            test: test,
+            rhs: rhs,
+        })
+    }
+
+    // Emits code that skips the next instruction when `condition` is false.
+    // Ordering tests go through VF: after vf := b, `vf =- a` leaves VF set when
+    // a >= b and `vf -= a` leaves it set when b >= a.
+    fn skip_unless(&mut self, condition: Condition) -> Result<(), OctoError> {
+        let x = condition.x;
+        let load_vf = match condition.rhs {
+            Rhs::Register(y) => Opcode::LD_R { x: 0xF, y: y },
+            Rhs::Byte(byte) => Opcode::LD_IMM { x: 0xF, byte: byte },
+        };
+        let (subtract, skip_when_set) = match condition.test {
+            Test::Equal | Test::NotEqual => {
+                let skip_if_equal = condition.test == Test::NotEqual;
+                return self.emit(match (condition.rhs, skip_if_equal) {
+                    (Rhs::Register(y), true) => Opcode::SE_R { x: x, y: y },
+                    (Rhs::Register(y), false) => Opcode::SNE_R { x: x, y: y },
+                    (Rhs::Byte(byte), true) => Opcode::SE { x: x, byte: byte },
+                    (Rhs::Byte(byte), false) => Opcode::SNE { x: x, byte: byte },
+                });
+            }
+            Test::Key => return self.emit(Opcode::SKNP { x: x }),
+            Test::NotKey => return self.emit(Opcode::SKP { x: x }),
+            Test::Less => (Opcode::SUBN_R { x: 0xF, y: x }, true),
+            Test::GreaterOrEqual => (Opcode::SUBN_R { x: 0xF, y: x }, false),
+            Test::Greater => (Opcode::SUB_R { x: 0xF, y: x }, true),
+            Test::LessOrEqual => (Opcode::SUB_R { x: 0xF, y: x }, false),
+        };
+        self.emit(load_vf)?;
+        self.emit(subtract)?;
+        self.emit(if skip_when_set {
+            Opcode::SNE { x: 0xF, byte: 0 }
+        } else {
+            Opcode::SE { x: 0xF, byte: 0 }
+        })
+    }
+
+    fn branch(&mut self) -> Result<(), OctoError> {
+        let condition = self.condition()?;
+        let keyword = self.expect_token()?;
+        match keyword.text.as_str() {
+            // The next statement is the one skipped
+            "then" => self.skip_unless(condition),
+            "begin" => {
+                self.skip_unless(condition.negate())?;
+                let jump = self.emit_placeholder()?;
+                self.blocks.push(Block::If {
+                    jump: jump,
+                    has_else: false,
+                });
+                Ok(())
+            }
+            _ => Err(keyword.error(format!(
+                "Expected 'then' or 'begin', found '{}'",
+                keyword.text
+            ))),
+        }
+    }
+
+    fn define_macro(&mut self) -> Result<(), OctoError> {
+        let name = self.name()?;
+        let mut params = Vec::new();
+        loop {
+            let token = self.expect_token()?;
+            if token.text == "{" {
+                break;
+            }
+            params.push(token.text);
+        }
+
+        let mut body = Vec::new();
+        let mut depth = 1;
+        loop {
+            let token = self.expect_token()?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                break;
+            }
+            body.push(token);
+        }
+        self.macros.insert(
+            name.text,
+            Macro {
+                params: params,
+                body: body,
+            },
+        );
+        Ok(())
+    }
+
+    // Replaces the call with the macro body, arguments substituted word for word
+    fn expand(&mut self, call: &Token) -> Result<(), OctoError> {
+        if call.depth >= MAX_MACRO_DEPTH {
+            return Err(call.error(format!("Macro {} expands too deeply", call.text)));
+        }
+        let count = self.macros[&call.text].params.len();
+        let mut args = HashMap::new();
+        for i in 0..count {
+            let arg = self.expect_token()?;
+            args.insert(self.macros[&call.text].params[i].clone(), arg.text);
+        }
+
+        let expansion: Vec<Token> = self.macros[&call.text]
+            .body
+            .iter()
+            .map(|token| Token {
+                text: args.get(&token.text).unwrap_or(&token.text).clone(),
+                depth: call.depth + 1,
+                ..token.clone()
+            })
+            .collect();
+        self.tokens.extend(expansion.into_iter().rev());
+        Ok(())
+    }
+
+    // Octo arithmetic has no precedence: binary operators group to the right,
+    // so 2 * 3 + 4 is 14. Parentheses group as usual.
+    fn calc(&mut self) -> Result<f64, OctoError> {
+        let left = self.calc_term()?;
+        match self.peek() {
+            None | Some("}") | Some(")") => Ok(left),
+            Some(_) => {
+                let operator = self.expect_token()?;
+                let right = self.calc()?;
+                let (a, b) = (left as i64, right as i64);
+                Ok(match operator.text.as_str() {
+                    "+" => left + right,
+                    "-" => left - right,
+                    "*" => left * right,
+                    "/" => left / right,
+                    "%" => left % right,
+                    "pow" => left.powf(right),
+                    "min" => left.min(right),
+                    "max" => left.max(right),
+                    "&" => (a & b) as f64,
+                    "|" => (a | b) as f64,
+                    "^" => (a ^ b) as f64,
+                    "<<" => (a << (b & 63)) as f64,
+                    ">>" => (a >> (b & 63)) as f64,
+                    "<" => (left < right) as i64 as f64,
+                    ">" => (left > right) as i64 as f64,
+                    "<=" => (left <= right) as i64 as f64,
+                    ">=" => (left >= right) as i64 as f64,
+                    "==" => (left == right) as i64 as f64,
+                    "!=" => (left != right) as i64 as f64,
+                    _ => return Err(operator.error(format!("Unknown operator: {}", operator.text))),
+                })
+            }
+        }
+    }
+
+    fn calc_term(&mut self) -> Result<f64, OctoError> {
+        let token = self.expect_token()?;
+        let unary: Option<fn(f64) -> f64> = match token.text.as_str() {
+            "-" => Some(|value| -value),
+            "~" => Some(|value| !(value as i64) as f64),
+            "!" => Some(|value| (value == 0.0) as i64 as f64),
+            "sin" => Some(f64::sin),
+            "cos" => Some(f64::cos),
+            "tan" => Some(f64::tan),
+            "exp" => Some(f64::exp),
+            "log" => Some(f64::ln),
+            "abs" => Some(f64::abs),
+            "sqrt" => Some(f64::sqrt),
+            "sign" => Some(f64::signum),
+            "ceil" => Some(f64::ceil),
+            "floor" => Some(f64::floor),
+            _ => None,
+        };
+        if let Some(function) = unary {
+            return Ok(function(self.calc_term()?));
+        }
+
+        match token.text.as_str() {
+            "(" => {
+                let value = self.calc()?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            "HERE" => Ok(self.here as f64),
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            text => self
+                .known_value(text)
+                .ok_or_else(|| token.error(format!("Undefined name: {}", text))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::headless::Headless;
+    use crate::rom::Layout;
+
+    fn assembled(source: &str) -> Vec<u8> {
+        asm::assemble(source, 0x200).unwrap().bytes
+    }
+
+    // Runs until the program settles into `: halt jump halt`
+    fn registers(source: &str) -> [u8; 16] {
+        let program = compile(source).unwrap();
+        let halt = program.labels["halt"];
+        let mut machine = Headless::new(&program.bytes, Layout::default(), 0).unwrap();
+        for _ in 0..1000 {
+            if machine.cpu.state().reg_pc == halt {
+                return machine.cpu.state().reg_gp;
+            }
+            machine.step().unwrap();
+        }
+        panic!("never reached halt");
+    }
+
+    #[test]
+    fn compiles_instructions() {
+        let program = compile(
+            "
+            : main
+                clear
+                v0 := 5          # counter
+                v1 := v0
+                v2 := random 0x0F
+                v3 := key
+                v4 := delay
+                delay := v4
+                buzzer := v4
+                v0 += 1
+                v0 -= 1
+                v0 += v1
+                v0 -= v1
+                v0 =- v1
+                v0 |= v1
+                v0 &= v1
+                v0 ^= v1
+                v0 >>= v1
+                v0 <<= v1
+                i := shape
+                i := hex v0
+                i += v1
+                bcd v0
+                save v2
+                load v2
+                sprite v0 v1 5
+                jump0 shape
+                native 0x123
+                sub
+                return
+            : sub ;
+            : shape 0xF0 -1
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            program.bytes,
+            assembled(
+                "
+                CLS
+                LD V0, 5
+                LD V1, V0
+                RND V2, 0x0F
+                LD V3, K
+                LD V4, DT
+                LD DT, V4
+                LD ST, V4
+                ADD V0, 1
+                ADD V0, 0xFF
+                ADD V0, V1
+                SUB V0, V1
+                SUBN V0, V1
+                OR V0, V1
+                AND V0, V1
+                XOR V0, V1
+                SHR V0, V1
+                SHL V0, V1
+                LD I, 0x23C
+                LD F, V0
+                ADD I, V1
+                LD B, V0
+                LD [I], V2
+                LD V2, [I]
+                DRW V0, V1, 5
+                JP V0, 0x23C
+                SYS 0x123
+                CALL 0x23A
+                RET
+                RET
+                db 0xF0, 0xFF
+                "
+            )
+        );
+        assert_eq!(program.labels["shape"], 0x23C);
+    }
+
+    #[test]
+    fn jumps_to_main_unless_it_comes_first() {
+        let program = compile(": data 1 2\n: main jump main").unwrap();
+        assert_eq!(program.bytes, vec![0x12, 0x04, 1, 2, 0x12, 0x04]);
+
+        let error = compile(": start jump start").unwrap_err();
+        assert_eq!(error.message, "This program is missing a 'main' label");
+    }
+
+    #[test]
+    fn aliases_constants_calc_and_org() {
+        let program = compile(
+            "
+            :alias counter v3
+            :const SPEED 4
+            :calc DOUBLE { SPEED * 2 }
+            :calc MIXED { 2 * 3 + 4 }
+            :calc GROUPED { ( 2 * 3 ) + 4 }
+            : main
+                counter := SPEED
+                counter += DOUBLE
+                :byte MIXED
+                :byte { GROUPED - 1 }
+            :org 0x300
+            : far
+                :byte { HERE >> 8 }
+                SPEED
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(&program.bytes[..6], &[0x63, 0x04, 0x73, 0x08, 14, 9]);
+        assert_eq!(program.labels["far"], 0x300);
+        assert_eq!(&program.bytes[0x100..], &[0x03, 0x04]);
+        assert!(program.bytes[6..0x100].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn expands_macros() {
+        let program = compile(
+            "
+            :macro set-both a b value {
+                a := value
+                b := value
+            }
+            :macro twice body { body body }
+            : main
+                set-both v1 v2 7
+                twice clear
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            program.bytes,
+            vec![0x61, 0x07, 0x62, 0x07, 0x00, 0xE0, 0x00, 0xE0]
+        );
+
+        let error = compile(":macro forever { forever }\n: main forever").unwrap_err();
+        assert_eq!(error.message, "Macro forever expands too deeply");
+    }
+
+    #[test]
+    fn forward_references_unpack_and_next() {
+        let program = compile(
+            "
+            : main
+                :unpack 0xA data
+                i := data
+                jump done
+            : done
+                :next target
+                v5 := 0
+                :call target
+            : data 0xAA
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            program.bytes,
+            assembled(
+                "
+                LD V0, 0xA2
+                LD V1, 0x0C
+                LD I, 0x20C
+                JP 0x208
+                LD V5, 0
+                CALL 0x209
+                db 0xAA
+                "
+            )
+        );
+        assert_eq!(program.lines[&0x202], 3);
+        assert_eq!(program.lines[&0x208], 8);
+
+        let error = compile(": main\n  jump nowhere").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 8));
+        assert_eq!(error.message, "Undefined name: nowhere");
+    }
+
+    #[test]
+    fn conditionals_and_loops_run_correctly() {
+        let registers = registers(
+            "
+            : main
+                v0 := 0
+                loop
+                    v0 += 1
+                    while v0 != 10
+                again
+
+                v1 := 0
+                if v0 == 10 then v1 := 1
+                if v0 < 11 then v1 += 2
+                if v0 > 11 then v1 += 4
+                if v0 <= 10 then v1 += 8
+                if v0 >= v0 then v1 += 16
+
+                if v0 key begin
+                    v2 := 1
+                else
+                    v2 := 2
+                end
+                if v0 > 3 begin
+                    v3 := 3
+                end
+            : halt
+                jump halt
+            ",
+        );
+
+        assert_eq!(registers[0], 10);
+        assert_eq!(registers[1], 1 + 2 + 8 + 16);
+        assert_eq!(registers[2], 2);
+        assert_eq!(registers[3], 3);
+    }
+
+    #[test]
+    fn reports_errors_where_they_are() {
+        let error = compile(": main\n  v0 := 256").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 9));
+
+        let error = compile(": main\n\n    hires").unwrap_err();
+        assert_eq!((error.line, error.column), (3, 5));
+        assert!(error.message.contains("XO-CHIP"));
+
+        let error = compile(": main\n  loop\n    v0 += 1").unwrap_err();
+        assert_eq!(error.message, "Unclosed block: loop without again");
+        assert!(compile(": main\nend").is_err());
+        assert!(compile(": main\n: main").is_err());
+        assert!(compile(": main\n  save v0 - v3").is_err());
+    }
+}
@@ -1,16 +1,23 @@
-#[derive(Debug)]
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Opcode {
   NOP,
   CLS,
   RET,
+  SYS { addr: u16 },
   JP { addr: u16 },
   CALL { addr: u16 },
+  JP_V0 { addr: u16 },
   SE { x: u8, byte: u8 },
   SNE { x: u8, byte: u8 },
+  SE_R { x: u8, y: u8 },
+  SNE_R { x: u8, y: u8 },
   LD_IMM { x: u8, byte: u8 },
   ADD_IMM { x: u8, byte: u8 },
   ADD_R { x: u8, y: u8 },
   SUB_R { x: u8, y: u8},
+  SUBN_R { x: u8, y: u8 },
   LD_R { x: u8, y: u8 },
   LDI_IMM { addr: u16 },
   DRW { x: u8, y: u8, size: u8 },
@@ -19,11 +26,16 @@ pub enum Opcode {
   LD_R_K { x: u8 },
   ADDI_R { x: u8 },
   LD_M { x: u8 },
+  ST_M { x: u8 },
+  LD_F { x: u8 },
+  LD_B { x: u8 },
   SET_DT { x: u8 },
   SET_ST { x: u8 },
   LD_DT { x: u8 },
   AND { x: u8, y: u8 },
-  SHR { x: u8 },
+  OR_R { x: u8, y: u8 },
+  SHR { x: u8, y: u8 },
+  SHL { x: u8, y: u8 },
   XOR_R { x: u8, y: u8 },
   RND { x: u8, byte: u8 }
 }
@@ -60,6 +72,9 @@ impl Opcode {
     match nibbles {
       (0x00, 0x00, 0x0E, 0x00) => Opcode::CLS,
       (0x00, 0x00, 0x0E, 0x0E) => Opcode::RET,
+      (0x00, _, _, _) => Opcode::SYS {
+        addr: Opcode::read_nnn(instruction),
+      },
       (0x01, _, _, _) => Opcode::JP {
         addr: Opcode::read_nnn(instruction),
       },
@@ -74,6 +89,10 @@ impl Opcode {
         x: Opcode::read_x(instruction),
         byte: Opcode::read_kk(instruction),
       },
+      (0x05, _, _, 0x00) => Opcode::SE_R {
+        x: Opcode::read_x(instruction),
+        y: Opcode::read_y(instruction),
+      },
       (0x06, _, _, _) => Opcode::LD_IMM {
         x: Opcode::read_x(instruction),
         byte: Opcode::read_kk(instruction),
@@ -86,6 +105,10 @@ impl Opcode {
         x: Opcode::read_x(instruction),
         y: Opcode::read_y(instruction),
       },
+      (0x08, _, _, 0x01) => Opcode::OR_R {
+        x: Opcode::read_x(instruction),
+        y: Opcode::read_y(instruction),
+      },
       (0x08, _, _, 0x02) => Opcode::AND {
         x: Opcode::read_x(instruction),
         y: Opcode::read_y(instruction),
@@ -104,10 +127,26 @@ impl Opcode {
       },
       (0x08, _, _, 0x06) => Opcode::SHR {
         x: Opcode::read_x(instruction),
+        y: Opcode::read_y(instruction),
+      },
+      (0x08, _, _, 0x07) => Opcode::SUBN_R {
+        x: Opcode::read_x(instruction),
+        y: Opcode::read_y(instruction),
+      },
+      (0x08, _, _, 0x0E) => Opcode::SHL {
+        x: Opcode::read_x(instruction),
+        y: Opcode::read_y(instruction),
+      },
+      (0x09, _, _, 0x00) => Opcode::SNE_R {
+        x: Opcode::read_x(instruction),
+        y: Opcode::read_y(instruction),
       },
       (0x0A, _, _, _) => Opcode::LDI_IMM {
         addr: Opcode::read_nnn(instruction),
       },
+      (0x0B, _, _, _) => Opcode::JP_V0 {
+        addr: Opcode::read_nnn(instruction),
+      },
       (0x0C, _, _, _) => Opcode::RND {
         x: Opcode::read_x(instruction),
         byte: Opcode::read_kk(instruction)
@@ -138,10 +177,197 @@ impl Opcode {
       (0x0F, _, 0x01, 0x0E) => Opcode::ADDI_R {
         x: Opcode::read_x(instruction),
       },
+      (0x0F, _, 0x02, 0x09) => Opcode::LD_F {
+        x: Opcode::read_x(instruction),
+      },
+      (0x0F, _, 0x03, 0x03) => Opcode::LD_B {
+        x: Opcode::read_x(instruction),
+      },
+      (0x0F, _, 0x05, 0x05) => Opcode::ST_M {
+        x: Opcode::read_x(instruction),
+      },
       (0x0F, _, 0x06, 0x05) => Opcode::LD_M {
         x: Opcode::read_x(instruction),
       },
       _ => Opcode::NOP, // panic!("Unrecognized instruction {:#X?}", instruction),
     }
   }
+  // Inverse of `decode`. NOP has no single encoding and becomes 0xFFFF.
+  pub fn encode(&self) -> u16 {
+    let xy = |x: u8, y: u8| ((x as u16) << 8) | ((y as u16) << 4);
+    let xkk = |x: u8, kk: u8| ((x as u16) << 8) | kk as u16;
+    let x_only = |x: u8| (x as u16) << 8;
+
+    match *self {
+      Opcode::NOP => 0xFFFF,
+      Opcode::CLS => 0x00E0,
+      Opcode::RET => 0x00EE,
+      Opcode::SYS { addr } => addr,
+      Opcode::JP { addr } => 0x1000 | addr,
+      Opcode::CALL { addr } => 0x2000 | addr,
+      Opcode::SE { x, byte } => 0x3000 | xkk(x, byte),
+      Opcode::SNE { x, byte } => 0x4000 | xkk(x, byte),
+      Opcode::SE_R { x, y } => 0x5000 | xy(x, y),
+      Opcode::LD_IMM { x, byte } => 0x6000 | xkk(x, byte),
+      Opcode::ADD_IMM { x, byte } => 0x7000 | xkk(x, byte),
+      Opcode::LD_R { x, y } => 0x8000 | xy(x, y),
+      Opcode::OR_R { x, y } => 0x8001 | xy(x, y),
+      Opcode::AND { x, y } => 0x8002 | xy(x, y),
+      Opcode::XOR_R { x, y } => 0x8003 | xy(x, y),
+      Opcode::ADD_R { x, y } => 0x8004 | xy(x, y),
+      Opcode::SUB_R { x, y } => 0x8005 | xy(x, y),
+      Opcode::SHR { x, y } => 0x8006 | xy(x, y),
+      Opcode::SUBN_R { x, y } => 0x8007 | xy(x, y),
+      Opcode::SHL { x, y } => 0x800E | xy(x, y),
+      Opcode::SNE_R { x, y } => 0x9000 | xy(x, y),
+      Opcode::LDI_IMM { addr } => 0xA000 | addr,
+      Opcode::JP_V0 { addr } => 0xB000 | addr,
+      Opcode::RND { x, byte } => 0xC000 | xkk(x, byte),
+      Opcode::DRW { x, y, size } => 0xD000 | xy(x, y) | size as u16,
+      Opcode::SKP { x } => 0xE09E | x_only(x),
+      Opcode::SKNP { x } => 0xE0A1 | x_only(x),
+      Opcode::LD_DT { x } => 0xF007 | x_only(x),
+      Opcode::LD_R_K { x } => 0xF00A | x_only(x),
+      Opcode::SET_DT { x } => 0xF015 | x_only(x),
+      Opcode::SET_ST { x } => 0xF018 | x_only(x),
+      Opcode::ADDI_R { x } => 0xF01E | x_only(x),
+      Opcode::LD_F { x } => 0xF029 | x_only(x),
+      Opcode::LD_B { x } => 0xF033 | x_only(x),
+      Opcode::ST_M { x } => 0xF055 | x_only(x),
+      Opcode::LD_M { x } => 0xF065 | x_only(x),
+    }
+  }
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Opcode::NOP => "NOP",
+      Opcode::CLS => "CLS",
+      Opcode::RET => "RET",
+      Opcode::SYS { .. } => "SYS",
+      Opcode::JP { .. } => "JP",
+      Opcode::CALL { .. } => "CALL",
+      Opcode::JP_V0 { .. } => "JP_V0",
+      Opcode::SE { .. } => "SE",
+      Opcode::SNE { .. } => "SNE",
+      Opcode::SE_R { .. } => "SE_R",
+      Opcode::SNE_R { .. } => "SNE_R",
+      Opcode::LD_IMM { .. } => "LD_IMM",
+      Opcode::ADD_IMM { .. } => "ADD_IMM",
+      Opcode::ADD_R { .. } => "ADD_R",
+      Opcode::SUB_R { .. } => "SUB_R",
+      Opcode::SUBN_R { .. } => "SUBN_R",
+      Opcode::LD_R { .. } => "LD_R",
+      Opcode::LDI_IMM { .. } => "LDI_IMM",
+      Opcode::DRW { .. } => "DRW",
+      Opcode::SKNP { .. } => "SKNP",
+      Opcode::SKP { .. } => "SKP",
+      Opcode::LD_R_K { .. } => "LD_R_K",
+      Opcode::ADDI_R { .. } => "ADDI_R",
+      Opcode::LD_M { .. } => "LD_M",
+      Opcode::ST_M { .. } => "ST_M",
+      Opcode::LD_F { .. } => "LD_F",
+      Opcode::LD_B { .. } => "LD_B",
+      Opcode::SET_DT { .. } => "SET_DT",
+      Opcode::SET_ST { .. } => "SET_ST",
+      Opcode::LD_DT { .. } => "LD_DT",
+      Opcode::AND { .. } => "AND",
+      Opcode::OR_R { .. } => "OR_R",
+      Opcode::SHR { .. } => "SHR",
+      Opcode::SHL { .. } => "SHL",
+      Opcode::XOR_R { .. } => "XOR_R",
+      Opcode::RND { .. } => "RND",
+    }
+  }
+}
+
+impl fmt::Display for Opcode {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Opcode::NOP => write!(f, "NOP"),
+      Opcode::CLS => write!(f, "CLS"),
+      Opcode::RET => write!(f, "RET"),
+      Opcode::SYS { addr } => write!(f, "SYS 0x{:03X}", addr),
+      Opcode::JP { addr } => write!(f, "JP 0x{:03X}", addr),
+      Opcode::CALL { addr } => write!(f, "CALL 0x{:03X}", addr),
+      Opcode::JP_V0 { addr } => write!(f, "JP V0, 0x{:03X}", addr),
+      Opcode::SE { x, byte } => write!(f, "SE V{:X}, 0x{:02X}", x, byte),
+      Opcode::SNE { x, byte } => write!(f, "SNE V{:X}, 0x{:02X}", x, byte),
+      Opcode::SE_R { x, y } => write!(f, "SE V{:X}, V{:X}", x, y),
+      Opcode::SNE_R { x, y } => write!(f, "SNE V{:X}, V{:X}", x, y),
+      Opcode::LD_IMM { x, byte } => write!(f, "LD V{:X}, 0x{:02X}", x, byte),
+      Opcode::ADD_IMM { x, byte } => write!(f, "ADD V{:X}, 0x{:02X}", x, byte),
+      Opcode::ADD_R { x, y } => write!(f, "ADD V{:X}, V{:X}", x, y),
+      Opcode::SUB_R { x, y } => write!(f, "SUB V{:X}, V{:X}", x, y),
+      Opcode::SUBN_R { x, y } => write!(f, "SUBN V{:X}, V{:X}", x, y),
+      Opcode::LD_R { x, y } => write!(f, "LD V{:X}, V{:X}", x, y),
+      Opcode::LDI_IMM { addr } => write!(f, "LD I, 0x{:03X}", addr),
+      Opcode::DRW { x, y, size } => write!(f, "DRW V{:X}, V{:X}, {}", x, y, size),
+      Opcode::SKNP { x } => write!(f, "SKNP V{:X}", x),
+      Opcode::SKP { x } => write!(f, "SKP V{:X}", x),
+      Opcode::LD_R_K { x } => write!(f, "LD V{:X}, K", x),
+      Opcode::ADDI_R { x } => write!(f, "ADD I, V{:X}", x),
+      Opcode::LD_M { x } => write!(f, "LD V{:X}, [I]", x),
+      Opcode::ST_M { x } => write!(f, "LD [I], V{:X}", x),
+      Opcode::LD_F { x } => write!(f, "LD F, V{:X}", x),
+      Opcode::LD_B { x } => write!(f, "LD B, V{:X}", x),
+      Opcode::SET_DT { x } => write!(f, "LD DT, V{:X}", x),
+      Opcode::SET_ST { x } => write!(f, "LD ST, V{:X}", x),
+      Opcode::LD_DT { x } => write!(f, "LD V{:X}, DT", x),
+      Opcode::AND { x, y } => write!(f, "AND V{:X}, V{:X}", x, y),
+      Opcode::OR_R { x, y } => write!(f, "OR V{:X}, V{:X}", x, y),
+      Opcode::SHR { x, y } => write!(f, "SHR V{:X}, V{:X}", x, y),
+      Opcode::SHL { x, y } => write!(f, "SHL V{:X}, V{:X}", x, y),
+      Opcode::XOR_R { x, y } => write!(f, "XOR V{:X}, V{:X}", x, y),
+      Opcode::RND { x, byte } => write!(f, "RND V{:X}, 0x{:02X}", x, byte),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn decodes_operands() {
+    assert_eq!(Opcode::decode(0x00E0), Opcode::CLS);
+    assert_eq!(Opcode::decode(0x00EE), Opcode::RET);
+    assert_eq!(Opcode::decode(0x1ABC), Opcode::JP { addr: 0xABC });
+    assert_eq!(Opcode::decode(0x2ABC), Opcode::CALL { addr: 0xABC });
+    assert_eq!(Opcode::decode(0x0123), Opcode::SYS { addr: 0x123 });
+    assert_eq!(Opcode::decode(0x3A12), Opcode::SE { x: 0xA, byte: 0x12 });
+    assert_eq!(Opcode::decode(0x5AB0), Opcode::SE_R { x: 0xA, y: 0xB });
+    assert_eq!(Opcode::decode(0x8AB5), Opcode::SUB_R { x: 0xA, y: 0xB });
+    assert_eq!(Opcode::decode(0xBABC), Opcode::JP_V0 { addr: 0xABC });
+    assert_eq!(Opcode::decode(0xDAB7), Opcode::DRW { x: 0xA, y: 0xB, size: 7 });
+    assert_eq!(Opcode::decode(0xEA9E), Opcode::SKP { x: 0xA });
+    assert_eq!(Opcode::decode(0xFA33), Opcode::LD_B { x: 0xA });
+    assert_eq!(Opcode::decode(0xFA55), Opcode::ST_M { x: 0xA });
+  }
+
+  #[test]
+  fn unknown_instructions_decode_to_nop() {
+    assert_eq!(Opcode::decode(0x5AB1), Opcode::NOP);
+    assert_eq!(Opcode::decode(0x8AB8), Opcode::NOP);
+    assert_eq!(Opcode::decode(0xFAFF), Opcode::NOP);
+  }
+
+  #[test]
+  fn formats_mnemonics() {
+    assert_eq!(Opcode::decode(0x6A02).to_string(), "LD VA, 0x02");
+    assert_eq!(Opcode::decode(0xD125).to_string(), "DRW V1, V2, 5");
+    assert_eq!(Opcode::decode(0xF065).to_string(), "LD V0, [I]");
+  }
+
+  #[test]
+  fn encode_round_trips_every_instruction() {
+    for instruction in 0..=0xFFFFu16 {
+      let opcode = Opcode::decode(instruction);
+      assert_eq!(Opcode::decode(opcode.encode()), opcode, "{:#06X}", instruction);
+
+      match opcode {
+        Opcode::NOP => {}
+        _ => assert_eq!(opcode.encode(), instruction, "{:#06X}", instruction),
+      }
+    }
+  }
 }
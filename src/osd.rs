@@ -0,0 +1,319 @@
+use std::time::{Duration, Instant};
+
+// Text drawn over the emulated screen in software, so it works with any
+// frontend that ends up with a 0RGB buffer
+
+const GLYPH_WIDTH: usize = 3;
+const GLYPH_HEIGHT: usize = 5;
+// Messages stay up this many 60 Hz frames
+const MESSAGE_FRAMES: u32 = 120;
+
+const TEXT_COLOR: u32 = 0xFFFFFF;
+const SHADOW_COLOR: u32 = 0x000000;
+
+// Rows of 3 pixels, most significant bit on the left. Lower case is drawn as
+// upper case and anything else without a glyph as '?'.
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c.to_ascii_uppercase() {
+        'A' => [2, 5, 7, 5, 5],
+        'B' => [6, 5, 6, 5, 6],
+        'C' => [3, 4, 4, 4, 3],
+        'D' => [6, 5, 5, 5, 6],
+        'E' => [7, 4, 6, 4, 7],
+        'F' => [7, 4, 6, 4, 4],
+        'G' => [3, 4, 5, 5, 3],
+        'H' => [5, 5, 7, 5, 5],
+        'I' => [7, 2, 2, 2, 7],
+        'J' => [1, 1, 1, 5, 2],
+        'K' => [5, 5, 6, 5, 5],
+        'L' => [4, 4, 4, 4, 7],
+        'M' => [5, 7, 7, 5, 5],
+        'N' => [6, 5, 5, 5, 5],
+        'O' => [2, 5, 5, 5, 2],
+        'P' => [6, 5, 6, 4, 4],
+        'Q' => [2, 5, 5, 6, 3],
+        'R' => [6, 5, 6, 5, 5],
+        'S' => [3, 4, 2, 1, 6],
+        'T' => [7, 2, 2, 2, 2],
+        'U' => [5, 5, 5, 5, 7],
+        'V' => [5, 5, 5, 5, 2],
+        'W' => [5, 5, 7, 7, 5],
+        'X' => [5, 5, 2, 5, 5],
+        'Y' => [5, 5, 2, 2, 2],
+        'Z' => [7, 1, 2, 4, 7],
+        '0' => [7, 5, 5, 5, 7],
+        '1' => [2, 6, 2, 2, 7],
+        '2' => [6, 1, 2, 4, 7],
+        '3' => [6, 1, 2, 1, 6],
+        '4' => [5, 5, 7, 1, 1],
+        '5' => [7, 4, 6, 1, 6],
+        '6' => [3, 4, 7, 5, 7],
+        '7' => [7, 1, 2, 2, 2],
+        '8' => [7, 5, 7, 5, 7],
+        '9' => [7, 5, 7, 1, 6],
+        ' ' => [0, 0, 0, 0, 0],
+        '.' => [0, 0, 0, 0, 2],
+        ',' => [0, 0, 0, 2, 4],
+        ':' => [0, 2, 0, 2, 0],
+        '-' => [0, 0, 7, 0, 0],
+        '+' => [0, 2, 7, 2, 0],
+        '=' => [0, 7, 0, 7, 0],
+        '%' => [5, 1, 2, 4, 5],
+        '/' => [1, 1, 2, 4, 4],
+        '(' => [1, 2, 2, 2, 1],
+        ')' => [4, 2, 2, 2, 4],
+        '[' => [3, 2, 2, 2, 3],
+        ']' => [6, 2, 2, 2, 6],
+        '!' => [2, 2, 2, 0, 2],
+        '_' => [0, 0, 0, 0, 7],
+        '#' => [5, 7, 5, 7, 5],
+        '\'' => [2, 2, 0, 0, 0],
+        _ => [6, 1, 2, 0, 2],
+    }
+}
+
+// Size of `text` in pixels at `scale`, including the one pixel shadow
+pub fn text_size(text: &str, scale: usize) -> (usize, usize) {
+    let chars = text.chars().count();
+    (
+        (chars * (GLYPH_WIDTH + 1) + 1) * scale,
+        (GLYPH_HEIGHT + 2) * scale,
+    )
+}
+
+// Glyphs about as tall as one CHIP-8 pixel, keeping most of the game in view
+pub fn text_scale(width: usize) -> usize {
+    (width / 64 / 5).max(1)
+}
+
+// Characters that fit across `width` pixels
+pub fn columns(width: usize, scale: usize) -> usize {
+    (width / scale).saturating_sub(1) / (GLYPH_WIDTH + 1)
+}
+
+// Draws `text` with its top left corner at (x, y), on a dark box so it reads
+// against lit pixels. Anything outside the buffer is cut off.
+pub fn draw_text(buffer: &mut [u32], width: usize, x: usize, y: usize, text: &str, scale: usize) {
+    let height = buffer.len() / width;
+    let mut fill = |px: usize, py: usize, color: u32| {
+        for dy in 0..scale {
+            for dx in 0..scale {
+                let (bx, by) = (x + (px * scale) + dx, y + (py * scale) + dy);
+                if bx < width && by < height {
+                    buffer[by * width + bx] = color;
+                }
+            }
+        }
+    };
+
+    let (box_width, box_height) = text_size(text, 1);
+    for py in 0..box_height {
+        for px in 0..box_width {
+            fill(px, py, SHADOW_COLOR);
+        }
+    }
+    for (i, c) in text.chars().enumerate() {
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                if bits & (0b100 >> column) != 0 {
+                    fill(1 + i * (GLYPH_WIDTH + 1) + column, 1 + row, TEXT_COLOR);
+                }
+            }
+        }
+    }
+}
+
+// Frames and instructions per second, averaged over the last second
+#[derive(Debug)]
+pub struct Stats {
+    since: Instant,
+    frames: u64,
+    cycles: u64,
+    pub fps: f64,
+    pub ips: f64,
+}
+
+impl Stats {
+    pub fn new(now: Instant) -> Self {
+        Stats {
+            since: now,
+            frames: 0,
+            cycles: 0,
+            fps: 0.0,
+            ips: 0.0,
+        }
+    }
+
+    // Called once per presented frame with the instructions run since the last one
+    pub fn record(&mut self, now: Instant, cycles: u64) {
+        self.frames += 1;
+        self.cycles += cycles;
+
+        let elapsed = now.duration_since(self.since);
+        if elapsed >= Duration::from_secs(1) {
+            self.fps = self.frames as f64 / elapsed.as_secs_f64();
+            self.ips = self.cycles as f64 / elapsed.as_secs_f64();
+            self.since = now;
+            self.frames = 0;
+            self.cycles = 0;
+        }
+    }
+}
+
+// What the overlay shows besides the message
+#[derive(Debug, Default)]
+pub struct Status {
+    pub paused: bool,
+    pub speed_percent: u64,
+    pub quirks: String,
+    pub fps: f64,
+    pub ips: f64,
+}
+
+#[derive(Debug, Default)]
+pub struct Overlay {
+    // Frame rate, speed and quirks; pause and messages always show
+    pub show_stats: bool,
+    message: Option<(String, u32)>,
+}
+
+impl Overlay {
+    pub fn new() -> Self {
+        Overlay::default()
+    }
+
+    pub fn show_message(&mut self, text: &str) {
+        self.message = Some((text.to_string(), MESSAGE_FRAMES));
+    }
+
+    // Called on every 60 Hz frame to expire the message
+    pub fn tick(&mut self) {
+        if let Some((_, frames)) = &mut self.message {
+            *frames -= 1;
+            if *frames == 0 {
+                self.message = None;
+            }
+        }
+    }
+
+    pub fn lines(&self, status: &Status) -> Vec<String> {
+        let mut lines = Vec::new();
+        if self.show_stats {
+            lines.push(format!("{:.0} FPS {:.0} IPS", status.fps, status.ips));
+            lines.push(format!("QUIRKS {}", status.quirks));
+        }
+        if status.paused {
+            lines.push("PAUSED".to_string());
+        }
+        if status.speed_percent > 100 {
+            lines.push(format!("TURBO {}%", status.speed_percent));
+        } else if status.speed_percent < 100 || self.show_stats {
+            lines.push(format!("SPEED {}%", status.speed_percent));
+        }
+        if let Some((message, _)) = &self.message {
+            lines.push(message.clone());
+        }
+        lines
+    }
+
+    // Draws the lines down the left edge, scaled to suit the buffer. Returns
+    // false if there was nothing to draw.
+    pub fn render(&self, buffer: &mut [u32], width: usize, status: &Status) -> bool {
+        let lines = self.lines(status);
+        let scale = text_scale(width);
+        let line_height = text_size("", scale).1;
+        for (i, line) in lines.iter().enumerate() {
+            draw_text(buffer, width, 0, i * line_height, line, scale);
+        }
+        !lines.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_text(buffer: &[u32], width: usize) -> Vec<String> {
+        buffer
+            .chunks(width)
+            .map(|row| {
+                row.iter()
+                    .map(|pixel| if *pixel == TEXT_COLOR { '#' } else { '.' })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn draws_glyphs_on_a_shadow_box() {
+        let width = 10;
+        let mut buffer = vec![0x123456; width * 8];
+        draw_text(&mut buffer, width, 0, 0, "H1", 1);
+
+        assert_eq!(
+            to_text(&buffer, width),
+            vec![
+                "..........",
+                ".#.#..#...",
+                ".#.#.##...",
+                ".###..#...",
+                ".#.#..#...",
+                ".#.#.###..",
+                "..........",
+                "..........",
+            ]
+        );
+        assert_eq!(buffer[6 * width], SHADOW_COLOR);
+        assert_eq!(buffer[7 * width], 0x123456);
+        assert_eq!(buffer[9], 0x123456);
+    }
+
+    #[test]
+    fn clips_at_buffer_edges() {
+        let mut buffer = vec![0; 4 * 4];
+        draw_text(&mut buffer, 4, 2, 2, "WIDE TEXT", 2);
+        assert_eq!(buffer[0], 0);
+    }
+
+    #[test]
+    fn lines_follow_status() {
+        let mut overlay = Overlay::new();
+        let mut status = Status {
+            paused: true,
+            speed_percent: 200,
+            quirks: "chip8".to_string(),
+            fps: 60.0,
+            ips: 720.0,
+        };
+        assert_eq!(overlay.lines(&status), vec!["PAUSED", "TURBO 200%"]);
+
+        overlay.show_stats = true;
+        overlay.show_message("Reset");
+        status.paused = false;
+        status.speed_percent = 100;
+        assert_eq!(
+            overlay.lines(&status),
+            vec!["60 FPS 720 IPS", "QUIRKS chip8", "SPEED 100%", "Reset"]
+        );
+
+        for _ in 0..MESSAGE_FRAMES {
+            overlay.tick();
+        }
+        overlay.show_stats = false;
+        assert!(overlay.lines(&status).is_empty());
+        let mut buffer = vec![0; 64 * 32];
+        assert!(!overlay.render(&mut buffer, 64, &status));
+    }
+
+    #[test]
+    fn stats_average_over_a_second() {
+        let start = Instant::now();
+        let mut stats = Stats::new(start);
+        for frame in 1..=30 {
+            stats.record(start + Duration::from_millis(frame * 1000 / 30), 10);
+        }
+
+        assert!((stats.fps - 30.0).abs() < 0.01);
+        assert!((stats.ips - 300.0).abs() < 0.1);
+    }
+}
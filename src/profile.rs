@@ -0,0 +1,365 @@
+use super::cpu::CpuState;
+use super::disasm;
+use super::flow::Flow;
+use super::opcode::Opcode;
+use super::timing;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+const HOTTEST: usize = 20;
+
+// Time is counted both in instructions and in the machine cycles the COSMAC VIP
+// interpreter would take, which is what matters for fitting in a frame.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Counts {
+    pub executions: u64,
+    pub vip_cycles: u64,
+}
+
+impl Counts {
+    fn add(&mut self, vip_cycles: u64) {
+        self.executions += 1;
+        self.vip_cycles += vip_cycles;
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Subroutine {
+    pub calls: u64,
+    // Including the subroutines it calls
+    pub total: Counts,
+    pub own: Counts,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Frame {
+    entry: u16,
+    start: Counts,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    // Indexed by address
+    addresses: Vec<Counts>,
+    opcodes: BTreeMap<&'static str, Counts>,
+    subroutines: BTreeMap<u16, Subroutine>,
+    calls: Vec<Frame>,
+    total: Counts,
+    // DRW count and VIP cycles of each frame so far
+    frames: Vec<(u64, u64)>,
+    frame_start: Counts,
+    frame_draws: u64,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler::default()
+    }
+
+    // Called after `instruction` ran, with the registers it ran with
+    pub fn record(&mut self, instruction: u16, before: &CpuState) {
+        let opcode = Opcode::decode(instruction);
+        let pc = before.reg_pc as usize;
+        let vip_cycles = timing::instruction_cycles(opcode, before) as u64;
+
+        if self.addresses.len() <= pc {
+            self.addresses.resize(pc + 1, Counts::default());
+        }
+        self.addresses[pc].add(vip_cycles);
+        self.opcodes
+            .entry(opcode.name())
+            .or_default()
+            .add(vip_cycles);
+        self.total.add(vip_cycles);
+        if let Some(frame) = self.calls.last() {
+            self.subroutines
+                .entry(frame.entry)
+                .or_default()
+                .own
+                .add(vip_cycles);
+        }
+
+        match opcode {
+            Opcode::CALL { addr } => {
+                self.subroutines.entry(addr).or_default().calls += 1;
+                self.calls.push(Frame {
+                    entry: addr,
+                    start: self.total,
+                });
+            }
+            Opcode::RET => {
+                if let Some(frame) = self.calls.pop() {
+                    self.finish_call(frame);
+                }
+            }
+            Opcode::DRW { .. } => self.frame_draws += 1,
+            _ => {}
+        }
+    }
+
+    fn finish_call(&mut self, frame: Frame) {
+        // A recursive call's time is already part of the outer call's
+        if self.calls.iter().any(|outer| outer.entry == frame.entry) {
+            return;
+        }
+        let total = &mut self.subroutines.entry(frame.entry).or_default().total;
+        total.executions += self.total.executions - frame.start.executions;
+        total.vip_cycles += self.total.vip_cycles - frame.start.vip_cycles;
+    }
+
+    // Called on each 60 Hz tick
+    pub fn end_frame(&mut self) {
+        self.frames.push((
+            self.frame_draws,
+            self.total.vip_cycles - self.frame_start.vip_cycles,
+        ));
+        self.frame_start = self.total;
+        self.frame_draws = 0;
+    }
+
+    pub fn total(&self) -> Counts {
+        self.total
+    }
+
+    pub fn at(&self, addr: u16) -> Counts {
+        self.addresses
+            .get(addr as usize)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn opcode(&self, name: &str) -> Counts {
+        self.opcodes.get(name).copied().unwrap_or_default()
+    }
+
+    // Subroutines still running when the profile ends have no total yet
+    pub fn subroutine(&self, entry: u16) -> Option<Subroutine> {
+        self.subroutines.get(&entry).copied()
+    }
+
+    pub fn draws_per_frame(&self) -> Vec<u64> {
+        self.frames.iter().map(|(draws, _)| *draws).collect()
+    }
+
+    fn share(&self, executions: u64) -> f64 {
+        executions as f64 * 100.0 / self.total.executions.max(1) as f64
+    }
+
+    // `labels` name subroutines and addresses where known
+    pub fn report(&self, labels: &BTreeMap<String, u16>) -> String {
+        let names: BTreeMap<u16, &str> = labels
+            .iter()
+            .map(|(name, addr)| (*addr, name.as_str()))
+            .collect();
+        let name = |addr: u16| match names.get(&addr) {
+            Some(name) => format!("  {}", name),
+            None => String::new(),
+        };
+        let mut out = String::new();
+
+        writeln!(
+            out,
+            "{} instructions, {} VIP machine cycles, {} frames",
+            self.total.executions,
+            self.total.vip_cycles,
+            self.frames.len()
+        )
+        .unwrap();
+        if !self.frames.is_empty() {
+            let frames = self.frames.len() as f64;
+            let draws: u64 = self.frames.iter().map(|(draws, _)| draws).sum();
+            let most_draws = self.frames.iter().map(|(draws, _)| *draws).max().unwrap();
+            let most_cycles = self.frames.iter().map(|(_, cycles)| *cycles).max().unwrap();
+            let over_budget = self
+                .frames
+                .iter()
+                .filter(|(_, cycles)| *cycles as i64 > timing::INTERPRETER_CYCLES_PER_FRAME)
+                .count();
+            writeln!(
+                out,
+                "DRW per frame: {:.1} on average, {} at most",
+                draws as f64 / frames,
+                most_draws
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "VIP cycles per frame: {:.0} on average, {} at most, {} frames over the VIP's {}",
+                self.total.vip_cycles as f64 / frames,
+                most_cycles,
+                over_budget,
+                timing::INTERPRETER_CYCLES_PER_FRAME
+            )
+            .unwrap();
+        }
+
+        let mut hottest: Vec<(u16, Counts)> = self
+            .addresses
+            .iter()
+            .enumerate()
+            .filter(|(_, counts)| counts.executions > 0)
+            .map(|(addr, counts)| (addr as u16, *counts))
+            .collect();
+        hottest.sort_by_key(|(addr, counts)| (std::cmp::Reverse(counts.vip_cycles), *addr));
+        writeln!(out, "\nhottest addresses by VIP cycles:").unwrap();
+        writeln!(out, "  addr   executions      %   VIP cycles").unwrap();
+        for (addr, counts) in hottest.iter().take(HOTTEST) {
+            writeln!(
+                out,
+                "  {:03X}  {:>11} {:>6.2} {:>12}{}",
+                addr,
+                counts.executions,
+                self.share(counts.executions),
+                counts.vip_cycles,
+                name(*addr)
+            )
+            .unwrap();
+        }
+
+        if !self.subroutines.is_empty() {
+            let mut subroutines: Vec<(&u16, &Subroutine)> = self.subroutines.iter().collect();
+            subroutines
+                .sort_by_key(|(entry, sub)| (std::cmp::Reverse(sub.total.vip_cycles), **entry));
+            writeln!(
+                out,
+                "\nsubroutines by VIP cycles, including what they call:"
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "  addr       calls   instructions      %   VIP cycles   own cycles  per call"
+            )
+            .unwrap();
+            for (entry, sub) in subroutines {
+                writeln!(
+                    out,
+                    "  {:03X}  {:>10} {:>14} {:>6.2} {:>12} {:>12} {:>9.0}{}",
+                    entry,
+                    sub.calls,
+                    sub.total.executions,
+                    self.share(sub.total.executions),
+                    sub.total.vip_cycles,
+                    sub.own.vip_cycles,
+                    sub.total.vip_cycles as f64 / sub.calls.max(1) as f64,
+                    name(*entry)
+                )
+                .unwrap();
+            }
+        }
+
+        let mut opcodes: Vec<(&&str, &Counts)> = self.opcodes.iter().collect();
+        opcodes.sort_by_key(|(name, counts)| (std::cmp::Reverse(counts.executions), **name));
+        writeln!(out, "\ninstructions:").unwrap();
+        writeln!(out, "  opcode   executions      %   VIP cycles").unwrap();
+        for (name, counts) in opcodes {
+            writeln!(
+                out,
+                "  {:<7} {:>11} {:>6.2} {:>12}",
+                name,
+                counts.executions,
+                self.share(counts.executions),
+                counts.vip_cycles
+            )
+            .unwrap();
+        }
+        out
+    }
+
+    // The program's disassembly with how often each instruction ran. Code found
+    // only by running it is listed as code too.
+    pub fn annotate(&self, program: &[u8], origin: u16, labels: &BTreeMap<String, u16>) -> String {
+        let mut flow = Flow::analyze(program, origin);
+        for (offset, pair) in program.windows(2).enumerate() {
+            let addr = origin + offset as u16;
+            if self.at(addr).executions > 0 {
+                let instruction = u16::from_be_bytes([pair[0], pair[1]]);
+                flow.instructions.insert(addr, Opcode::decode(instruction));
+            }
+        }
+
+        let mut all_labels = flow.labels();
+        all_labels.retain(|_, addr| !labels.values().any(|a| a == addr));
+        all_labels.extend(labels.iter().map(|(name, addr)| (name.clone(), *addr)));
+        disasm::disassemble_annotated(program, origin, &all_labels, &flow, |addr| {
+            if !flow.instructions.contains_key(&addr) {
+                return None;
+            }
+            let counts = self.at(addr);
+            Some(match counts.executions {
+                0 => "never ran".to_string(),
+                executions => format!(
+                    "ran {}x, {:.2}%, {} VIP cycles",
+                    executions,
+                    self.share(executions),
+                    counts.vip_cycles
+                ),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::headless::Headless;
+    use crate::rom::Layout;
+
+    const SOURCE: &str = "
+        start:  CALL frame
+                JP start
+        frame:  CALL draw
+                CALL draw
+                RET
+        draw:   LD I, dot
+                DRW V0, V1, 1
+                RET
+        dot:    db 0x80
+    ";
+
+    fn profile(frames: u64) -> (Vec<u8>, Profiler) {
+        let assembly = assemble(SOURCE, 0x200).unwrap();
+        let mut machine = Headless::new(&assembly.bytes, Layout::default(), 0).unwrap();
+        machine.set_cycles_per_frame(11);
+        machine.set_profiler(Profiler::new());
+        machine.run_frames(frames).unwrap();
+        (assembly.bytes, machine.profiler().unwrap().clone())
+    }
+
+    #[test]
+    fn counts_addresses_opcodes_and_draws() {
+        // Each pass through the loop takes 11 instructions, one frame
+        let (_, profiler) = profile(3);
+
+        assert_eq!(profiler.total().executions, 33);
+        assert_eq!(profiler.at(0x200).executions, 3);
+        assert_eq!(profiler.at(0x20A).executions, 6);
+        assert_eq!(profiler.opcode("CALL").executions, 9);
+        assert_eq!(profiler.opcode("DRW").executions, 6);
+        assert_eq!(profiler.draws_per_frame(), [2, 2, 2]);
+    }
+
+    #[test]
+    fn times_subroutines_with_what_they_call() {
+        let (program, profiler) = profile(3);
+
+        let frame = profiler.subroutine(0x204).unwrap();
+        assert_eq!(frame.calls, 3);
+        assert_eq!(frame.total.executions, 3 * 9);
+        assert_eq!(frame.own.executions, 3 * 3);
+        let draw = profiler.subroutine(0x20A).unwrap();
+        assert_eq!(draw.calls, 6);
+        assert_eq!(draw.total.executions, 6 * 3);
+        assert!(draw.total.vip_cycles > 6 * 3 * 40);
+
+        let mut labels = BTreeMap::new();
+        labels.insert("draw".to_string(), 0x20A);
+        let report = profiler.report(&labels);
+        assert!(report.starts_with("33 instructions, "));
+        assert!(report.contains("DRW per frame: 2.0 on average, 2 at most"));
+        let listing = profiler.annotate(&program, 0x200, &labels);
+        assert!(listing.contains("draw:\n    LD I, data_210       ; 20A: A210  ran 6x, 18.18%"));
+        assert!(listing.contains("    db 0x80              ; 210: 80\n"));
+    }
+}
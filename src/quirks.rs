@@ -0,0 +1,58 @@
+// Behaviours that differ between CHIP-8 interpreters. The default matches what
+// this emulator has always done; the presets follow the platforms ROMs target.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Quirks {
+    // AND, OR and XOR clear VF
+    pub vf_reset: bool,
+    // Fx55 and Fx65 leave I pointing past the last register
+    pub memory_increment: bool,
+    // 8xy6 and 8xyE shift Vy into Vx instead of shifting Vx in place
+    pub shift_vy: bool,
+    // Bxnn jumps to xnn + Vx instead of nnn + V0
+    pub jump_vx: bool,
+    // Sprites are cut off at the screen edges instead of wrapping around
+    pub clip_sprites: bool,
+}
+
+pub const PRESETS: [&str; 4] = ["default", "chip8", "schip", "xochip"];
+
+impl Quirks {
+    pub fn preset(name: &str) -> Option<Self> {
+        let quirks = match name {
+            "default" => Quirks::default(),
+            "chip8" | "vip" => Quirks {
+                vf_reset: true,
+                memory_increment: true,
+                shift_vy: true,
+                jump_vx: false,
+                clip_sprites: true,
+            },
+            "schip" => Quirks {
+                vf_reset: false,
+                memory_increment: false,
+                shift_vy: false,
+                jump_vx: true,
+                clip_sprites: true,
+            },
+            "xochip" => Quirks {
+                vf_reset: false,
+                memory_increment: true,
+                shift_vy: true,
+                jump_vx: false,
+                clip_sprites: false,
+            },
+            _ => return None,
+        };
+
+        Some(quirks)
+    }
+
+    // The preset these quirks match, or "custom"
+    pub fn name(&self) -> &'static str {
+        PRESETS
+            .iter()
+            .find(|name| Quirks::preset(name) == Some(*self))
+            .copied()
+            .unwrap_or("custom")
+    }
+}
@@ -0,0 +1,166 @@
+use super::cpu::Cpu;
+use super::memory::Memory;
+
+use std::fmt;
+
+pub const CHIP8_LOAD_ADDRESS: u16 = 0x200;
+pub const ETI660_LOAD_ADDRESS: u16 = 0x600;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Layout {
+    pub load_address: u16,
+    pub entry_point: u16,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout::at(CHIP8_LOAD_ADDRESS)
+    }
+}
+
+impl Layout {
+    pub fn at(address: u16) -> Self {
+        Layout {
+            load_address: address,
+            entry_point: address,
+        }
+    }
+
+    pub fn preset(name: &str) -> Option<Self> {
+        match name {
+            // The later interpreters load where the original did
+            "chip8" | "vip" | "schip" | "xochip" => Some(Layout::at(CHIP8_LOAD_ADDRESS)),
+            "eti660" => Some(Layout::at(ETI660_LOAD_ADDRESS)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RomError {
+    TooLarge { size: usize, available: usize },
+    EntryPointOutOfRange { entry_point: u16 },
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::TooLarge { size, available } => write!(
+                f,
+                "ROM is {} bytes but only {} bytes are available at the load address",
+                size, available
+            ),
+            RomError::EntryPointOutOfRange { entry_point } => {
+                write!(f, "entry point {:#05X} is outside memory", entry_point)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RomError {}
+
+// Copies the program into memory and points the CPU at its entry point
+pub fn load(
+    cpu: &mut Cpu,
+    memory: &mut Memory,
+    program: &[u8],
+    layout: Layout,
+) -> Result<(), RomError> {
+    let available = memory.size().saturating_sub(layout.load_address as usize);
+    if program.len() > available {
+        return Err(RomError::TooLarge {
+            size: program.len(),
+            available: available,
+        });
+    }
+    if !memory.contains(layout.entry_point, 2) {
+        return Err(RomError::EntryPointOutOfRange {
+            entry_point: layout.entry_point,
+        });
+    }
+
+    memory.write_chunk(layout.load_address, program.to_vec().into_boxed_slice());
+    cpu.set_pc(layout.entry_point);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_for_every_platform() {
+        for platform in ["chip8", "vip", "schip", "xochip"].iter() {
+            assert_eq!(Layout::preset(platform), Some(Layout::default()));
+        }
+        assert_eq!(Layout::preset("eti660"), Some(Layout::at(0x600)));
+        assert_eq!(Layout::preset("pdp8"), None);
+    }
+
+    #[test]
+    fn loads_eti660_programs_at_0x600() {
+        let mut cpu = Cpu::with_seed(0);
+        let mut memory = Memory::new();
+        load(
+            &mut cpu,
+            &mut memory,
+            &[0x12, 0x34],
+            Layout::preset("eti660").unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(memory.read_doublebyte(0x600), 0x1234);
+        assert_eq!(cpu.state().reg_pc, 0x600);
+    }
+
+    #[test]
+    fn separate_entry_point() {
+        let mut cpu = Cpu::with_seed(0);
+        let mut memory = Memory::new();
+        let layout = Layout {
+            load_address: 0x200,
+            entry_point: 0x260,
+        };
+        load(&mut cpu, &mut memory, &[0xAA], layout).unwrap();
+
+        assert_eq!(memory.read_byte(0x200), 0xAA);
+        assert_eq!(cpu.state().reg_pc, 0x260);
+    }
+
+    #[test]
+    fn rejects_programs_larger_than_memory() {
+        let mut cpu = Cpu::with_seed(0);
+        let mut memory = Memory::new();
+        let program = vec![0; 0xA01];
+
+        assert_eq!(
+            load(
+                &mut cpu,
+                &mut memory,
+                &program,
+                Layout::preset("eti660").unwrap()
+            ),
+            Err(RomError::TooLarge {
+                size: 0xA01,
+                available: 0xA00,
+            })
+        );
+        assert!(load(&mut cpu, &mut memory, &program[1..], Layout::at(0x600)).is_ok());
+    }
+
+    #[test]
+    fn rejects_entry_point_outside_memory() {
+        let mut cpu = Cpu::with_seed(0);
+        let mut memory = Memory::new();
+        let layout = Layout {
+            load_address: 0x200,
+            entry_point: 0xFFF,
+        };
+
+        assert_eq!(
+            load(&mut cpu, &mut memory, &[], layout),
+            Err(RomError::EntryPointOutOfRange { entry_point: 0xFFF })
+        );
+    }
+}
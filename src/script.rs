@@ -0,0 +1,419 @@
+use super::cpu::{Cpu, CpuState};
+use super::display::Display;
+use super::keyboard::KeyboardState;
+use super::memory::Memory;
+
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, Map, ParseError, Scope, AST, INT};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+// Rhai scripts that watch and drive the machine. The top level runs once at the
+// start and can set breakpoints. These functions are called if the script
+// defines them, with `this` bound to a map that keeps its contents between calls:
+//
+//   on_frame(frame)      after each 60 Hz frame
+//   on_step(pc)          before each instruction
+//   on_breakpoint(pc)    before an instruction at a breakpoint
+//
+// Scripts can use pc, v(x), i, delay and sound with their set_ forms, peek and
+// poke memory, read pixel(x, y) and screen(), press, release and pressed keys,
+// set breakpoint(addr) and stop() the run.
+
+type Result<T> = std::result::Result<T, Box<EvalAltResult>>;
+
+#[derive(Debug)]
+pub enum ScriptError {
+    Io { path: PathBuf, error: io::Error },
+    Parse(ParseError),
+    Run(Box<EvalAltResult>),
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScriptError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            ScriptError::Parse(e) => write!(f, "script error: {}", e),
+            ScriptError::Run(e) => write!(f, "script error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+// The machine as lent to the script for the length of a call
+#[derive(Debug)]
+struct Machine {
+    state: CpuState,
+    memory: Memory,
+    framebuffer: Box<Vec<u32>>,
+    // Held by the script until it releases them
+    keys: [bool; 16],
+    breakpoints: BTreeSet<u16>,
+    stopped: bool,
+}
+
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    this: Dynamic,
+    machine: Rc<RefCell<Machine>>,
+    frames: u64,
+}
+
+impl fmt::Debug for Script {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Script")
+            .field("machine", &self.machine)
+            .field("frames", &self.frames)
+            .finish()
+    }
+}
+
+fn in_range(value: INT, max: INT, what: &str) -> Result<INT> {
+    if (0..=max).contains(&value) {
+        Ok(value)
+    } else {
+        Err(format!("{} out of range: {}", what, value).into())
+    }
+}
+
+impl Script {
+    pub fn load<P: AsRef<Path>>(path: P) -> std::result::Result<Self, ScriptError> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path).map_err(|e| ScriptError::Io {
+            path: path.to_path_buf(),
+            error: e,
+        })?;
+        Script::new(&source)
+    }
+
+    pub fn new(source: &str) -> std::result::Result<Self, ScriptError> {
+        let machine = Rc::new(RefCell::new(Machine {
+            state: Cpu::with_seed(0).state(),
+            memory: Memory::default(),
+            framebuffer: Box::default(),
+            keys: [false; 16],
+            breakpoints: BTreeSet::new(),
+            stopped: false,
+        }));
+        let mut engine = Engine::new();
+        register(&mut engine, &machine);
+        let ast = engine.compile(source).map_err(ScriptError::Parse)?;
+
+        Ok(Script {
+            engine: engine,
+            ast: ast,
+            scope: Scope::new(),
+            this: Map::new().into(),
+            machine: machine,
+            frames: 0,
+        })
+    }
+
+    // Runs the top level of the script
+    pub fn start(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut Memory,
+        display: &mut Display,
+    ) -> std::result::Result<(), ScriptError> {
+        let (engine, ast, scope) = (&self.engine, &self.ast, &mut self.scope);
+        lend(&self.machine, cpu, memory, display, || {
+            engine.run_ast_with_scope(scope, ast)
+        })
+        .map_err(ScriptError::Run)
+    }
+
+    fn defines(&self, name: &str) -> bool {
+        self.ast
+            .iter_functions()
+            .any(|f| f.name == name && f.params.len() == 1)
+    }
+
+    fn call(
+        &mut self,
+        name: &str,
+        arg: INT,
+        cpu: &mut Cpu,
+        memory: &mut Memory,
+        display: &mut Display,
+    ) -> std::result::Result<(), ScriptError> {
+        if !self.defines(name) {
+            return Ok(());
+        }
+        let (engine, ast, scope, this) = (&self.engine, &self.ast, &mut self.scope, &mut self.this);
+        lend(&self.machine, cpu, memory, display, || {
+            let options = CallFnOptions::new().eval_ast(false).bind_this_ptr(this);
+            engine
+                .call_fn_with_options::<Dynamic>(options, scope, ast, name, (arg,))
+                .map(drop)
+        })
+        .map_err(ScriptError::Run)
+    }
+
+    // Called before each instruction
+    pub fn before_step(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut Memory,
+        display: &mut Display,
+    ) -> std::result::Result<(), ScriptError> {
+        let pc = cpu.state().reg_pc;
+        if self.machine.borrow().breakpoints.contains(&pc) {
+            self.call("on_breakpoint", pc as INT, cpu, memory, display)?;
+        }
+        self.call("on_step", pc as INT, cpu, memory, display)
+    }
+
+    // Called after each 60 Hz frame
+    pub fn end_frame(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut Memory,
+        display: &mut Display,
+    ) -> std::result::Result<(), ScriptError> {
+        self.frames += 1;
+        self.call("on_frame", self.frames as INT, cpu, memory, display)
+    }
+
+    // The keys pressed on the keyboard, with the ones the script holds
+    pub fn keys_over(&self, keyboard_state: &KeyboardState) -> KeyboardState {
+        let held = self.machine.borrow().keys;
+        let mut keys = KeyboardState::default();
+        for key in 0..16 {
+            keys.set_key(
+                key,
+                keyboard_state.is_key_pressed(key) || held[key as usize],
+            );
+        }
+        keys
+    }
+
+    pub fn stopped(&self) -> bool {
+        self.machine.borrow().stopped
+    }
+}
+
+// Moves the machine's state in for `f` and back out again, so the registered
+// functions can reach it without holding references
+fn lend<F: FnOnce() -> Result<()>>(
+    machine: &Rc<RefCell<Machine>>,
+    cpu: &mut Cpu,
+    memory: &mut Memory,
+    display: &mut Display,
+    f: F,
+) -> Result<()> {
+    {
+        let mut machine = machine.borrow_mut();
+        machine.state = cpu.state();
+        mem::swap(&mut machine.memory, memory);
+        mem::swap(&mut machine.framebuffer, &mut display.framebuffer);
+    }
+    let result = f();
+
+    let mut machine = machine.borrow_mut();
+    cpu.set_state(&machine.state);
+    mem::swap(&mut machine.memory, memory);
+    mem::swap(&mut machine.framebuffer, &mut display.framebuffer);
+    result
+}
+
+fn register(engine: &mut Engine, machine: &Rc<RefCell<Machine>>) {
+    let m = machine.clone();
+    engine.register_fn("pc", move || m.borrow().state.reg_pc as INT);
+    let m = machine.clone();
+    engine.register_fn("set_pc", move |addr: INT| -> Result<()> {
+        m.borrow_mut().state.reg_pc = in_range(addr, 0xFFF, "address")? as u16;
+        Ok(())
+    });
+    let m = machine.clone();
+    engine.register_fn("v", move |x: INT| -> Result<INT> {
+        Ok(m.borrow().state.reg_gp[in_range(x, 0xF, "register")? as usize] as INT)
+    });
+    let m = machine.clone();
+    engine.register_fn("set_v", move |x: INT, value: INT| -> Result<()> {
+        let x = in_range(x, 0xF, "register")? as usize;
+        m.borrow_mut().state.reg_gp[x] = in_range(value, 0xFF, "value")? as u8;
+        Ok(())
+    });
+    let m = machine.clone();
+    engine.register_fn("i", move || m.borrow().state.reg_i as INT);
+    let m = machine.clone();
+    engine.register_fn("set_i", move |value: INT| -> Result<()> {
+        m.borrow_mut().state.reg_i = in_range(value, 0xFFFF, "value")? as u16;
+        Ok(())
+    });
+    let m = machine.clone();
+    engine.register_fn("delay", move || m.borrow().state.reg_delay as INT);
+    let m = machine.clone();
+    engine.register_fn("set_delay", move |value: INT| -> Result<()> {
+        m.borrow_mut().state.reg_delay = in_range(value, 0xFF, "value")? as u8;
+        Ok(())
+    });
+    let m = machine.clone();
+    engine.register_fn("sound", move || m.borrow().state.reg_sound_timer as INT);
+    let m = machine.clone();
+    engine.register_fn("set_sound", move |value: INT| -> Result<()> {
+        m.borrow_mut().state.reg_sound_timer = in_range(value, 0xFF, "value")? as u8;
+        Ok(())
+    });
+
+    let m = machine.clone();
+    engine.register_fn("peek", move |addr: INT| -> Result<INT> {
+        let machine = m.borrow();
+        let addr = in_range(addr, machine.memory.size() as INT - 1, "address")?;
+        Ok(machine.memory.read_byte(addr as u16) as INT)
+    });
+    let m = machine.clone();
+    engine.register_fn("poke", move |addr: INT, value: INT| -> Result<()> {
+        let mut machine = m.borrow_mut();
+        let addr = in_range(addr, machine.memory.size() as INT - 1, "address")?;
+        machine
+            .memory
+            .write_byte(addr as u16, in_range(value, 0xFF, "value")? as u8);
+        Ok(())
+    });
+
+    let m = machine.clone();
+    engine.register_fn("pixel", move |x: INT, y: INT| -> Result<bool> {
+        let (x, y) = (in_range(x, 63, "x")?, in_range(y, 31, "y")?);
+        Ok(m.borrow().framebuffer[(y * 64 + x) as usize] != 0)
+    });
+    let m = machine.clone();
+    engine.register_fn("screen", move || {
+        let mut text = String::new();
+        for row in m.borrow().framebuffer.chunks(64) {
+            text.extend(row.iter().map(|pixel| if *pixel != 0 { '#' } else { '.' }));
+            text.push('\n');
+        }
+        text
+    });
+
+    let m = machine.clone();
+    engine.register_fn("press", move |key: INT| -> Result<()> {
+        m.borrow_mut().keys[in_range(key, 0xF, "key")? as usize] = true;
+        Ok(())
+    });
+    let m = machine.clone();
+    engine.register_fn("release", move |key: INT| -> Result<()> {
+        m.borrow_mut().keys[in_range(key, 0xF, "key")? as usize] = false;
+        Ok(())
+    });
+    let m = machine.clone();
+    engine.register_fn("pressed", move |key: INT| -> Result<bool> {
+        Ok(m.borrow().keys[in_range(key, 0xF, "key")? as usize])
+    });
+
+    let m = machine.clone();
+    engine.register_fn("breakpoint", move |addr: INT| -> Result<()> {
+        let addr = in_range(addr, 0xFFF, "address")? as u16;
+        m.borrow_mut().breakpoints.insert(addr);
+        Ok(())
+    });
+    let m = machine.clone();
+    engine.register_fn("stop", move || m.borrow_mut().stopped = true);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::headless::Headless;
+    use crate::rom::Layout;
+
+    fn machine(source: &str, script: &str) -> Headless {
+        let program = assemble(source, 0x200).unwrap().bytes;
+        let mut machine = Headless::new(&program, Layout::default(), 0).unwrap();
+        machine.set_script(Script::new(script).unwrap()).unwrap();
+        machine
+    }
+
+    #[test]
+    fn reads_and_writes_the_machine() {
+        let source = "
+                LD V0, 5
+                LD I, 0x300
+                LD [I], V0
+            end: JP end
+        ";
+        let script = "
+            poke(0x301, 0xAB);
+            set_v(3, 7);
+            fn on_frame(frame) {
+                this.frames = frame;
+                this.stored = peek(0x300);
+                this.v3 = v(3);
+                this.pc = pc();
+                set_v(4, i() >> 8);
+            }
+        ";
+        let mut machine = machine(source, script);
+        machine.run_frames(2).unwrap();
+
+        assert_eq!(machine.memory.read_byte(0x301), 0xAB);
+        assert_eq!(machine.cpu.state().reg_gp[4], 3);
+        let this = machine_map(&machine);
+        assert_eq!(this["frames"].as_int().unwrap(), 2);
+        assert_eq!(this["stored"].as_int().unwrap(), 5);
+        assert_eq!(this["v3"].as_int().unwrap(), 7);
+        assert_eq!(this["pc"].as_int().unwrap(), 0x206);
+    }
+
+    fn machine_map(machine: &Headless) -> Map {
+        machine.script().unwrap().this.clone().cast::<Map>()
+    }
+
+    #[test]
+    fn presses_keys_and_stops_at_breakpoints() {
+        // Counts in V1 until key 5 is held, then draws
+        let source = "
+                LD V0, 5
+            wait: ADD V1, 1
+                SKP V0
+                JP wait
+            hit: LD I, dot
+                DRW V2, V2, 1
+            end: JP end
+            dot: db 0x80
+        ";
+        let script = "
+            breakpoint(0x208);
+            fn on_frame(frame) {
+                if frame == 3 { press(5); }
+            }
+            fn on_breakpoint(pc) {
+                this.counted = v(1);
+            }
+            fn on_step(pc) {
+                if pc == 0x20C { stop(); }
+            }
+        ";
+        let mut machine = machine(source, script);
+        machine.run_frames(100).unwrap();
+
+        assert!(machine.stopped());
+        assert!(machine.screen_text().starts_with("#."));
+        assert!(!machine.keyboard_state.is_key_pressed(5));
+        // 8 by the end of the third frame, then once more before SKP sees the key
+        assert_eq!(machine_map(&machine)["counted"].as_int().unwrap(), 9);
+    }
+
+    #[test]
+    fn reports_errors() {
+        assert!(matches!(Script::new("fn ("), Err(ScriptError::Parse(_))));
+
+        let mut machine = machine("end: JP end\n", "fn on_step(pc) { poke(0x1000, 0); }");
+        machine.run_frames(5).unwrap();
+        assert!(machine.stopped());
+        assert_eq!(machine.cycles(), 0);
+        let error = machine.take_script_error().unwrap().to_string();
+        assert!(error.contains("address out of range: 4096"), "{}", error);
+    }
+}
@@ -0,0 +1,199 @@
+use super::display::sprite_bit;
+use super::png;
+
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+
+const PIXEL_ON: u32 = 0x44FFFF00;
+const PIXEL_OFF: u32 = 0x00000000;
+const GRID: u32 = 0x00404040;
+const EXPORT_SCALE: usize = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sprite {
+    pub address: u16,
+    pub width: usize,
+    pub height: usize,
+    pixels: Vec<bool>,
+}
+
+impl Sprite {
+    // A height of 0 selects the 16x16 (two bytes per row) layout, like DRW with n = 0
+    pub fn decode(address: u16, bytes: &[u8], height: u8) -> Self {
+        let (width, height, bytes_per_row) = if height == 0 {
+            (16, 16, 2)
+        } else {
+            (8, height as usize, 1)
+        };
+
+        let mut pixels = vec![false; width * height];
+        for row in 0..height {
+            for col in 0..width {
+                let byte_pos = row * bytes_per_row + col / 8;
+                if let Some(byte) = bytes.get(byte_pos) {
+                    pixels[row * width + col] = sprite_bit(*byte, col % 8) == 1;
+                }
+            }
+        }
+
+        Sprite {
+            address: address,
+            width: width,
+            height: height,
+            pixels: pixels,
+        }
+    }
+
+    pub fn is_set(&self, x: usize, y: usize) -> bool {
+        self.pixels[y * self.width + x]
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SpriteSheet {
+    pub sprites: Vec<Sprite>,
+}
+
+impl SpriteSheet {
+    pub fn decode(address: u16, bytes: &[u8], height: u8) -> Self {
+        let sprite_len = if height == 0 { 32 } else { height as usize };
+        let sprites = bytes
+            .chunks(sprite_len)
+            .enumerate()
+            .map(|(i, chunk)| Sprite::decode(address + (i * sprite_len) as u16, chunk, height))
+            .collect();
+
+        SpriteSheet { sprites: sprites }
+    }
+
+    fn size(&self) -> (usize, usize) {
+        let width = self.sprites.iter().map(|s| s.width + 1).sum::<usize>().saturating_sub(1);
+        let height = self.sprites.iter().map(|s| s.height).max().unwrap_or(0);
+        (width, height)
+    }
+
+    pub fn to_text(&self) -> String {
+        let (_, height) = self.size();
+        let mut out = String::new();
+
+        for sprite in self.sprites.iter() {
+            out.push_str(&format!("{:03X}{} ", sprite.address, " ".repeat(sprite.width - 3)));
+        }
+        out.push('\n');
+
+        for y in 0..height {
+            for sprite in self.sprites.iter() {
+                for x in 0..sprite.width {
+                    let set = y < sprite.height && sprite.is_set(x, y);
+                    out.push(if set { '#' } else { '.' });
+                }
+                out.push(' ');
+            }
+            out.push('\n');
+        }
+
+        out
+    }
+
+    pub fn to_source(&self) -> String {
+        let mut out = String::new();
+
+        for sprite in self.sprites.iter() {
+            out.push_str(&format!("sprite_{:03X}:\n", sprite.address));
+            for y in 0..sprite.height {
+                for byte in 0..sprite.width / 8 {
+                    let mut value = 0u8;
+                    for bit in 0..8 {
+                        if sprite.is_set(byte * 8 + bit, y) {
+                            value |= 0x80 >> bit;
+                        }
+                    }
+                    out.push_str(&format!("    db 0b{:08b}\n", value));
+                }
+            }
+        }
+
+        out
+    }
+
+    pub fn to_pixels(&self, scale: usize) -> (usize, usize, Vec<u32>) {
+        let (width, height) = self.size();
+        let (width, height) = (width * scale, height * scale);
+        let mut pixels = vec![GRID; width * height];
+
+        let mut offset = 0;
+        for sprite in self.sprites.iter() {
+            for y in 0..sprite.height * scale {
+                for x in 0..sprite.width * scale {
+                    pixels[y * width + offset + x] = if sprite.is_set(x / scale, y / scale) {
+                        PIXEL_ON
+                    } else {
+                        PIXEL_OFF
+                    };
+                }
+            }
+            offset += (sprite.width + 1) * scale;
+        }
+
+        (width, height, pixels)
+    }
+
+    pub fn export_png<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let (width, height, pixels) = self.to_pixels(EXPORT_SCALE);
+        let mut out = BufWriter::new(File::create(path)?);
+        png::write_png(&mut out, width, height, &pixels)
+    }
+
+    pub fn export_source<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        std::fs::write(path, self.to_source())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    #[test]
+    fn decodes_and_exports_sprites() {
+        // The font's "1" then a 3 row arrow
+        let bytes = [0x20, 0x60, 0x20, 0x20, 0x70, 0x80, 0xC0, 0xE0];
+        let sheet = SpriteSheet::decode(0x300, &bytes, 5);
+        assert_eq!(sheet.sprites.len(), 2);
+        assert_eq!(sheet.sprites[1].address, 0x305);
+        assert!(sheet.sprites[0].is_set(2, 0) && !sheet.sprites[0].is_set(1, 0));
+        assert_eq!(
+            sheet.to_text().lines().take(3).collect::<Vec<_>>(),
+            [
+                "300      305      ",
+                "..#..... #....... ",
+                ".##..... ##...... "
+            ]
+        );
+
+        // The source assembles back into the same bytes, padded to whole sprites
+        let source = sheet.to_source();
+        assert!(source.starts_with("sprite_300:\n    db 0b00100000\n"));
+        let mut padded = bytes.to_vec();
+        padded.extend_from_slice(&[0, 0]);
+        assert_eq!(assemble(&source, 0x300).unwrap().bytes, padded);
+    }
+
+    #[test]
+    fn decodes_large_sprites() {
+        let bytes: Vec<u8> = (0..32)
+            .map(|i| if i % 2 == 0 { 0xFF } else { 0x01 })
+            .collect();
+        let sheet = SpriteSheet::decode(0x200, &bytes, 0);
+        let sprite = &sheet.sprites[0];
+        assert_eq!((sprite.width, sprite.height), (16, 16));
+        assert!(sprite.is_set(7, 15) && !sprite.is_set(8, 15) && sprite.is_set(15, 15));
+        assert_eq!(assemble(&sheet.to_source(), 0x200).unwrap().bytes, bytes);
+
+        let (width, height, pixels) = sheet.to_pixels(2);
+        assert_eq!((width, height), (32, 32));
+        assert_eq!(pixels[0], PIXEL_ON);
+        assert_eq!(pixels[16], PIXEL_OFF);
+    }
+}
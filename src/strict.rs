@@ -0,0 +1,320 @@
+use super::cpu::{Cpu, CpuState};
+use super::flow::{ByteKind, Flow};
+use super::memory::{self, Memory};
+use super::opcode::Opcode;
+use super::quirks::Quirks;
+use super::rom::Layout;
+
+use std::collections::HashSet;
+use std::fmt;
+use std::mem::{self, Discriminant};
+
+// The COSMAC VIP interpreter only has room for 12 return addresses
+pub const VIP_STACK_DEPTH: u8 = 12;
+
+// Things a ROM does that are probably bugs, or that would fail on other
+// interpreters. Checked before each instruction runs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Problem {
+    // Stores into the interpreter's memory, below where the ROM is loaded
+    InterpreterWrite { address: u16 },
+    // Reads of bytes that neither the ROM nor the program put there
+    UninitialisedRead { address: u16 },
+    // Jumps and calls to odd addresses, which some interpreters can't fetch from
+    OddJump { target: u16 },
+    // The PC is outside anything loaded or written
+    RunsOffProgram,
+    // The PC is in bytes the static analysis found to be data
+    RunsIntoData,
+    PastEnd { address: u16, length: u16 },
+    StackDepth { depth: u8 },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::InterpreterWrite { address } => {
+                write!(f, "writes {:#05X}, in the interpreter's memory", address)
+            }
+            Problem::UninitialisedRead { address } => {
+                write!(f, "reads {:#05X}, which nothing has written", address)
+            }
+            Problem::OddJump { target } => write!(f, "goes to an odd address, {:#05X}", target),
+            Problem::RunsOffProgram => write!(f, "runs outside the program"),
+            Problem::RunsIntoData => write!(f, "runs into data"),
+            Problem::PastEnd { address, length } => write!(
+                f,
+                "uses {} bytes from {:#05X}, past the end of memory",
+                length, address
+            ),
+            Problem::StackDepth { depth } => write!(
+                f,
+                "calls {} deep, more than the COSMAC VIP's {}",
+                depth, VIP_STACK_DEPTH
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Diagnostic {
+    pub pc: u16,
+    pub instruction: u16,
+    pub problem: Problem,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#05X} {:04X} {}: {}",
+            self.pc,
+            self.instruction,
+            Opcode::decode(self.instruction),
+            self.problem
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct Strict {
+    load_address: u16,
+    flow: Flow,
+    // By address, whether the ROM, the font or the program put something there
+    initialised: Vec<bool>,
+    // Each problem is reported once per instruction
+    seen: HashSet<(u16, Discriminant<Problem>)>,
+    diagnostics: Vec<Diagnostic>,
+    reported: usize,
+}
+
+impl Strict {
+    pub fn new(program: &[u8], layout: Layout) -> Self {
+        let mut strict = Strict {
+            load_address: layout.load_address,
+            flow: Flow::analyze(program, layout.load_address),
+            initialised: Vec::new(),
+            seen: HashSet::new(),
+            diagnostics: Vec::new(),
+            reported: 0,
+        };
+        strict.initialise(memory::FONT_ADDRESS, memory::FONT_SIZE);
+        strict.initialise(layout.load_address, program.len());
+        strict
+    }
+
+    fn initialise(&mut self, address: u16, length: usize) {
+        let end = address as usize + length;
+        if self.initialised.len() < end {
+            self.initialised.resize(end, false);
+        }
+        for byte in &mut self.initialised[address as usize..end] {
+            *byte = true;
+        }
+    }
+
+    fn is_initialised(&self, address: u16) -> bool {
+        self.initialised
+            .get(address as usize)
+            .copied()
+            .unwrap_or(false)
+    }
+
+    fn report(&mut self, state: &CpuState, memory: &Memory, problem: Problem) {
+        let pc = state.reg_pc;
+        if !self.seen.insert((pc, mem::discriminant(&problem))) {
+            return;
+        }
+        let instruction = if memory.contains(pc, 2) {
+            memory.read_doublebyte(pc)
+        } else {
+            0
+        };
+        self.diagnostics.push(Diagnostic {
+            pc: pc,
+            instruction: instruction,
+            problem: problem,
+        });
+    }
+
+    // Called before the instruction at the PC runs
+    pub fn check(&mut self, cpu: &Cpu, memory: &Memory) {
+        let state = &cpu.state();
+        let pc = state.reg_pc;
+        if !self.is_initialised(pc) || !self.is_initialised(pc.wrapping_add(1)) {
+            self.report(state, memory, Problem::RunsOffProgram);
+        } else if self.flow.kind(pc) == Some(ByteKind::Data) {
+            self.report(state, memory, Problem::RunsIntoData);
+        }
+        if !memory.contains(pc, 2) {
+            return;
+        }
+
+        let i = state.reg_i;
+        let opcode = Opcode::decode(memory.read_doublebyte(pc));
+        match opcode {
+            Opcode::DRW { size, .. } => self.read(state, memory, i, size as u16),
+            Opcode::LD_M { x } => self.read(state, memory, i, x as u16 + 1),
+            Opcode::ST_M { x } => self.write(state, memory, i, x as u16 + 1),
+            Opcode::LD_B { .. } => self.write(state, memory, i, 3),
+            Opcode::CALL { .. } if state.reg_sp >= VIP_STACK_DEPTH => {
+                let depth = state.reg_sp + 1;
+                self.report(state, memory, Problem::StackDepth { depth: depth });
+            }
+            _ => {}
+        }
+        // Only where code moves onto odd addresses, not within code that already runs there
+        if let Some(target) = jump_target(opcode, state, cpu.quirks()) {
+            if target & 1 == 1 && pc & 1 == 0 {
+                self.report(state, memory, Problem::OddJump { target: target });
+            }
+        }
+    }
+
+    // False when the range runs past the end of memory, which the CPU refuses
+    fn in_memory(&mut self, state: &CpuState, memory: &Memory, address: u16, length: u16) -> bool {
+        if memory.contains(address, length as usize) {
+            return true;
+        }
+        let problem = Problem::PastEnd {
+            address: address,
+            length: length,
+        };
+        self.report(state, memory, problem);
+        false
+    }
+
+    fn read(&mut self, state: &CpuState, memory: &Memory, address: u16, length: u16) {
+        if !self.in_memory(state, memory, address, length) {
+            return;
+        }
+        let uninitialised = (address..address + length).find(|a| !self.is_initialised(*a));
+        if let Some(address) = uninitialised {
+            self.report(
+                state,
+                memory,
+                Problem::UninitialisedRead { address: address },
+            );
+        }
+    }
+
+    fn write(&mut self, state: &CpuState, memory: &Memory, address: u16, length: u16) {
+        if !self.in_memory(state, memory, address, length) {
+            return;
+        }
+        if address < self.load_address {
+            self.report(
+                state,
+                memory,
+                Problem::InterpreterWrite { address: address },
+            );
+        }
+        self.initialise(address, length as usize);
+    }
+
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    // Diagnostics found since the last call
+    pub fn take_new(&mut self) -> &[Diagnostic] {
+        let start = self.reported;
+        self.reported = self.diagnostics.len();
+        &self.diagnostics[start..]
+    }
+}
+
+// Where a jump or call goes, following the jump quirk for `JP V0`
+fn jump_target(opcode: Opcode, state: &CpuState, quirks: Quirks) -> Option<u16> {
+    match opcode {
+        Opcode::JP { addr } | Opcode::CALL { addr } => Some(addr),
+        Opcode::JP_V0 { addr } => {
+            let x = if quirks.jump_vx { addr >> 8 & 0xF } else { 0 };
+            Some(addr + state.reg_gp[x as usize] as u16)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::headless::Headless;
+
+    // What strict mode says about `source`, run until it loops or fails
+    fn problems(source: &str) -> Vec<Problem> {
+        let program = assemble(source, 0x200).unwrap().bytes;
+        let mut machine = Headless::new(&program, Layout::default(), 0).unwrap();
+        machine.set_strict(Strict::new(&program, Layout::default()));
+        let _ = machine.run_frames(4);
+        machine.diagnostics().iter().map(|d| d.problem).collect()
+    }
+
+    #[test]
+    fn flags_memory_use() {
+        let source = "
+                LD I, 0x100
+                LD [I], V0
+                LD I, 0x900
+                LD [I], V1
+                LD V1, [I]
+                LD I, 0x800
+                DRW V0, V0, 1
+            end: JP end
+        ";
+        assert_eq!(
+            problems(source),
+            [
+                Problem::InterpreterWrite { address: 0x100 },
+                Problem::UninitialisedRead { address: 0x800 },
+            ]
+        );
+
+        let source = "LD I, 0xFFE\nLD [I], V3\n";
+        assert_eq!(
+            problems(source),
+            [Problem::PastEnd {
+                address: 0xFFE,
+                length: 4
+            }]
+        );
+    }
+
+    #[test]
+    fn flags_where_the_pc_goes() {
+        // The jump table's second entry is the sprite
+        let source = "
+                    LD I, sprite
+                    DRW V0, V0, 2
+                    LD V0, 2
+                    JP V0, table
+            table:  JP table
+            sprite: db 0x70, 0x01
+            end:    JP end
+        ";
+        assert_eq!(problems(source), [Problem::RunsIntoData]);
+
+        assert_eq!(problems("JP 0x300\n"), [Problem::RunsOffProgram]);
+
+        let program = [0x12, 0x03, 0x00, 0x12, 0x03];
+        let mut machine = Headless::new(&program, Layout::default(), 0).unwrap();
+        machine.set_strict(Strict::new(&program, Layout::default()));
+        machine.run_frames(2).unwrap();
+        let diagnostics: Vec<String> = machine
+            .diagnostics()
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(
+            diagnostics,
+            ["0x200 1203 JP 0x203: goes to an odd address, 0x203"]
+        );
+    }
+
+    #[test]
+    fn flags_deep_calls() {
+        let problems = problems("sub: CALL sub\n");
+        assert_eq!(problems, [Problem::StackDepth { depth: 13 }]);
+    }
+}
@@ -0,0 +1,175 @@
+use super::asm::Assembly;
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// What the assemblers know about a ROM, saved next to it as ROM.sym so the
+// debugger and disassembler can show labels and source lines
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SymbolMap {
+    // The source file, relative to the map unless absolute
+    pub source: Option<PathBuf>,
+    pub labels: BTreeMap<String, u16>,
+    // The source line each instruction and data byte came from
+    pub lines: BTreeMap<u16, usize>,
+    #[serde(skip)]
+    source_lines: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum SymbolError {
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        error: serde_json::Error,
+    },
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SymbolError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            SymbolError::Parse { path, error } => write!(f, "{}: {}", path.display(), error),
+        }
+    }
+}
+
+impl std::error::Error for SymbolError {}
+
+pub fn path_for(rom: &Path) -> PathBuf {
+    rom.with_extension("sym")
+}
+
+impl SymbolMap {
+    pub fn from_assembly(assembly: &Assembly, source: Option<&Path>) -> Self {
+        SymbolMap {
+            source: source.map(Path::to_path_buf),
+            labels: assembly.labels.clone(),
+            lines: assembly.lines.clone(),
+            source_lines: Vec::new(),
+        }
+    }
+
+    // Also reads the source, if it can be found, to show lines from it
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, SymbolError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| SymbolError::Io {
+            path: path.to_path_buf(),
+            error: e,
+        })?;
+        let mut symbols: SymbolMap =
+            serde_json::from_str(&text).map_err(|e| SymbolError::Parse {
+                path: path.to_path_buf(),
+                error: e,
+            })?;
+
+        if let Some(source) = &symbols.source {
+            let source = path.parent().unwrap_or_else(|| Path::new("")).join(source);
+            if let Ok(text) = fs::read_to_string(source) {
+                symbols.set_source_text(&text);
+            }
+        }
+        Ok(symbols)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), SymbolError> {
+        let path = path.as_ref();
+        let text = serde_json::to_string_pretty(self).expect("SymbolMap always serializes");
+        fs::write(path, text).map_err(|e| SymbolError::Io {
+            path: path.to_path_buf(),
+            error: e,
+        })
+    }
+
+    pub fn set_source_text(&mut self, text: &str) {
+        self.source_lines = text.lines().map(String::from).collect();
+    }
+
+    pub fn address_of(&self, label: &str) -> Option<u16> {
+        self.labels.get(label).copied()
+    }
+
+    // The closest label at or before `address`, and how far past it the address is
+    pub fn locate(&self, address: u16) -> Option<(&str, u16)> {
+        self.labels
+            .iter()
+            .filter(|(_, label_address)| **label_address <= address)
+            .max_by_key(|(name, label_address)| (**label_address, std::cmp::Reverse(*name)))
+            .map(|(name, label_address)| (name.as_str(), address - label_address))
+    }
+
+    pub fn line_at(&self, address: u16) -> Option<usize> {
+        self.lines.get(&address).copied()
+    }
+
+    pub fn source_line(&self, line: usize) -> Option<&str> {
+        self.source_lines
+            .get(line.checked_sub(1)?)
+            .map(|text| text.trim())
+    }
+
+    // Like "0x204 <main+4>, line 12: v0 := 5", with whatever is known
+    pub fn describe(&self, address: u16) -> String {
+        let mut text = format!("{:#05X}", address);
+        match self.locate(address) {
+            Some((name, 0)) => text += &format!(" <{}>", name),
+            Some((name, offset)) => text += &format!(" <{}+{}>", name, offset),
+            None => {}
+        }
+        if let Some(line) = self.line_at(address) {
+            text += &format!(", line {}", line);
+            if let Some(source) = self.source_line(line) {
+                text += &format!(": {}", source);
+            }
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    const SOURCE: &str = "start: CLS\n       CALL sub\nloop:  JP loop\nsub:   RET\n";
+
+    #[test]
+    fn describes_addresses_from_the_assembly() {
+        let assembly = asm::assemble(SOURCE, 0x200).unwrap();
+        let mut symbols = SymbolMap::from_assembly(&assembly, None);
+        symbols.set_source_text(SOURCE);
+
+        assert_eq!(symbols.address_of("sub"), Some(0x206));
+        assert_eq!(symbols.locate(0x203), Some(("start", 3)));
+        assert_eq!(symbols.locate(0x1FE), None);
+        assert_eq!(symbols.describe(0x202), "0x202 <start+2>, line 2: CALL sub");
+        assert_eq!(symbols.describe(0x206), "0x206 <sub>, line 4: sub:   RET");
+        assert_eq!(symbols.describe(0x300), "0x300 <sub+250>");
+    }
+
+    #[test]
+    fn saves_and_loads_with_the_source() {
+        let dir = std::env::temp_dir().join(format!("chip8-symbols-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("game.asm"), SOURCE).unwrap();
+
+        let assembly = asm::assemble(SOURCE, 0x200).unwrap();
+        let symbols = SymbolMap::from_assembly(&assembly, Some(Path::new("game.asm")));
+        symbols.save(dir.join("game.sym")).unwrap();
+        let loaded = SymbolMap::load(dir.join("game.sym")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(loaded.labels, symbols.labels);
+        assert_eq!(loaded.lines, symbols.lines);
+        assert_eq!(loaded.source_line(3), Some("loop:  JP loop"));
+    }
+}
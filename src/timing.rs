@@ -0,0 +1,188 @@
+use super::cpu::CpuState;
+use super::opcode::Opcode;
+
+// The VIP's 1.76 MHz 1802 takes 8 clocks per machine cycle, giving about 3668
+// machine cycles per 60 Hz frame. Display DMA and the interrupt routine that
+// counts down the timers take their share before the interpreter gets the rest.
+pub const CYCLES_PER_FRAME: i64 = 3668;
+const DISPLAY_DMA_CYCLES: i64 = 1024;
+const INTERRUPT_CYCLES: i64 = 46;
+pub const INTERPRETER_CYCLES_PER_FRAME: i64 =
+    CYCLES_PER_FRAME - DISPLAY_DMA_CYCLES - INTERRUPT_CYCLES;
+
+// Fetching and dispatching an instruction, paid on top of each instruction's own cost
+const FETCH_CYCLES: u32 = 40;
+
+// Machine cycles the VIP interpreter spends on `opcode`, given the registers it runs with
+pub fn instruction_cycles(opcode: Opcode, state: &CpuState) -> u32 {
+    let v = |x: u8| state.reg_gp[x as usize];
+    let skip = |taken: bool| if taken { 14 } else { 10 };
+
+    let cycles = match opcode {
+        Opcode::NOP => 0,
+        Opcode::CLS => 3078,
+        Opcode::RET => 10,
+        Opcode::SYS { .. } => 26,
+        Opcode::JP { .. } => 12,
+        Opcode::CALL { .. } => 26,
+        // Crossing into the next page costs an extra increment of the high byte
+        Opcode::JP_V0 { addr } => {
+            if (addr & 0xFF) + v(0) as u16 > 0xFF {
+                24
+            } else {
+                22
+            }
+        }
+        Opcode::SE { x, byte } => skip(v(x) == byte),
+        Opcode::SNE { x, byte } => skip(v(x) != byte),
+        Opcode::SE_R { x, y } => skip(v(x) == v(y)) + 4,
+        Opcode::SNE_R { x, y } => skip(v(x) != v(y)) + 4,
+        Opcode::LD_IMM { .. } => 6,
+        Opcode::ADD_IMM { .. } => 10,
+        Opcode::LD_R { .. } => 12,
+        Opcode::OR_R { .. }
+        | Opcode::AND { .. }
+        | Opcode::XOR_R { .. }
+        | Opcode::ADD_R { .. }
+        | Opcode::SUB_R { .. }
+        | Opcode::SUBN_R { .. }
+        | Opcode::SHR { .. }
+        | Opcode::SHL { .. } => 44,
+        Opcode::LDI_IMM { .. } => 12,
+        Opcode::RND { .. } => 36,
+        // Rows that straddle two display bytes are shifted and written twice
+        Opcode::DRW { x, size, .. } => {
+            let rows = if size == 0 { 16 } else { size as u32 };
+            let row_cycles = if v(x) % 8 == 0 { 46 } else { 72 };
+            26 + rows * row_cycles
+        }
+        Opcode::SKP { .. } | Opcode::SKNP { .. } => 14,
+        Opcode::LD_DT { .. } | Opcode::SET_DT { .. } | Opcode::SET_ST { .. } => 10,
+        Opcode::LD_R_K { .. } => 16,
+        Opcode::ADDI_R { .. } => 16,
+        Opcode::LD_F { .. } => 20,
+        // BCD conversion subtracts powers of ten one at a time
+        Opcode::LD_B { x } => {
+            let value = v(x) as u32;
+            80 + 16 * (value / 100 + value / 10 % 10 + value % 10)
+        }
+        Opcode::LD_M { x } | Opcode::ST_M { x } => 14 + 14 * (x as u32 + 1),
+    };
+
+    FETCH_CYCLES + cycles
+}
+
+#[derive(Debug, Default)]
+pub struct VipTiming {
+    // Goes negative when an instruction overruns the frame, delaying the next one
+    budget: i64,
+    frame_start: bool,
+}
+
+impl VipTiming {
+    pub fn new() -> Self {
+        VipTiming::default()
+    }
+
+    // Called on each 60 Hz interrupt
+    pub fn begin_frame(&mut self) {
+        self.budget =
+            (self.budget + INTERPRETER_CYCLES_PER_FRAME).min(INTERPRETER_CYCLES_PER_FRAME);
+        self.frame_start = true;
+    }
+
+    // Charges `opcode` to the current frame if it can start now. DRW waits for the
+    // next interrupt unless it is the first instruction of the frame, as the VIP
+    // interpreter only draws right after vertical blank.
+    pub fn start(&mut self, opcode: Opcode, state: &CpuState) -> bool {
+        if self.budget <= 0 {
+            return false;
+        }
+        if let Opcode::DRW { .. } = opcode {
+            if !self.frame_start {
+                self.budget = 0;
+                return false;
+            }
+        }
+
+        self.charge(instruction_cycles(opcode, state) as u64);
+        self.frame_start = false;
+        true
+    }
+
+    // For work done outside the interpreter, like 0nnn machine code routines
+    pub fn charge(&mut self, cycles: u64) {
+        self.budget -= cycles as i64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+
+    fn state() -> CpuState {
+        Cpu::with_seed(0).state()
+    }
+
+    #[test]
+    fn frame_budget_limits_instructions() {
+        let mut timing = VipTiming::new();
+        timing.begin_frame();
+
+        let opcode = Opcode::LD_IMM { x: 0, byte: 1 };
+        let mut count = 0;
+        while timing.start(opcode, &state()) {
+            count += 1;
+        }
+
+        let cost = instruction_cycles(opcode, &state()) as i64;
+        assert_eq!(count, (INTERPRETER_CYCLES_PER_FRAME + cost - 1) / cost);
+        assert!(!timing.start(opcode, &state()));
+    }
+
+    #[test]
+    fn drw_waits_for_next_frame() {
+        let mut timing = VipTiming::new();
+        let drw = Opcode::DRW { x: 0, y: 0, size: 5 };
+        timing.begin_frame();
+
+        assert!(timing.start(Opcode::LD_IMM { x: 0, byte: 1 }, &state()));
+        assert!(!timing.start(drw, &state()));
+        assert!(!timing.start(Opcode::LD_IMM { x: 0, byte: 1 }, &state()));
+
+        timing.begin_frame();
+        assert!(timing.start(drw, &state()));
+    }
+
+    #[test]
+    fn overrun_carries_into_next_frame() {
+        let mut timing = VipTiming::new();
+        timing.begin_frame();
+        timing.charge(INTERPRETER_CYCLES_PER_FRAME as u64 * 2);
+
+        timing.begin_frame();
+        assert!(!timing.start(Opcode::LD_IMM { x: 0, byte: 1 }, &state()));
+        timing.begin_frame();
+        assert!(timing.start(Opcode::LD_IMM { x: 0, byte: 1 }, &state()));
+    }
+
+    #[test]
+    fn costs_depend_on_operands() {
+        let mut state = state();
+        state.reg_gp[0] = 199;
+        state.reg_gp[1] = 4;
+
+        assert_eq!(
+            instruction_cycles(Opcode::LD_B { x: 0 }, &state),
+            FETCH_CYCLES + 80 + 16 * 19
+        );
+        let unaligned = Opcode::DRW { x: 1, y: 0, size: 4 };
+        let aligned = Opcode::DRW { x: 2, y: 0, size: 4 };
+        assert!(instruction_cycles(unaligned, &state) > instruction_cycles(aligned, &state));
+        assert_eq!(
+            instruction_cycles(Opcode::SE { x: 1, byte: 4 }, &state),
+            instruction_cycles(Opcode::SE { x: 1, byte: 5 }, &state) + 4
+        );
+    }
+}
@@ -0,0 +1,267 @@
+use super::cpu::CpuState;
+use super::opcode::Opcode;
+
+use serde_json::{json, Map, Value};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Default)]
+pub struct TraceFilter {
+    pub addresses: Option<RangeInclusive<u16>>,
+    pub opcodes: Option<Vec<String>>,
+    pub cycles: Option<RangeInclusive<u64>>,
+}
+
+impl TraceFilter {
+    fn matches(&self, cycle: u64, pc: u16, opcode: &Opcode) -> bool {
+        if let Some(addresses) = &self.addresses {
+            if !addresses.contains(&pc) {
+                return false;
+            }
+        }
+
+        if let Some(cycles) = &self.cycles {
+            if !cycles.contains(&cycle) {
+                return false;
+            }
+        }
+
+        // By the mnemonic the trace shows, so "ld" matches every LD form
+        if let Some(opcodes) = &self.opcodes {
+            let text = opcode.to_string();
+            let mnemonic = text.split_whitespace().next().unwrap_or("");
+            if !opcodes.iter().any(|name| name.eq_ignore_ascii_case(mnemonic)) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+pub struct Tracer {
+    out: Box<dyn Write>,
+    format: TraceFormat,
+    filter: TraceFilter,
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Tracer")
+            .field("format", &self.format)
+            .field("filter", &self.filter)
+            .finish()
+    }
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>, format: TraceFormat, filter: TraceFilter) -> Self {
+        Tracer {
+            out: out,
+            format: format,
+            filter: filter,
+        }
+    }
+
+    pub fn to_file<P: AsRef<Path>>(
+        path: P,
+        format: TraceFormat,
+        filter: TraceFilter,
+    ) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(Tracer::new(Box::new(BufWriter::new(file)), format, filter))
+    }
+
+    pub fn trace(
+        &mut self,
+        cycle: u64,
+        instruction: u16,
+        before: &CpuState,
+        after: &CpuState,
+    ) -> io::Result<()> {
+        let opcode = Opcode::decode(instruction);
+        if !self.filter.matches(cycle, before.reg_pc, &opcode) {
+            return Ok(());
+        }
+
+        let changes = register_changes(before, after);
+        match self.format {
+            TraceFormat::Text => {
+                let changes: Vec<String> = changes
+                    .iter()
+                    .map(|(name, value)| format!(" {}={:02X}", name, value))
+                    .collect();
+                writeln!(
+                    self.out,
+                    "{:08} {:03X} {:04X} {:<20} I={:03X} DT={:02X} ST={:02X}{}",
+                    cycle,
+                    before.reg_pc,
+                    instruction,
+                    opcode.to_string(),
+                    after.reg_i,
+                    after.reg_delay,
+                    after.reg_sound_timer,
+                    changes.concat()
+                )
+            }
+            TraceFormat::Json => {
+                let changes: Map<String, Value> = changes
+                    .into_iter()
+                    .map(|(name, value)| (name, Value::from(value)))
+                    .collect();
+                let line = json!({
+                    "cycle": cycle,
+                    "pc": before.reg_pc,
+                    "opcode": format!("{:04X}", instruction),
+                    "mnemonic": opcode.to_string(),
+                    "changes": changes,
+                    "i": after.reg_i,
+                    "dt": after.reg_delay,
+                    "st": after.reg_sound_timer,
+                });
+                writeln!(self.out, "{}", line)
+            }
+        }
+    }
+}
+
+// I and the timers always have their own columns
+fn register_changes(before: &CpuState, after: &CpuState) -> Vec<(String, u16)> {
+    let mut changes = Vec::new();
+    for i in 0..16 {
+        if before.reg_gp[i] != after.reg_gp[i] {
+            changes.push((format!("V{:X}", i), after.reg_gp[i] as u16));
+        }
+    }
+    if before.reg_sp != after.reg_sp {
+        changes.push((String::from("SP"), after.reg_sp as u16));
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headless::Headless;
+    use crate::rom::Layout;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // LD V0, 0x12; LD I, 0x300; ADD V0, 1; JP 0x204
+    const PROGRAM: [u8; 8] = [0x60, 0x12, 0xA3, 0x00, 0x70, 0x01, 0x12, 0x04];
+
+    // Somewhere to trace to that the test can read afterwards
+    #[derive(Clone, Default)]
+    struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(bytes)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Closed;
+
+    impl Write for Closed {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::from(io::ErrorKind::BrokenPipe))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn trace(format: TraceFormat, filter: TraceFilter, steps: usize) -> Vec<String> {
+        let buffer = Buffer::default();
+        let mut machine = Headless::new(&PROGRAM, Layout::default(), 0).unwrap();
+        machine.set_tracer(Tracer::new(Box::new(buffer.clone()), format, filter));
+        for _ in 0..steps {
+            machine.step().unwrap();
+        }
+        let text = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        text.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn writes_text_and_json() {
+        let lines = trace(TraceFormat::Text, TraceFilter::default(), 3);
+        assert_eq!(
+            lines,
+            [
+                "00000000 200 6012 LD V0, 0x12          I=000 DT=00 ST=00 V0=12",
+                "00000001 202 A300 LD I, 0x300          I=300 DT=00 ST=00",
+                "00000002 204 7001 ADD V0, 0x01         I=300 DT=00 ST=00 V0=13",
+            ]
+        );
+
+        let lines = trace(TraceFormat::Json, TraceFilter::default(), 1);
+        assert_eq!(lines.len(), 1);
+        let line: Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(
+            line,
+            json!({
+                "cycle": 0,
+                "pc": 512,
+                "opcode": "6012",
+                "mnemonic": "LD V0, 0x12",
+                "changes": { "V0": 18 },
+                "i": 0,
+                "dt": 0,
+                "st": 0,
+            })
+        );
+    }
+
+    #[test]
+    fn filters_by_address_opcode_and_cycle() {
+        let filter = TraceFilter {
+            addresses: Some(0x204..=0x204),
+            ..TraceFilter::default()
+        };
+        let lines = trace(TraceFormat::Text, filter, 8);
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|line| line[9..12] == *"204"));
+
+        let filter = TraceFilter {
+            opcodes: Some(vec!["jp".to_string(), "ADD".to_string()]),
+            cycles: Some(0..=4),
+            ..TraceFilter::default()
+        };
+        let cycles: Vec<String> = trace(TraceFormat::Text, filter, 8)
+            .iter()
+            .map(|line| line[..8].to_string())
+            .collect();
+        assert_eq!(cycles, ["00000002", "00000003", "00000004"]);
+    }
+
+    #[test]
+    fn stops_tracing_when_the_output_fails() {
+        let mut machine = Headless::new(&PROGRAM, Layout::default(), 0).unwrap();
+        machine.set_tracer(Tracer::new(
+            Box::new(Closed),
+            TraceFormat::Text,
+            TraceFilter::default(),
+        ));
+        machine.run_frames(3).unwrap();
+
+        assert!(machine.stopped());
+        assert_eq!(machine.cycles(), 1);
+        let error = machine.take_trace_error().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+    }
+}
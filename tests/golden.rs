@@ -0,0 +1,125 @@
+use chip8_emu::headless::Headless;
+use chip8_emu::png;
+use chip8_emu::rom::Layout;
+
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+const WIDTH: usize = 64;
+const HEIGHT: usize = 32;
+const FRAMES: u64 = 300;
+const SEED: u64 = 0xC8;
+
+const ROM_DIR: &str = "roms";
+const GOLDEN_DIR: &str = "tests/golden";
+const DIFF_DIR: &str = "target/golden-diffs";
+
+// Regenerate the stored images with `UPDATE_GOLDEN=1 cargo test --test golden`
+fn update_golden() -> bool {
+    std::env::var_os("UPDATE_GOLDEN").is_some()
+}
+
+// Only the ROMs bundled in ROM_DIR are checked; the community test suites are not
+// redistributed with this tree.
+fn bundled_roms() -> Vec<PathBuf> {
+    let mut roms: Vec<PathBuf> = fs::read_dir(ROM_DIR)
+        .unwrap()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "ch8"))
+        .collect();
+    roms.sort();
+    roms
+}
+
+fn run_headless(program: Vec<u8>) -> Vec<bool> {
+    let mut machine = Headless::new(&program, Layout::default(), SEED).unwrap();
+    machine.run_frames(FRAMES).unwrap();
+
+    machine
+        .display
+        .framebuffer
+        .iter()
+        .map(|pixel| *pixel != 0)
+        .collect()
+}
+
+fn to_pbm(pixels: &[bool]) -> String {
+    let mut out = format!("P1\n{} {}\n", WIDTH, HEIGHT);
+    for row in pixels.chunks(WIDTH) {
+        let row: Vec<&str> = row.iter().map(|set| if *set { "1" } else { "0" }).collect();
+        out.push_str(&row.join(" "));
+        out.push('\n');
+    }
+    out
+}
+
+fn from_pbm(text: &str) -> Vec<bool> {
+    text.lines()
+        .skip(2)
+        .flat_map(|line| line.split_whitespace())
+        .map(|bit| bit == "1")
+        .collect()
+}
+
+// Pixels only in the golden image are red, pixels only in the new frame are green
+fn write_diff(path: &Path, expected: &[bool], actual: &[bool]) {
+    let pixels: Vec<u32> = expected
+        .iter()
+        .zip(actual.iter())
+        .map(|(expected, actual)| match (expected, actual) {
+            (true, true) => 0x00FFFFFF,
+            (true, false) => 0x00FF0000,
+            (false, true) => 0x0000FF00,
+            (false, false) => 0x00000000,
+        })
+        .collect();
+
+    fs::create_dir_all(DIFF_DIR).unwrap();
+    let mut file = File::create(path).unwrap();
+    png::write_png(&mut file, WIDTH, HEIGHT, &pixels).unwrap();
+}
+
+// Compares the ROM's last frame with its golden image, or records the image
+fn check(rom: &Path, failures: &mut Vec<String>) {
+    let name = rom.file_stem().unwrap().to_string_lossy().into_owned();
+    let golden_path = Path::new(GOLDEN_DIR).join(format!("{}.pbm", name));
+    let actual = run_headless(fs::read(rom).unwrap());
+
+    if update_golden() {
+        fs::write(&golden_path, to_pbm(&actual)).unwrap();
+        return;
+    }
+
+    let expected = match fs::read_to_string(&golden_path) {
+        Ok(text) => from_pbm(&text),
+        Err(_) => {
+            failures.push(format!(
+                "{}: no golden image at {}",
+                name,
+                golden_path.display()
+            ));
+            return;
+        }
+    };
+
+    if expected != actual {
+        let diff_path = Path::new(DIFF_DIR).join(format!("{}.png", name));
+        write_diff(&diff_path, &expected, &actual);
+        failures.push(format!(
+            "{}: framebuffer differs, see {}",
+            name,
+            diff_path.display()
+        ));
+    }
+}
+
+#[test]
+fn framebuffers_match_golden_images() {
+    let mut failures = Vec::new();
+    for rom in bundled_roms() {
+        check(&rom, &mut failures);
+    }
+
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
@@ -0,0 +1,107 @@
+use chip8_emu::headless::Headless;
+use chip8_emu::jit::BlockCache;
+use chip8_emu::rom::Layout;
+
+const SEED: u64 = 0xC8;
+
+fn machine(program: &[u8]) -> Headless {
+    Headless::new(program, Layout::default(), SEED).unwrap()
+}
+
+fn assert_same(interpreted: &Headless, translated: &Headless) {
+    assert_eq!(interpreted.cpu.state(), translated.cpu.state());
+    assert_eq!(
+        interpreted.display.framebuffer,
+        translated.display.framebuffer
+    );
+    assert_eq!(
+        interpreted.memory.read_chunk(0, interpreted.memory.size()),
+        translated.memory.read_chunk(0, translated.memory.size())
+    );
+}
+
+// Runs both backends in lockstep frames, ticking timers between them like the frontend does
+fn run_both(
+    interpreted: &mut Headless,
+    translated: &mut Headless,
+    jit: &mut BlockCache,
+    frames: usize,
+) {
+    for _ in 0..frames {
+        interpreted.run_frame().unwrap();
+        jit.run(
+            &mut translated.cpu,
+            &mut translated.memory,
+            &mut translated.display,
+            &translated.keyboard_state,
+            8,
+        )
+        .unwrap();
+        translated.cpu.tick_timers();
+
+        assert_same(interpreted, translated);
+    }
+}
+
+#[test]
+fn matches_interpreter_on_bundled_roms() {
+    for rom in ["breakout", "space_invaders", "chip8_emu_logo"].iter() {
+        let program = std::fs::read(format!("roms/{}.ch8", rom)).unwrap();
+        let mut interpreted = machine(&program);
+        let mut translated = machine(&program);
+
+        run_both(
+            &mut interpreted,
+            &mut translated,
+            &mut BlockCache::new(),
+            5_000,
+        );
+    }
+}
+
+#[test]
+fn retranslates_modified_code() {
+    // LD V0, 0; ADD V0, 1; LD V1, V0; JP 0x202
+    let program = [0x60, 0x00, 0x70, 0x01, 0x81, 0x00, 0x12, 0x02];
+    let mut interpreted = machine(&program);
+    let mut translated = machine(&program);
+    let mut jit = BlockCache::new();
+
+    run_both(&mut interpreted, &mut translated, &mut jit, 10);
+
+    // ADD V0, 1 becomes ADD V0, 3
+    interpreted.memory.write_byte(0x203, 0x03);
+    translated.memory.write_byte(0x203, 0x03);
+
+    run_both(&mut interpreted, &mut translated, &mut jit, 10);
+}
+
+// Steps both backends until they fail, which they must do the same way
+fn fail_both(program: &[u8]) {
+    let mut interpreted = machine(program);
+    let mut translated = machine(program);
+    let mut jit = BlockCache::new();
+
+    let error = loop {
+        if let Err(e) = interpreted.step() {
+            break e;
+        }
+    };
+    let result = jit.run(
+        &mut translated.cpu,
+        &mut translated.memory,
+        &mut translated.display,
+        &translated.keyboard_state,
+        100,
+    );
+    assert_eq!(result.err(), Some(error));
+    assert_same(&interpreted, &translated);
+}
+
+#[test]
+fn fails_like_interpreter_past_end_of_memory() {
+    // JP 0xFFF
+    fail_both(&[0x1F, 0xFF]);
+    // LD V0, 0xFF; JP V0, 0xFFF
+    fail_both(&[0x60, 0xFF, 0xBF, 0xFF]);
+}
@@ -0,0 +1,80 @@
+use chip8_emu::cpu::Cpu;
+use chip8_emu::display::Display;
+use chip8_emu::keyboard::KeyboardState;
+use chip8_emu::memory::Memory;
+
+fn run(program: &[u16], steps: usize) -> (Cpu, Memory, Display) {
+    let mut cpu = Cpu::with_seed(0);
+    let mut memory = Memory::new();
+    let mut display = Display::new();
+    let keyboard_state = KeyboardState::default();
+    let bytes: Vec<u8> = program.iter().flat_map(|i| i.to_be_bytes().to_vec()).collect();
+    memory.write_chunk(0x200, bytes.into_boxed_slice());
+
+    for _ in 0..steps {
+        cpu.step(&mut memory, &mut display, &keyboard_state).unwrap();
+    }
+
+    (cpu, memory, display)
+}
+
+fn row(display: &Display, y: usize, x: usize, width: usize) -> String {
+    (x..x + width)
+        .map(|x| if display.framebuffer[y * 64 + x] != 0 { '#' } else { '.' })
+        .collect()
+}
+
+#[test]
+fn draws_bcd_digits_through_subroutine() {
+    let program = [
+        0x60FE, // 200: LD V0, 254
+        0xA300, // 202: LD I, 0x300
+        0xF033, // 204: LD B, V0
+        0xF265, // 206: LD V2, [I]
+        0x6300, // 208: LD V3, 0
+        0x6400, // 20A: LD V4, 0
+        0x2218, // 20C: CALL 0x218 (hundreds)
+        0x8010, // 20E: LD V0, V1
+        0x2218, // 210: CALL 0x218 (tens)
+        0x8020, // 212: LD V0, V2
+        0x2218, // 214: CALL 0x218 (ones)
+        0x1216, // 216: JP 0x216
+        0xF029, // 218: LD F, V0
+        0xD345, // 21A: DRW V3, V4, 5
+        0x7305, // 21C: ADD V3, 5
+        0x00EE, // 21E: RET
+    ];
+    let (cpu, memory, display) = run(&program, 6 + 3 * 5 + 2 + 1);
+    let state = cpu.state();
+
+    assert_eq!(memory.read_chunk(0x300, 3), vec![2, 5, 4]);
+    assert_eq!(state.reg_pc, 0x216);
+    assert_eq!(state.reg_sp, 0);
+    assert_eq!(state.reg_gp[3], 15);
+    assert_eq!(state.reg_gp[0xF], 0);
+
+    assert_eq!(row(&display, 0, 0, 15), "####.####.#..#.");
+    assert_eq!(row(&display, 2, 0, 15), "####.####.####.");
+    assert_eq!(row(&display, 4, 0, 15), "####.####....#.");
+}
+
+#[test]
+fn counts_down_with_loop() {
+    let program = [
+        0x600A, // 200: LD V0, 10
+        0x6100, // 202: LD V1, 0
+        0x6201, // 204: LD V2, 1
+        0x7102, // 206: ADD V1, 2
+        0x8025, // 208: SUB V0, V2
+        0x3000, // 20A: SE V0, 0
+        0x1206, // 20C: JP 0x206
+        0x120E, // 20E: JP 0x20E
+    ];
+    let (cpu, _, _) = run(&program, 3 + 10 * 4);
+    let state = cpu.state();
+
+    assert_eq!(state.reg_pc, 0x20E);
+    assert_eq!(state.reg_gp[0], 0);
+    assert_eq!(state.reg_gp[1], 20);
+    assert_eq!(state.reg_gp[0xF], 1);
+}